@@ -0,0 +1,200 @@
+//! Client side of the packet-train bandwidth probe.
+//!
+//! A measurement sends a train of `BandwidthProbe` packets over one link. The
+//! server answers with a `BandwidthReport` on the rate at which the train
+//! arrived, which estimates the link's upstream capacity, followed by a train
+//! of its own whose arrival rate estimates the downstream capacity.
+
+use crate::usage::UsageTracker;
+use chacha20poly1305::Key;
+use onebox_core::bandwidth::{self, BandwidthProbe, BandwidthReport, TrainArrivals, TRAIN_LENGTH};
+use onebox_core::packet::{PacketHeader, PacketType};
+use onebox_core::prelude::*;
+use onebox_core::types::ClientId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// How long to wait for the server's report and downstream train.
+pub const MEASUREMENT_TIMEOUT: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Estimated capacity of a link, in bytes per second per direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CapacityEstimate {
+    pub upstream: Option<f64>,
+    pub downstream: Option<f64>,
+}
+
+struct PendingTrain {
+    report: Option<BandwidthReport>,
+    arrivals: TrainArrivals,
+}
+
+/// Tracks the bandwidth probe trains that are waiting for an answer.
+pub struct BandwidthEstimator {
+    next_train_id: AtomicU32,
+    pending: Mutex<HashMap<u32, PendingTrain>>,
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> Self {
+        // Train ids select the nonces of train packets, so start from a
+        // different point on every run rather than always from zero.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or(0);
+        Self {
+            next_train_id: AtomicU32::new(seed),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allocates an id for a new train and starts waiting for its answer.
+    pub fn start_train(&self) -> u32 {
+        let train_id = self.next_train_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(
+            train_id,
+            PendingTrain {
+                report: None,
+                arrivals: TrainArrivals::new(TRAIN_LENGTH),
+            },
+        );
+        train_id
+    }
+
+    /// Records a packet of the server's downstream train.
+    pub fn on_probe_packet(&self, probe: &BandwidthProbe, len: usize, at: Instant) {
+        if let Some(train) = self.pending.lock().unwrap().get_mut(&probe.train_id) {
+            train.arrivals.record(len, at);
+        }
+    }
+
+    /// Records the server's report on one of our trains.
+    pub fn on_report(&self, report: BandwidthReport) {
+        if let Some(train) = self.pending.lock().unwrap().get_mut(&report.train_id) {
+            train.report = Some(report);
+        }
+    }
+
+    /// Whether everything the server sends for the train has arrived.
+    pub fn is_answered(&self, train_id: u32) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(&train_id)
+            .is_none_or(|train| train.report.is_some() && train.arrivals.is_complete())
+    }
+
+    /// Stops waiting for the train and returns what could be measured.
+    pub fn finish(&self, train_id: u32) -> CapacityEstimate {
+        match self.pending.lock().unwrap().remove(&train_id) {
+            Some(train) => CapacityEstimate {
+                upstream: train.report.and_then(|report| report.bytes_per_sec),
+                downstream: train.arrivals.bytes_per_sec(),
+            },
+            None => CapacityEstimate::default(),
+        }
+    }
+
+    /// Sends a probe train over `socket` and waits for the server's answer.
+    ///
+    /// The answer is delivered through `on_report` and `on_probe_packet` by
+    /// the task that receives from the socket.
+    pub async fn measure(
+        &self,
+        link: &str,
+        socket: &UdpSocket,
+        key: &Key,
+        client_id: ClientId,
+        usage: &UsageTracker,
+    ) -> anyhow::Result<CapacityEstimate> {
+        let train_id = self.start_train();
+        let mut train = Vec::with_capacity(TRAIN_LENGTH as usize);
+        for index in 0..TRAIN_LENGTH {
+            let probe = BandwidthProbe {
+                train_id,
+                index,
+                count: TRAIN_LENGTH,
+            };
+            let seq = bandwidth::train_sequence(train_id, index, false);
+            let header = PacketHeader::new(seq, PacketType::BandwidthProbe, client_id);
+            let header_bytes = bincode::serialize(&header)?;
+            let ciphertext = encrypt(key, &bandwidth::train_payload(&probe), seq)?;
+            train.push([header_bytes.as_slice(), ciphertext.as_slice()].concat());
+        }
+        // Packets are built up front so that they leave back to back.
+        for packet in &train {
+            if let Err(e) = socket.send(packet).await {
+                self.finish(train_id);
+                return Err(e.into());
+            }
+            usage.record(link, packet.len());
+        }
+
+        let deadline = Instant::now() + MEASUREMENT_TIMEOUT;
+        while !self.is_answered(train_id) && Instant::now() < deadline {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Ok(self.finish(train_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_combines_report_and_downstream_train() {
+        let estimator = BandwidthEstimator::new();
+        let train_id = estimator.start_train();
+        assert!(!estimator.is_answered(train_id));
+
+        estimator.on_report(BandwidthReport {
+            train_id,
+            received: TRAIN_LENGTH,
+            bytes_per_sec: Some(250_000.0),
+        });
+        let start = Instant::now();
+        for index in 0..TRAIN_LENGTH {
+            let probe = BandwidthProbe {
+                train_id,
+                index,
+                count: TRAIN_LENGTH,
+            };
+            let at = start + Duration::from_millis(index as u64);
+            estimator.on_probe_packet(&probe, 1000, at);
+        }
+        assert!(estimator.is_answered(train_id));
+
+        let estimate = estimator.finish(train_id);
+        assert_eq!(estimate.upstream, Some(250_000.0));
+        assert!((estimate.downstream.unwrap() - 1_000_000.0).abs() < 1.0);
+        assert_eq!(estimator.finish(train_id), CapacityEstimate::default());
+    }
+
+    #[test]
+    fn unanswered_train_yields_no_estimate() {
+        let estimator = BandwidthEstimator::new();
+        let train_id = estimator.start_train();
+        let other = estimator.start_train();
+        assert_ne!(train_id, other);
+
+        estimator.on_report(BandwidthReport {
+            train_id: other,
+            received: 3,
+            bytes_per_sec: None,
+        });
+        assert_eq!(estimator.finish(train_id), CapacityEstimate::default());
+        assert_eq!(estimator.finish(other), CapacityEstimate::default());
+    }
+}
@@ -0,0 +1,254 @@
+//! Extra per-link health checks: an ICMP echo, a TCP connect, an HTTP GET or
+//! a DNS lookup sent through the link's interface, to catch links that reach
+//! the server but not the rest of the internet, e.g. behind a captive portal
+//! or with broken DNS.
+//!
+//! Host names in TCP and HTTP checks are resolved by the system resolver,
+//! which isn't bound to the link and goes through the tunnel once it is up;
+//! only a DNS check tests name resolution over the link itself.
+
+use anyhow::{anyhow, bail, Context};
+use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+use onebox_core::types::HealthCheck;
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// The longest HTTP status line that is read.
+const MAX_STATUS_LINE: usize = 1024;
+
+/// The largest DNS response read.
+const MAX_DNS_RESPONSE: usize = 4096;
+
+/// Runs every check through `iface` and returns whether all of them passed.
+pub async fn run_all(checks: &[HealthCheck], iface: &str, timeout: Duration) -> bool {
+    let mut passed = true;
+    for check in checks {
+        let result = match tokio::time::timeout(timeout, run(check, iface)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out after {:?}", timeout)),
+        };
+        match result {
+            Ok(()) => debug!("Health check {:?} passed on {}", check, iface),
+            Err(e) => {
+                warn!("Health check {:?} failed on {}: {:#}", check, iface, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+async fn run(check: &HealthCheck, iface: &str) -> anyhow::Result<()> {
+    match check {
+        HealthCheck::Icmp { target } => ping(target, iface).await,
+        HealthCheck::Tcp { target } => connect(target, iface).await.map(drop),
+        HealthCheck::Http { url, expect_status } => {
+            let status = http_get(url, iface).await?;
+            if status != *expect_status {
+                bail!("status {} instead of {}", status, expect_status);
+            }
+            Ok(())
+        }
+        HealthCheck::Dns { name, server } => resolve(name, server, iface).await,
+    }
+}
+
+/// Sends one ICMP echo with `ping -I`, which binds to the interface.
+async fn ping(target: &str, iface: &str) -> anyhow::Result<()> {
+    let status = Command::new("ping")
+        .args(["-n", "-q", "-c", "1", "-I", iface, target])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .context("failed to run ping")?;
+    if !status.success() {
+        bail!("no echo reply ({})", status);
+    }
+    Ok(())
+}
+
+/// Opens a TCP connection to `target` (`host:port`) bound to `iface`.
+async fn connect(target: &str, iface: &str) -> anyhow::Result<TcpStream> {
+    let addr = lookup_host(target)
+        .await
+        .with_context(|| format!("failed to resolve {target}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{target} did not resolve to any address"))?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    setsockopt(&socket, BindToDevice, &OsString::from(iface))
+        .with_context(|| format!("failed to bind to device {iface}"))?;
+    Ok(socket.connect(addr).await?)
+}
+
+/// Looks up the A record of `name` at `server` through `iface`, and
+/// requires an address in the answer.
+async fn resolve(name: &str, server: &str, iface: &str) -> anyhow::Result<()> {
+    let server = parse_server(server)?;
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    setsockopt(&socket, BindToDevice, &OsString::from(iface))
+        .with_context(|| format!("failed to bind to device {iface}"))?;
+    socket.connect(server).await?;
+    let id = rand_id();
+    socket.send(&dns_query(id, name)).await?;
+
+    let mut buf = vec![0u8; MAX_DNS_RESPONSE];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        if buf[..len].get(..2) != Some(&id.to_be_bytes()[..]) {
+            continue;
+        }
+        let (_, addrs) = crate::dns::parse_response(&buf[..len])
+            .ok_or_else(|| anyhow!("{server} failed to resolve {name}"))?;
+        if addrs.is_empty() {
+            bail!("{server} returned no address for {name}");
+        }
+        return Ok(());
+    }
+}
+
+/// A DNS server given as an IP address, with port 53 unless another is set.
+fn parse_server(server: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = server.parse() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = server
+        .parse()
+        .with_context(|| format!("invalid DNS server {server}"))?;
+    Ok(SocketAddr::new(ip, 53))
+}
+
+/// A query ID that differs from one check to the next.
+fn rand_id() -> u16 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16
+}
+
+/// A recursive query for the A record of `name`.
+fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.extend([0, 0, 1, 0, 1]);
+    query
+}
+
+/// Sends a GET for `url` through `iface` and returns the response status.
+async fn http_get(url: &str, iface: &str) -> anyhow::Result<u16> {
+    let (host, port, path) = parse_url(url)?;
+    let mut stream = connect(&format!("{host}:{port}"), iface).await?;
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: onebox\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 256];
+    while !response.windows(2).any(|w| w == b"\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 || response.len() >= MAX_STATUS_LINE {
+            break;
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+    parse_status_line(&response)
+}
+
+/// Splits a plain-HTTP URL into host, port and path.
+fn parse_url(url: &str) -> anyhow::Result<(&str, u16, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("only http:// URLs are supported: {url}"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        bail!("no host in {url}");
+    }
+    Ok((host, port, path))
+}
+
+/// Reads the status code from the start of an HTTP response.
+fn parse_status_line(response: &[u8]) -> anyhow::Result<u16> {
+    let line = response
+        .split(|&b| b == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default();
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => status
+            .parse()
+            .with_context(|| format!("invalid status line: {line}")),
+        _ => bail!("invalid status line: {line}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_into_host_port_and_path() {
+        assert_eq!(
+            parse_url("http://connectivitycheck.gstatic.com/generate_204").unwrap(),
+            ("connectivitycheck.gstatic.com", 80, "/generate_204")
+        );
+        assert_eq!(
+            parse_url("http://10.0.0.1:8080").unwrap(),
+            ("10.0.0.1", 8080, "/")
+        );
+        assert!(parse_url("https://example.com/").is_err());
+        assert!(parse_url("http:///path").is_err());
+    }
+
+    #[test]
+    fn status_lines_are_parsed() {
+        let response = b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(parse_status_line(response).unwrap(), 204);
+        assert!(parse_status_line(b"<html>").is_err());
+        assert!(parse_status_line(b"").is_err());
+    }
+
+    #[test]
+    fn dns_queries_ask_for_the_a_record() {
+        assert_eq!(
+            dns_query(0x1234, "example.com."),
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+        assert_eq!(
+            parse_server("9.9.9.9").unwrap(),
+            "9.9.9.9:53".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_server("[2620:fe::fe]:5353").unwrap(),
+            "[2620:fe::fe]:5353".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_server("dns.example").is_err());
+    }
+}
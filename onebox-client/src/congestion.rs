@@ -0,0 +1,396 @@
+//! Per-link pacing and delay-based congestion control.
+//!
+//! Each link gets a token bucket whose refill rate is adapted from the RTTs
+//! of probes and of acknowledged data: the rate grows while the link is kept
+//! busy and its RTT stays close to the minimum observed RTT, and shrinks when
+//! queueing delay builds up or probes are lost. Data acknowledgements keep
+//! the rate moving when probes are suppressed, and far more often than
+//! probes do. The data path asks each link how long a packet
+//! would have to wait and prefers links that can send right away.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The slowest rate a link is ever paced at, in bytes per second.
+const MIN_RATE: f64 = 8_000.0;
+/// The rate a link starts at, in bytes per second (10 Mbit/s).
+const INITIAL_RATE: f64 = 1_250_000.0;
+/// How long a minimum RTT sample stays valid before it may be replaced.
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// The bucket holds this much time worth of sending at the current rate.
+const BURST_DURATION: Duration = Duration::from_millis(10);
+/// The bucket always holds at least a few full-size packets.
+const MIN_BURST_BYTES: f64 = 4.0 * 1500.0;
+
+const INCREASE_FACTOR: f64 = 1.25;
+const DELAY_DECREASE_FACTOR: f64 = 0.85;
+const LOSS_DECREASE_FACTOR: f64 = 0.7;
+/// How far above a measured capacity the rate may still grow, to leave room
+/// for estimates that came out low.
+const CAPACITY_HEADROOM: f64 = 1.5;
+
+/// Settings shared by every link's pacer.
+#[derive(Debug, Clone, Copy)]
+pub struct PacerConfig {
+    /// Whether pacing is enabled at all. When disabled, sends are never delayed.
+    pub enabled: bool,
+    /// Queueing delay above the minimum RTT at which the rate is reduced.
+    pub queue_delay_target: Duration,
+    /// Upper bound on the rate, in bytes per second.
+    pub max_rate: Option<f64>,
+}
+
+/// A token bucket pacer for a single link.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    config: PacerConfig,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    min_rtt: Option<(Duration, Instant)>,
+    /// The link capacity last measured by a bandwidth probe, in bytes per second.
+    capacity: Option<f64>,
+    /// Whether the bucket ran dry since the last RTT sample, i.e. the link
+    /// was actually limited by the pacer rather than by the offered load.
+    rate_limited: bool,
+}
+
+impl Pacer {
+    /// Creates a pacer starting at the initial rate with a full bucket.
+    pub fn new(config: PacerConfig, now: Instant) -> Self {
+        let rate = config
+            .max_rate
+            .map_or(INITIAL_RATE, |max| INITIAL_RATE.min(max));
+        let mut pacer = Self {
+            config,
+            rate,
+            tokens: 0.0,
+            last_refill: now,
+            min_rtt: None,
+            capacity: None,
+            rate_limited: false,
+        };
+        pacer.tokens = pacer.burst();
+        pacer
+    }
+
+    /// The current pacing rate in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// The smallest RTT seen within the last `MIN_RTT_WINDOW`.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt.map(|(rtt, _)| rtt)
+    }
+
+    fn burst(&self) -> f64 {
+        (self.rate * BURST_DURATION.as_secs_f64()).max(MIN_BURST_BYTES)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst());
+        self.last_refill = now;
+    }
+
+    /// How long a packet of `bytes` would have to wait before it may be sent.
+    pub fn delay_for(&mut self, bytes: usize, now: Instant) -> Duration {
+        if !self.config.enabled {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        let deficit = bytes as f64 - self.tokens;
+        if deficit <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(deficit / self.rate)
+        }
+    }
+
+    /// Whether the link cannot take a packet of `bytes` right now.
+    pub fn is_saturated(&mut self, bytes: usize, now: Instant) -> bool {
+        !self.delay_for(bytes, now).is_zero()
+    }
+
+    /// Takes `bytes` out of the bucket and returns how long the caller must
+    /// wait before actually sending them.
+    pub fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let delay = self.delay_for(bytes, now);
+        if self.config.enabled {
+            self.tokens -= bytes as f64;
+            if !delay.is_zero() {
+                self.rate_limited = true;
+            }
+        }
+        delay
+    }
+
+    /// Adapts the rate to a new RTT sample.
+    pub fn on_rtt_sample(&mut self, rtt: Duration, now: Instant) {
+        let min_rtt = match self.min_rtt {
+            Some((min, at)) if min <= rtt && now.duration_since(at) < MIN_RTT_WINDOW => min,
+            _ => {
+                self.min_rtt = Some((rtt, now));
+                rtt
+            }
+        };
+
+        let queue_delay = rtt.saturating_sub(min_rtt);
+        if queue_delay > self.config.queue_delay_target {
+            self.set_rate(self.rate * DELAY_DECREASE_FACTOR);
+        } else if self.rate_limited {
+            self.set_rate(self.rate * INCREASE_FACTOR);
+        }
+        self.rate_limited = false;
+    }
+
+    /// Backs off after a probe was lost.
+    pub fn on_loss(&mut self) {
+        self.set_rate(self.rate * LOSS_DECREASE_FACTOR);
+    }
+
+    /// Jumps to a freshly measured link capacity, in bytes per second, and
+    /// keeps the rate from growing far beyond it.
+    pub fn set_capacity(&mut self, capacity: f64) {
+        self.capacity = Some(capacity);
+        self.set_rate(capacity);
+    }
+
+    fn set_rate(&mut self, rate: f64) {
+        let rate = rate.max(MIN_RATE);
+        let rate = self.config.max_rate.map_or(rate, |max| rate.min(max));
+        self.rate = self
+            .capacity
+            .map_or(rate, |capacity| rate.min(capacity * CAPACITY_HEADROOM));
+    }
+}
+
+/// The pacers of every link, shared between the data path and the prober.
+pub struct CongestionControl {
+    config: PacerConfig,
+    pacers: RwLock<HashMap<String, Arc<Mutex<Pacer>>>>,
+}
+
+impl CongestionControl {
+    /// Creates an empty set of pacers using `config` for every link.
+    pub fn new(config: PacerConfig) -> Self {
+        Self {
+            config,
+            pacers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Starts pacing a link, optionally capping its rate in bytes per second.
+    pub fn register(&self, name: &str, max_rate: Option<f64>) {
+        let config = PacerConfig {
+            max_rate: max_rate.or(self.config.max_rate),
+            ..self.config
+        };
+        self.pacers.write().unwrap().insert(
+            name.to_string(),
+            Arc::new(Mutex::new(Pacer::new(config, Instant::now()))),
+        );
+    }
+
+    /// Stops pacing a link.
+    pub fn unregister(&self, name: &str) {
+        self.pacers.write().unwrap().remove(name);
+    }
+
+    /// Runs `f` against the pacer of the named link, if it is registered.
+    pub fn with_pacer<T>(&self, name: &str, f: impl FnOnce(&mut Pacer) -> T) -> Option<T> {
+        let pacer = self.pacers.read().unwrap().get(name).cloned()?;
+        let mut pacer = pacer.lock().unwrap();
+        Some(f(&mut pacer))
+    }
+
+    /// Feeds an RTT sample measured on the named link.
+    pub fn on_rtt_sample(&self, name: &str, rtt: Duration) {
+        self.with_pacer(name, |pacer| pacer.on_rtt_sample(rtt, Instant::now()));
+    }
+
+    /// Reports a lost probe on the named link.
+    pub fn on_loss(&self, name: &str) {
+        self.with_pacer(name, Pacer::on_loss);
+    }
+
+    /// Feeds a capacity measured on the named link, in bytes per second.
+    pub fn set_capacity(&self, name: &str, capacity: f64) {
+        self.with_pacer(name, |pacer| pacer.set_capacity(capacity));
+    }
+
+    /// The current pacing rate of the named link, in bytes per second.
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        self.with_pacer(name, |pacer| pacer.rate())
+    }
+
+    /// Picks the link to send `bytes` on and reserves them on its pacer.
+    ///
+    /// Links are tried in round-robin order starting at `start`, and the
+    /// first one that is not saturated wins. If every link is saturated, the
+    /// one that frees up soonest is chosen. Returns the index of the chosen
+    /// link and how long to wait before sending on it.
+    pub fn schedule<T>(
+        &self,
+        links: &[(String, T)],
+        start: usize,
+        bytes: usize,
+    ) -> Option<(usize, Duration)> {
+        let now = Instant::now();
+        let mut best: Option<(usize, Duration)> = None;
+        for offset in 0..links.len() {
+            let index = (start + offset) % links.len();
+            let delay = self
+                .with_pacer(&links[index].0, |pacer| pacer.delay_for(bytes, now))
+                .unwrap_or_default();
+            if best.is_none_or(|(_, best_delay)| delay < best_delay) {
+                best = Some((index, delay));
+            }
+            if delay.is_zero() {
+                break;
+            }
+        }
+        let (index, _) = best?;
+        let delay = self
+            .with_pacer(&links[index].0, |pacer| pacer.reserve(bytes, now))
+            .unwrap_or_default();
+        Some((index, delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::SendLog;
+
+    const CONFIG: PacerConfig = PacerConfig {
+        enabled: true,
+        queue_delay_target: Duration::from_millis(50),
+        max_rate: None,
+    };
+
+    #[test]
+    fn bucket_paces_sends_at_the_current_rate() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, now);
+        let burst = pacer.burst() as usize;
+        assert_eq!(pacer.reserve(burst, now), Duration::ZERO);
+        assert!(pacer.is_saturated(1500, now));
+
+        let delay = pacer.reserve(12_500, now);
+        assert_eq!(delay, Duration::from_millis(10));
+        assert!(!pacer.is_saturated(1500, now + Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn disabled_pacer_never_delays() {
+        let now = Instant::now();
+        let config = PacerConfig {
+            enabled: false,
+            ..CONFIG
+        };
+        let mut pacer = Pacer::new(config, now);
+        assert_eq!(pacer.reserve(10_000_000, now), Duration::ZERO);
+        assert!(!pacer.is_saturated(1500, now));
+    }
+
+    #[test]
+    fn rate_grows_only_while_rate_limited() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, now);
+        pacer.on_rtt_sample(Duration::from_millis(20), now);
+        assert_eq!(pacer.rate(), INITIAL_RATE);
+
+        pacer.reserve(1_000_000, now);
+        pacer.on_rtt_sample(Duration::from_millis(25), now);
+        assert_eq!(pacer.rate(), INITIAL_RATE * INCREASE_FACTOR);
+    }
+
+    #[test]
+    fn queueing_delay_reduces_rate() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, now);
+        pacer.on_rtt_sample(Duration::from_millis(20), now);
+        pacer.on_rtt_sample(Duration::from_millis(200), now);
+        assert_eq!(pacer.rate(), INITIAL_RATE * DELAY_DECREASE_FACTOR);
+        assert_eq!(pacer.min_rtt(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn min_rtt_expires() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, now);
+        pacer.on_rtt_sample(Duration::from_millis(20), now);
+        let later = now + MIN_RTT_WINDOW;
+        pacer.on_rtt_sample(Duration::from_millis(80), later);
+        assert_eq!(pacer.min_rtt(), Some(Duration::from_millis(80)));
+    }
+
+    #[test]
+    fn schedule_skips_saturated_links() {
+        let cc = CongestionControl::new(CONFIG);
+        cc.register("eth0", None);
+        cc.register("wlan0", None);
+        let links = [("eth0".to_string(), ()), ("wlan0".to_string(), ())];
+
+        assert_eq!(cc.schedule(&links, 0, 1500), Some((0, Duration::ZERO)));
+        cc.with_pacer("eth0", |pacer| pacer.reserve(1_000_000, Instant::now()));
+        assert_eq!(cc.schedule(&links, 0, 1500), Some((1, Duration::ZERO)));
+
+        cc.with_pacer("wlan0", |pacer| pacer.reserve(2_000_000, Instant::now()));
+        let (index, delay) = cc.schedule(&links, 1, 1500).unwrap();
+        assert_eq!(index, 0);
+        assert!(!delay.is_zero());
+        assert_eq!(cc.schedule::<()>(&[], 0, 1500), None);
+    }
+
+    #[test]
+    fn acknowledged_data_grows_the_rate_with_probes_suppressed() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, start);
+        let mut log = SendLog::new(Duration::from_secs(1));
+        let mut now = start;
+        for seq in 0..20 {
+            // Data is offered faster than the pacer lets it out, and each
+            // batch is acknowledged like the server does.
+            now += pacer.reserve(1_000_000, now);
+            log.on_sent(seq, now);
+            now += Duration::from_millis(20);
+            let rtt = log.on_ack(seq, now).unwrap();
+            pacer.on_rtt_sample(rtt, now);
+        }
+        assert!(pacer.rate() > INITIAL_RATE * 10.0);
+    }
+
+    #[test]
+    fn measured_capacity_sets_and_bounds_the_rate() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(CONFIG, now);
+        pacer.set_capacity(400_000.0);
+        assert_eq!(pacer.rate(), 400_000.0);
+        for _ in 0..10 {
+            pacer.reserve(10_000_000, now);
+            pacer.on_rtt_sample(Duration::from_millis(20), now);
+        }
+        assert_eq!(pacer.rate(), 400_000.0 * CAPACITY_HEADROOM);
+    }
+
+    #[test]
+    fn loss_backs_off_but_respects_bounds() {
+        let now = Instant::now();
+        let config = PacerConfig {
+            max_rate: Some(100_000.0),
+            ..CONFIG
+        };
+        let mut pacer = Pacer::new(config, now);
+        assert_eq!(pacer.rate(), 100_000.0);
+        for _ in 0..100 {
+            pacer.on_loss();
+        }
+        assert_eq!(pacer.rate(), MIN_RATE);
+    }
+}
@@ -0,0 +1,208 @@
+//! A DNS forwarder that learns the addresses of names matching the split
+//! tunnel's domain rules.
+//!
+//! Queries are passed on to the upstream resolver as they are. Answers are
+//! read for A and AAAA records, and the addresses of a matching name are
+//! routed before the answer goes back, so the first connection to them
+//! already takes the right path.
+
+use crate::split::SplitTunnel;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, info};
+
+/// The largest DNS message over UDP with EDNS.
+const MAX_MESSAGE: usize = 4096;
+
+/// How long the upstream resolver has to answer.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Answers DNS queries on `listen` by asking `upstream`, until the socket
+/// fails.
+pub async fn run_forwarder(listen: SocketAddr, upstream: SocketAddr, split: Arc<SplitTunnel>) {
+    let socket = match UdpSocket::bind(listen).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            error!("Failed to bind DNS forwarder to {}: {}", listen, e);
+            return;
+        }
+    };
+    info!(
+        "DNS forwarder listening on {}, upstream {}",
+        listen, upstream
+    );
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("DNS forwarder receive error: {}", e);
+                return;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let split = split.clone();
+        tokio::spawn(async move {
+            match forward(&query, upstream).await {
+                Ok(response) => {
+                    if let Some((name, addrs)) = parse_response(&response) {
+                        split.learn(&name, &addrs).await;
+                    }
+                    let _ = socket.send_to(&response, client).await;
+                }
+                Err(e) => debug!("DNS query from {} failed: {}", client, e),
+            }
+        });
+    }
+}
+
+async fn forward(query: &[u8], upstream: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await??;
+        // Only the answer to this query, with the same ID, is passed on.
+        if len >= 2 && query.len() >= 2 && buf[..2] == query[..2] {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+/// The name asked about in a successful DNS response, and the addresses in
+/// its A and AAAA answers, which may follow CNAMEs, with their TTLs in
+/// seconds.
+pub(crate) fn parse_response(msg: &[u8]) -> Option<(String, Vec<(IpAddr, u32)>)> {
+    let flags = read_u16(msg, 2)?;
+    let is_response = flags & 0x8000 != 0;
+    let rcode = flags & 0x000f;
+    if !is_response || rcode != 0 {
+        return None;
+    }
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    if questions == 0 {
+        return None;
+    }
+    let (name, mut pos) = read_name(msg, 12)?;
+    pos += 4;
+    for _ in 1..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let kind = read_u16(msg, pos)?;
+        let ttl = u32::from(read_u16(msg, pos + 4)?) << 16 | u32::from(read_u16(msg, pos + 6)?);
+        let len = usize::from(read_u16(msg, pos + 8)?);
+        let data = msg.get(pos + 10..pos + 10 + len)?;
+        match (kind, len) {
+            (TYPE_A, 4) => addrs.push((IpAddr::from(<[u8; 4]>::try_from(data).ok()?), ttl)),
+            (TYPE_AAAA, 16) => addrs.push((IpAddr::from(<[u8; 16]>::try_from(data).ok()?), ttl)),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Some((name, addrs))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and the position
+/// after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Each pointer must go backwards, which rules out loops.
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let target = usize::from(read_u16(msg, pos)? & 0x3fff);
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            len if len & 0xc0 == 0 => {
+                let label = msg.get(pos + 1..pos + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + usize::from(len);
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response for www.example.com: a CNAME to example.com, compressed,
+    /// then an A and an AAAA record for it.
+    fn response() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        msg.extend(b"\x03www\x07Example\x03com\x00");
+        msg.extend([0, 1, 0, 1]);
+        // www.example.com CNAME example.com
+        msg.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+        // example.com A 93.184.216.34
+        msg.extend([0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        // example.com AAAA 2606:2800::1, with a TTL of 300
+        msg.extend([0xc0, 16, 0, 28, 0, 1, 0, 0, 1, 44, 0, 16]);
+        msg.extend([0x26, 0x06, 0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        msg
+    }
+
+    #[test]
+    fn answers_are_read_for_the_question_name() {
+        let (name, addrs) = parse_response(&response()).unwrap();
+        assert_eq!(name, "www.example.com");
+        assert_eq!(
+            addrs,
+            [
+                ("93.184.216.34".parse::<IpAddr>().unwrap(), 60),
+                ("2606:2800::1".parse().unwrap(), 300)
+            ]
+        );
+    }
+
+    #[test]
+    fn queries_failures_and_malformed_messages_are_ignored() {
+        let mut query = response();
+        query[2] = 0x01;
+        assert!(parse_response(&query).is_none());
+
+        let mut nxdomain = response();
+        nxdomain[3] = 0x83;
+        assert!(parse_response(&nxdomain).is_none());
+
+        let full = response();
+        assert!(parse_response(&full[..full.len() - 3]).is_none());
+
+        // A name pointing at itself.
+        let mut looped = full[..12].to_vec();
+        looped.extend([0xc0, 12, 0, 1, 0, 1]);
+        assert!(parse_response(&looped).is_none());
+    }
+}
@@ -1,68 +1,639 @@
 //! Health monitoring for network links.
 
-use std::collections::HashMap;
+use crate::bandwidth::CapacityEstimate;
+use crate::usage::CapState;
+use onebox_core::health::LinkMonitor;
+pub use onebox_core::health::{DataFailure, HealthPolicy, LinkStatus, StatusChange};
+use onebox_core::types::LinkConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
 use std::time::{Duration, Instant};
 
-/// Represents the status of a network link.
+/// The fewest packets a delivery window must span before its loss is updated.
+const MIN_DELIVERY_SAMPLE: u64 = 10;
+
+/// Which way traffic flows over a link.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum LinkStatus {
-    /// The link is considered up and healthy.
-    Up,
-    /// The link is considered down due to probe failures.
-    Down,
-    /// The link is in an unknown state, awaiting first probe result.
-    Unknown,
+pub enum Direction {
+    /// From the client to the server.
+    Upstream,
+    /// From the server to the client.
+    Downstream,
+}
+
+/// Loss in one direction of a link, measured by comparing the packet counters
+/// of the sending and the receiving end.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeliveryWindow {
+    last: Option<(u64, u64)>,
+    /// Packet loss over the most recent window, in percent.
+    pub loss_percent: Option<f32>,
+}
+
+impl DeliveryWindow {
+    /// Feeds the cumulative number of packets one end has sent and the other
+    /// end has received. Loss is computed over the packets sent since the
+    /// previous update, once there are enough of them.
+    pub fn update(&mut self, sent: u64, received: u64) {
+        let Some((last_sent, last_received)) = self.last else {
+            self.last = Some((sent, received));
+            return;
+        };
+        if sent < last_sent || received < last_received {
+            // One end restarted and its counters with it.
+            self.last = Some((sent, received));
+            return;
+        }
+        let sent_delta = sent - last_sent;
+        if sent_delta < MIN_DELIVERY_SAMPLE {
+            return;
+        }
+        let received_delta = (received - last_received).min(sent_delta);
+        self.loss_percent = Some((sent_delta - received_delta) as f32 / sent_delta as f32 * 100.0);
+        self.last = Some((sent, received));
+    }
+}
+
+/// Packets sent and received per link, counted on the data path and used for
+/// per-direction loss and to tell idle links from busy ones.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    links: RwLock<HashMap<String, LinkCounters>>,
+}
+
+#[derive(Debug, Default)]
+struct LinkCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Data packets only, in either direction.
+    data: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts counting packets of a link.
+    pub fn register(&self, name: &str) {
+        self.links
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default();
+    }
+
+    /// Stops counting packets of a link.
+    pub fn unregister(&self, name: &str) {
+        self.links.write().unwrap().remove(name);
+    }
+
+    /// Counts a packet sent on the link.
+    pub fn count_sent(&self, name: &str) {
+        if let Some(counters) = self.links.read().unwrap().get(name) {
+            counters.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a packet received on the link.
+    pub fn count_received(&self, name: &str) {
+        if let Some(counters) = self.links.read().unwrap().get(name) {
+            counters.received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a data packet sent or received on the link, on top of
+    /// `count_sent` or `count_received`.
+    pub fn count_data(&self, name: &str) {
+        if let Some(counters) = self.links.read().unwrap().get(name) {
+            counters.data.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The data packets sent and received on the link so far.
+    pub fn data_packets(&self, name: &str) -> Option<u64> {
+        self.links
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|counters| counters.data.load(Ordering::Relaxed))
+    }
+
+    /// The packets sent and received on the link so far.
+    pub fn get(&self, name: &str) -> Option<(u64, u64)> {
+        self.links.read().unwrap().get(name).map(|counters| {
+            (
+                counters.sent.load(Ordering::Relaxed),
+                counters.received.load(Ordering::Relaxed),
+            )
+        })
+    }
 }
 
-/// The number of consecutive probe failures before a link is marked as Down.
-pub const MAX_CONSECUTIVE_FAILURES: u32 = 4;
+/// Data packets recently sent on a link that the server has not yet
+/// acknowledged.
+#[derive(Debug)]
+pub struct SendLog {
+    unacked: VecDeque<(u64, Instant)>,
+    last_ack: Option<Instant>,
+    timeout: Duration,
+    /// Whether a failure seen on the link is still being applied.
+    failure_pending: bool,
+}
+
+/// How many unacknowledged data packets a `SendLog` remembers. Beyond this
+/// the oldest are forgotten, which at worst delays stall detection.
+const MAX_UNACKED: usize = 4096;
+
+impl SendLog {
+    /// Creates a log that considers data stalled once it has gone
+    /// unacknowledged for `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            unacked: VecDeque::new(),
+            last_ack: None,
+            timeout,
+            failure_pending: false,
+        }
+    }
+
+    /// Records a data packet sent at `now`. Returns true if the oldest
+    /// unacknowledged packet has waited longer than the timeout, in which
+    /// case the log starts over so that the stall is only reported once.
+    ///
+    /// Only a link that keeps carrying data can stall: after a pause in
+    /// sending longer than the timeout, the log starts over too.
+    pub fn on_sent(&mut self, seq: u64, now: Instant) -> bool {
+        if self
+            .unacked
+            .back()
+            .is_some_and(|&(_, sent_at)| now.duration_since(sent_at) > self.timeout)
+        {
+            self.unacked.clear();
+        }
+        let stalled = self
+            .unacked
+            .front()
+            .is_some_and(|&(_, sent_at)| now.duration_since(sent_at) > self.timeout);
+        if stalled {
+            self.unacked.clear();
+            return true;
+        }
+        if self.unacked.len() == MAX_UNACKED {
+            self.unacked.pop_front();
+        }
+        self.unacked.push_back((seq, now));
+        false
+    }
+
+    /// Records an acknowledgement of every packet up to `highest`. Earlier
+    /// packets that were lost count as acknowledged: a later one got through.
+    /// Returns the RTT of packet `highest` if it was still unacknowledged,
+    /// which includes up to a `DATA_ACK_INTERVAL` of acknowledgement delay.
+    pub fn on_ack(&mut self, highest: u64, now: Instant) -> Option<Duration> {
+        let mut rtt = None;
+        while let Some(&(seq, sent_at)) = self.unacked.front().filter(|&&(seq, _)| seq <= highest) {
+            if seq == highest {
+                rtt = Some(now.saturating_duration_since(sent_at));
+            }
+            self.unacked.pop_front();
+        }
+        self.last_ack = Some(now);
+        rtt
+    }
+
+    /// When the server last acknowledged data from the link.
+    pub fn last_ack(&self) -> Option<Instant> {
+        self.last_ack
+    }
+
+    /// Changes how long data may go unacknowledged.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+/// The `SendLog` of every passively monitored link.
+#[derive(Debug, Default)]
+pub struct DataAcks {
+    links: RwLock<HashMap<String, Mutex<SendLog>>>,
+}
+
+impl DataAcks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts watching data sent on a link.
+    pub fn register(&self, name: &str, timeout: Duration) {
+        self.links
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Mutex::new(SendLog::new(timeout)));
+    }
+
+    /// Stops watching a link.
+    pub fn unregister(&self, name: &str) {
+        self.links.write().unwrap().remove(name);
+    }
+
+    fn with_log<T>(&self, name: &str, f: impl FnOnce(&mut SendLog) -> T) -> Option<T> {
+        let links = self.links.read().unwrap();
+        let mut log = links.get(name)?.lock().unwrap();
+        Some(f(&mut log))
+    }
+
+    /// Records a data packet sent on the link; see `SendLog::on_sent`.
+    pub fn on_sent(&self, name: &str, seq: u64, now: Instant) -> bool {
+        self.with_log(name, |log| log.on_sent(seq, now))
+            .unwrap_or(false)
+    }
+
+    /// Records an acknowledgement received on the link; see
+    /// `SendLog::on_ack`.
+    pub fn on_ack(&self, name: &str, highest: u64, now: Instant) -> Option<Duration> {
+        self.with_log(name, |log| log.on_ack(highest, now))
+            .flatten()
+    }
+
+    /// When data sent on the link was last acknowledged.
+    pub fn last_ack(&self, name: &str) -> Option<Instant> {
+        self.with_log(name, |log| log.last_ack()).flatten()
+    }
+
+    /// Changes how long data on the link may go unacknowledged.
+    pub fn set_timeout(&self, name: &str, timeout: Duration) {
+        self.with_log(name, |log| log.set_timeout(timeout));
+    }
+
+    /// Claims the right to apply a failure seen on the link. Returns false
+    /// while an earlier failure is still being applied, or if the link isn't
+    /// watched, so a link failing every send queues at most one.
+    pub fn begin_failure(&self, name: &str) -> bool {
+        self.with_log(name, |log| {
+            !std::mem::replace(&mut log.failure_pending, true)
+        })
+        .unwrap_or(false)
+    }
+
+    /// Marks the failure claimed with `begin_failure` as applied.
+    pub fn end_failure(&self, name: &str) {
+        self.with_log(name, |log| log.failure_pending = false);
+    }
+}
 
 /// Holds health statistics for a single network link.
 #[derive(Debug, Clone)]
 pub struct LinkStats {
-    /// The current status of the link.
-    pub status: LinkStatus,
-    /// The last measured Round-Trip Time (RTT).
-    pub rtt: Duration,
-    /// The number of probes sent.
-    pub probes_sent: u64,
-    /// The number of probe echoes received.
-    pub probes_received: u64,
-    /// The number of consecutive probes that have failed (timed out).
-    pub consecutive_failures: u32,
-    /// A map of sent probe sequence numbers to the time they were sent.
-    pub in_flight_probes: HashMap<u64, Instant>,
-    /// The next sequence number to use for a probe on this link.
-    pub next_probe_seq: u64,
+    /// Probe-driven health state of the link.
+    pub monitor: LinkMonitor,
+    /// The priority tier of this link (lower number = higher priority).
+    pub priority: u8,
+    /// Relative cost of data on the link; capacity-derived weights are
+    /// divided by it.
+    pub cost: u32,
+    /// Where the link stands relative to its data caps.
+    pub cap_state: CapState,
+    /// The last measured link capacity, in both directions.
+    pub capacity: CapacityEstimate,
+    /// Loss of packets sent by the client over this link.
+    pub upstream: DeliveryWindow,
+    /// Loss of packets sent by the server over this link.
+    pub downstream: DeliveryWindow,
 }
 
 impl LinkStats {
-    /// Creates a new `LinkStats` with default values.
-    pub fn new() -> Self {
+    /// Creates a new `LinkStats` for a link in the given priority tier,
+    /// judged by `policy`.
+    pub fn new(priority: u8, policy: HealthPolicy) -> Self {
         Self {
-            status: LinkStatus::Unknown,
-            rtt: Duration::default(),
-            probes_sent: 0,
-            probes_received: 0,
-            consecutive_failures: 0,
-            in_flight_probes: HashMap::new(),
-            next_probe_seq: 0,
+            monitor: LinkMonitor::new(policy),
+            priority,
+            cost: 1,
+            capacity: CapacityEstimate::default(),
+            upstream: DeliveryWindow::default(),
+            downstream: DeliveryWindow::default(),
+            cap_state: CapState::Normal,
         }
     }
 
-    /// Calculates the current packet loss percentage.
-    pub fn packet_loss_percent(&self) -> f32 {
-        if self.probes_sent == 0 {
-            0.0
-        } else {
-            let loss = self.probes_sent.saturating_sub(self.probes_received);
-            (loss as f32 / self.probes_sent as f32) * 100.0
+    /// The current status of the link.
+    pub fn status(&self) -> LinkStatus {
+        self.monitor.status
+    }
+
+    /// The priority tier used for scheduling. A link past its soft data cap
+    /// is demoted to the lowest tier, so it only carries data as a last resort.
+    pub fn effective_priority(&self) -> u8 {
+        match self.cap_state {
+            CapState::Normal => self.priority,
+            CapState::Soft | CapState::Hard => u8::MAX,
         }
     }
+
+    /// Whether the link may be placed in the active pool at all.
+    pub fn is_eligible(&self) -> bool {
+        self.status() != LinkStatus::Down && self.cap_state != CapState::Hard
+    }
+
+    /// The scheduling weight of the link in one direction: the configured
+    /// weight if any, otherwise the measured capacity in Mbit/s divided by
+    /// the link's cost. Either is scaled down by the loss seen in that
+    /// direction.
+    pub fn weight(&self, direction: Direction, configured: Option<u32>) -> u32 {
+        let (capacity, delivery) = match direction {
+            Direction::Upstream => (self.capacity.upstream, &self.upstream),
+            Direction::Downstream => (self.capacity.downstream, &self.downstream),
+        };
+        let base = match (configured, capacity) {
+            (Some(0), _) => return 0,
+            (Some(weight), _) => weight as f64,
+            (None, Some(capacity)) => capacity * 8.0 / 1_000_000.0 / self.cost.max(1) as f64,
+            (None, None) => 1.0,
+        };
+        let delivered = 1.0 - delivery.loss_percent.unwrap_or(0.0) as f64 / 100.0;
+        let health = match self.status() {
+            LinkStatus::Degraded => self.monitor.policy().degraded_weight_percent as f64 / 100.0,
+            _ => 1.0,
+        };
+        ((base * delivered * health).round() as u32).max(1)
+    }
 }
 
 impl Default for LinkStats {
     fn default() -> Self {
-        Self::new()
+        Self::new(LinkConfig::default().priority, HealthPolicy::default())
+    }
+}
+
+/// Controls when traffic returns to a higher-priority tier after it recovers.
+#[derive(Debug, Clone, Copy)]
+pub struct FailbackPolicy {
+    /// Whether to move back to a recovered higher-priority tier at all.
+    pub enabled: bool,
+    /// How long a recovered link must stay up before traffic moves back to it.
+    pub delay: Duration,
+}
+
+/// Chooses the priority tier that should carry data traffic.
+///
+/// Links that are neither Down nor past a hard data cap are eligible, and the
+/// best (lowest) eligible tier normally wins. While a lower tier is carrying traffic, a recovered
+/// higher-priority tier only takes over again once one of its links has been
+/// Up for `policy.delay`, and never if failback is disabled.
+pub fn select_active_tier(
+    links: &HashMap<String, LinkStats>,
+    current: Option<u8>,
+    policy: &FailbackPolicy,
+    now: Instant,
+) -> Option<u8> {
+    let eligible = |tier: u8| {
+        links
+            .values()
+            .any(|s| s.effective_priority() == tier && s.is_eligible())
+    };
+    let best = links
+        .values()
+        .filter(|s| s.is_eligible())
+        .map(|s| s.effective_priority())
+        .min()?;
+
+    match current {
+        Some(current) if current > best && eligible(current) => {
+            let recovered = links.values().any(|s| {
+                s.effective_priority() == best
+                    && s.status() == LinkStatus::Up
+                    && s.monitor
+                        .up_since
+                        .is_some_and(|t| now.duration_since(t) >= policy.delay)
+            });
+            if policy.enabled && recovered {
+                Some(best)
+            } else {
+                Some(current)
+            }
+        }
+        _ => Some(best),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: FailbackPolicy = FailbackPolicy {
+        enabled: true,
+        delay: Duration::from_secs(10),
+    };
+
+    fn link(priority: u8, status: LinkStatus, up_since: Option<Instant>) -> LinkStats {
+        let mut stats = LinkStats::new(priority, HealthPolicy::default());
+        stats.monitor.status = status;
+        stats.monitor.up_since = up_since;
+        stats
+    }
+
+    #[test]
+    fn best_tier_is_selected_initially() {
+        let now = Instant::now();
+        let links = HashMap::from([
+            ("eth0".to_string(), link(100, LinkStatus::Unknown, None)),
+            ("wwan0".to_string(), link(200, LinkStatus::Unknown, None)),
+        ]);
+        assert_eq!(select_active_tier(&links, None, &POLICY, now), Some(100));
+    }
+
+    #[test]
+    fn backup_tier_takes_over_when_primary_is_down() {
+        let now = Instant::now();
+        let links = HashMap::from([
+            ("eth0".to_string(), link(100, LinkStatus::Down, None)),
+            ("wwan0".to_string(), link(200, LinkStatus::Up, Some(now))),
+        ]);
+        assert_eq!(
+            select_active_tier(&links, Some(100), &POLICY, now),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn failback_waits_for_delay() {
+        let now = Instant::now();
+        let mut links = HashMap::from([
+            ("eth0".to_string(), link(100, LinkStatus::Up, Some(now))),
+            ("wwan0".to_string(), link(200, LinkStatus::Up, Some(now))),
+        ]);
+        assert_eq!(
+            select_active_tier(&links, Some(200), &POLICY, now),
+            Some(200)
+        );
+
+        let later = now + Duration::from_secs(11);
+        assert_eq!(
+            select_active_tier(&links, Some(200), &POLICY, later),
+            Some(100)
+        );
+
+        links.get_mut("wwan0").unwrap().monitor.status = LinkStatus::Down;
+        assert_eq!(
+            select_active_tier(&links, Some(200), &POLICY, now),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn failback_disabled_stays_on_backup() {
+        let now = Instant::now();
+        let links = HashMap::from([
+            ("eth0".to_string(), link(100, LinkStatus::Up, Some(now))),
+            ("wwan0".to_string(), link(200, LinkStatus::Up, Some(now))),
+        ]);
+        let policy = FailbackPolicy {
+            enabled: false,
+            ..POLICY
+        };
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            select_active_tier(&links, Some(200), &policy, later),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn capped_links_are_demoted_or_excluded() {
+        let now = Instant::now();
+        let mut links = HashMap::from([
+            ("eth0".to_string(), link(100, LinkStatus::Down, None)),
+            ("wwan0".to_string(), link(200, LinkStatus::Up, Some(now))),
+            ("wwan1".to_string(), link(200, LinkStatus::Up, Some(now))),
+        ]);
+        links.get_mut("wwan0").unwrap().cap_state = CapState::Soft;
+        assert_eq!(select_active_tier(&links, None, &POLICY, now), Some(200));
+
+        links.get_mut("wwan1").unwrap().cap_state = CapState::Hard;
+        assert_eq!(
+            select_active_tier(&links, None, &POLICY, now),
+            Some(u8::MAX)
+        );
+
+        links.get_mut("wwan0").unwrap().cap_state = CapState::Hard;
+        assert_eq!(select_active_tier(&links, None, &POLICY, now), None);
+    }
+
+    #[test]
+    fn no_tier_when_all_links_are_down() {
+        let links = HashMap::from([("eth0".to_string(), link(100, LinkStatus::Down, None))]);
+        assert_eq!(
+            select_active_tier(&links, Some(100), &POLICY, Instant::now()),
+            None
+        );
+    }
+
+    #[test]
+    fn delivery_window_measures_loss_per_interval() {
+        let mut window = DeliveryWindow::default();
+        window.update(100, 90);
+        assert_eq!(window.loss_percent, None);
+        window.update(105, 95);
+        assert_eq!(window.loss_percent, None);
+        window.update(200, 170);
+        assert_eq!(window.loss_percent, Some(20.0));
+        window.update(300, 270);
+        assert_eq!(window.loss_percent, Some(0.0));
+
+        // A restarted peer resets the baseline instead of reporting loss.
+        window.update(310, 5);
+        assert_eq!(window.loss_percent, Some(0.0));
+        window.update(410, 55);
+        assert_eq!(window.loss_percent, Some(50.0));
+    }
+
+    #[test]
+    fn weights_follow_configuration_capacity_and_loss() {
+        let mut stats = LinkStats::default();
+        assert_eq!(stats.weight(Direction::Upstream, None), 1);
+        assert_eq!(stats.weight(Direction::Downstream, Some(0)), 0);
+
+        stats.capacity = CapacityEstimate {
+            upstream: Some(125_000.0),
+            downstream: Some(2_500_000.0),
+        };
+        assert_eq!(stats.weight(Direction::Upstream, None), 1);
+        assert_eq!(stats.weight(Direction::Downstream, None), 20);
+        assert_eq!(stats.weight(Direction::Downstream, Some(4)), 4);
+        stats.cost = 2;
+        assert_eq!(stats.weight(Direction::Downstream, None), 10);
+        stats.cost = 1;
+
+        stats.downstream.loss_percent = Some(50.0);
+        assert_eq!(stats.weight(Direction::Downstream, None), 10);
+        assert_eq!(stats.weight(Direction::Upstream, None), 1);
+
+        stats.monitor.status = LinkStatus::Degraded;
+        assert_eq!(stats.weight(Direction::Upstream, Some(8)), 2);
+        assert_eq!(stats.weight(Direction::Upstream, Some(1)), 1);
+    }
+
+    #[test]
+    fn traffic_counters_only_count_registered_links() {
+        let counters = TrafficCounters::new();
+        counters.register("eth0");
+        counters.count_sent("eth0");
+        counters.count_sent("eth0");
+        counters.count_received("eth0");
+        counters.count_sent("wlan0");
+        counters.count_data("eth0");
+        assert_eq!(counters.get("eth0"), Some((2, 1)));
+        assert_eq!(counters.data_packets("eth0"), Some(1));
+        assert_eq!(counters.get("wlan0"), None);
+    }
+
+    #[test]
+    fn unacknowledged_data_is_reported_once() {
+        let start = Instant::now();
+        let mut log = SendLog::new(Duration::from_millis(300));
+        assert!(!log.on_sent(1, start));
+        assert!(!log.on_sent(2, start + Duration::from_millis(100)));
+        log.on_ack(1, start + Duration::from_millis(150));
+        assert_eq!(log.last_ack(), Some(start + Duration::from_millis(150)));
+        // Packet 2 is still within its timeout.
+        assert!(!log.on_sent(3, start + Duration::from_millis(350)));
+        assert!(log.on_sent(4, start + Duration::from_millis(450)));
+        assert!(!log.on_sent(5, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn a_later_ack_covers_lost_packets() {
+        let start = Instant::now();
+        let mut log = SendLog::new(Duration::from_millis(300));
+        for seq in 1..=3 {
+            log.on_sent(seq, start);
+        }
+        log.on_ack(3, start + Duration::from_millis(50));
+        assert!(!log.on_sent(4, start + Duration::from_millis(200)));
+        assert!(!log.on_sent(5, start + Duration::from_millis(400)));
+        assert!(log.on_sent(6, start + Duration::from_millis(550)));
+    }
+
+    #[test]
+    fn one_failure_per_link_is_pending_at_a_time() {
+        let acks = DataAcks::new();
+        assert!(!acks.begin_failure("wan0"));
+        acks.register("wan0", Duration::from_millis(300));
+        assert!(acks.begin_failure("wan0"));
+        assert!(!acks.begin_failure("wan0"));
+        acks.end_failure("wan0");
+        assert!(acks.begin_failure("wan0"));
+    }
+
+    #[test]
+    fn a_pause_in_sending_is_not_a_stall() {
+        let start = Instant::now();
+        let mut log = SendLog::new(Duration::from_millis(300));
+        log.on_sent(1, start);
+        assert!(!log.on_sent(2, start + Duration::from_secs(5)));
+        assert!(!log.on_sent(3, start + Duration::from_millis(5200)));
+        assert!(log.on_sent(4, start + Duration::from_millis(5400)));
     }
 }
@@ -0,0 +1,254 @@
+//! Link event hooks: commands run, and lines written to a FIFO, when a link
+//! changes status.
+//!
+//! Events are queued to a single worker task, so hooks run one at a time in
+//! the order the changes happened, and a slow or hanging hook never holds up
+//! the health monitor or the data plane.
+
+use nix::fcntl::OFlag;
+use onebox_core::config::HooksConfig;
+use onebox_core::types::{LinkHealth, LinkStatus};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::health::StatusChange;
+
+/// Events waiting for the worker beyond this are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// A change of a link's status, with the link's health at that moment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkEvent {
+    pub link: String,
+    pub old: LinkStatus,
+    pub new: LinkStatus,
+    pub rtt_ms: Option<u32>,
+    pub loss_percent: Option<f32>,
+    pub quality: Option<f32>,
+}
+
+impl LinkEvent {
+    pub fn new(link: &str, (old, new): StatusChange, health: &LinkHealth) -> Self {
+        Self {
+            link: link.to_string(),
+            old,
+            new,
+            rtt_ms: health.rtt_ms,
+            loss_percent: health.packet_loss,
+            quality: health.quality,
+        }
+    }
+
+    /// The environment hook commands are run with. RTT, loss and quality are
+    /// empty when not known yet.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ONEBOX_LINK", self.link.clone()),
+            ("ONEBOX_OLD_STATE", state_name(self.old).to_string()),
+            ("ONEBOX_NEW_STATE", state_name(self.new).to_string()),
+            ("ONEBOX_RTT_MS", optional(self.rtt_ms)),
+            ("ONEBOX_LOSS_PERCENT", optional(self.loss_percent)),
+            (
+                "ONEBOX_QUALITY",
+                optional(self.quality.map(|q| format!("{q:.2}"))),
+            ),
+        ]
+    }
+
+    /// The line written to the FIFO, as space-separated `key=value` pairs.
+    pub fn line(&self) -> String {
+        format!(
+            "link={} old={} new={} rtt_ms={} loss_percent={} quality={}\n",
+            self.link,
+            state_name(self.old),
+            state_name(self.new),
+            optional(self.rtt_ms),
+            optional(self.loss_percent),
+            optional(self.quality.map(|q| format!("{q:.2}")))
+        )
+    }
+}
+
+fn state_name(status: LinkStatus) -> &'static str {
+    match status {
+        LinkStatus::Up => "up",
+        LinkStatus::Degraded => "degraded",
+        LinkStatus::Down => "down",
+        LinkStatus::Unknown => "unknown",
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// The commands configured for an event, in the order they run.
+fn commands<'a>(config: &'a HooksConfig, event: &LinkEvent) -> Vec<&'a str> {
+    let specific = match event.new {
+        LinkStatus::Up => &config.on_up,
+        LinkStatus::Degraded => &config.on_degraded,
+        LinkStatus::Down => &config.on_down,
+        LinkStatus::Unknown => &None,
+    };
+    specific
+        .iter()
+        .chain(config.on_change.iter())
+        .map(String::as_str)
+        .collect()
+}
+
+/// Fires the configured hooks for link events.
+#[derive(Debug, Clone)]
+pub struct LinkHooks {
+    queue: Option<mpsc::Sender<LinkEvent>>,
+}
+
+impl LinkHooks {
+    /// Starts the hook worker. Must be called within a Tokio runtime; does
+    /// nothing when no hooks are configured.
+    pub fn new(config: &HooksConfig) -> Self {
+        let configured = config.on_up.is_some()
+            || config.on_degraded.is_some()
+            || config.on_down.is_some()
+            || config.on_change.is_some()
+            || config.fifo.is_some();
+        if !configured {
+            return Self { queue: None };
+        }
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(run_hooks(config.clone(), rx));
+        Self { queue: Some(tx) }
+    }
+
+    /// Queues the hooks for an event. Never waits: the event is dropped if
+    /// the worker is too far behind.
+    pub fn fire(&self, event: LinkEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if let Err(e) = queue.try_send(event) {
+            warn!("Dropping link hook event: {}", e);
+        }
+    }
+}
+
+async fn run_hooks(config: HooksConfig, mut events: mpsc::Receiver<LinkEvent>) {
+    let timeout = Duration::from_secs(config.timeout_secs);
+    while let Some(event) = events.recv().await {
+        if let Some(fifo) = &config.fifo {
+            write_fifo(fifo, &event.line());
+        }
+        for command in commands(&config, &event) {
+            run_command(command, &event, timeout).await;
+        }
+    }
+}
+
+/// Runs one hook command, killing it if it is still running after `timeout`.
+async fn run_command(command: &str, event: &LinkEvent, timeout: Duration) {
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .envs(event.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run link hook '{}': {}", command, e);
+            return;
+        }
+    };
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            debug!("Link hook '{}' done for {}", command, event.link)
+        }
+        Ok(Ok(output)) => warn!(
+            "Link hook '{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(Err(e)) => warn!("Failed to wait for link hook '{}': {}", command, e),
+        Err(_) => warn!("Link hook '{}' timed out after {:?}", command, timeout),
+    }
+}
+
+/// Appends a line to the FIFO without waiting for a reader.
+fn write_fifo(path: &str, line: &str) {
+    let fifo = OpenOptions::new()
+        .append(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path);
+    let result = fifo.and_then(|mut fifo| fifo.write_all(line.as_bytes()));
+    if let Err(e) = result {
+        debug!("Could not write link event to {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn event(new: LinkStatus) -> LinkEvent {
+        LinkEvent {
+            link: "wan1".to_string(),
+            old: LinkStatus::Up,
+            new,
+            rtt_ms: Some(42),
+            loss_percent: None,
+            quality: Some(4.38),
+        }
+    }
+
+    #[test]
+    fn events_describe_the_change() {
+        let event = event(LinkStatus::Down);
+        assert_eq!(
+            event.line(),
+            "link=wan1 old=up new=down rtt_ms=42 loss_percent= quality=4.38\n"
+        );
+        let env = event.env();
+        assert!(env.contains(&("ONEBOX_NEW_STATE", "down".to_string())));
+        assert!(env.contains(&("ONEBOX_RTT_MS", "42".to_string())));
+        assert!(env.contains(&("ONEBOX_LOSS_PERCENT", String::new())));
+    }
+
+    #[test]
+    fn the_specific_hook_runs_before_the_catch_all() {
+        let config = HooksConfig {
+            on_down: Some("down".to_string()),
+            on_change: Some("change".to_string()),
+            ..HooksConfig::default()
+        };
+        assert_eq!(
+            commands(&config, &event(LinkStatus::Down)),
+            ["down", "change"]
+        );
+        assert_eq!(commands(&config, &event(LinkStatus::Degraded)), ["change"]);
+    }
+
+    #[tokio::test]
+    async fn commands_get_the_event_and_are_killed_on_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let command = format!("echo $ONEBOX_LINK $ONEBOX_NEW_STATE > {}", out.display());
+        run_command(&command, &event(LinkStatus::Down), Duration::from_secs(5)).await;
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "wan1 down\n");
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        run_command("sleep 5", &event(LinkStatus::Up), timeout).await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
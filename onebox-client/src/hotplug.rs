@@ -0,0 +1,182 @@
+//! Interface hotplug: rtnetlink notifications of links and addresses coming
+//! and going, and the link changes they call for.
+
+use nix::sys::socket::{
+    bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+/// rtnetlink multicast groups for link, IPv4 address and IPv6 address changes.
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+/// rtnetlink message types of interest.
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+
+/// Size of a netlink message header.
+const NLMSG_HDRLEN: usize = 16;
+
+/// A netlink socket subscribed to link and address changes.
+pub struct NetlinkWatcher {
+    fd: AsyncFd<OwnedFd>,
+    buf: Vec<u8>,
+}
+
+impl NetlinkWatcher {
+    /// Opens the socket. Must be called within a Tokio runtime.
+    pub fn new() -> io::Result<Self> {
+        let fd = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+        let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, groups))?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            buf: vec![0; 16 * 1024],
+        })
+    }
+
+    /// Waits until a link or address has changed.
+    pub async fn changed(&mut self) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                recv(fd.as_raw_fd(), &mut self.buf, MsgFlags::empty()).map_err(io::Error::from)
+            });
+            match result {
+                Ok(Ok(len)) if is_link_change(&self.buf[..len]) => return Ok(()),
+                Ok(Ok(_)) => {}
+                // The kernel dropped notifications, so anything may have changed.
+                Ok(Err(e)) if e.raw_os_error() == Some(nix::libc::ENOBUFS) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => {}
+            }
+        }
+    }
+}
+
+/// Whether a netlink datagram holds any link or address change.
+fn is_link_change(datagram: &[u8]) -> bool {
+    let mut rest = datagram;
+    while rest.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(rest[..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+        if matches!(kind, RTM_NEWLINK | RTM_DELLINK | RTM_NEWADDR | RTM_DELADDR) {
+            return true;
+        }
+        // Messages are aligned to 4 bytes.
+        let len = (len + 3) & !3;
+        if len < NLMSG_HDRLEN || len > rest.len() {
+            break;
+        }
+        rest = &rest[len..];
+    }
+    false
+}
+
+/// Compares the links in use, by interface name and address, with the WAN
+/// interfaces found now. Returns the links to remove, because their
+/// interface is gone or its address changed, and the interfaces to add.
+pub fn plan(
+    current: &HashMap<String, IpAddr>,
+    found: &[(String, IpAddr)],
+) -> (Vec<String>, Vec<(String, IpAddr)>) {
+    let mut removed: Vec<String> = current
+        .iter()
+        .filter(|(name, addr)| !found.iter().any(|(n, a)| n == *name && a == *addr))
+        .map(|(name, _)| name.clone())
+        .collect();
+    removed.sort();
+    let added = found
+        .iter()
+        .filter(|(name, addr)| current.get(name) != Some(addr))
+        .cloned()
+        .collect();
+    (removed, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, len: u32) -> Vec<u8> {
+        let mut message = vec![0; len as usize];
+        message[..4].copy_from_slice(&len.to_ne_bytes());
+        message[4..6].copy_from_slice(&kind.to_ne_bytes());
+        message
+    }
+
+    #[test]
+    fn link_and_address_messages_are_changes() {
+        assert!(is_link_change(&message(RTM_NEWADDR, 40)));
+        assert!(!is_link_change(&message(24, 40)));
+        let mut batch = message(24, 40);
+        batch.extend(message(RTM_DELLINK, 32));
+        assert!(is_link_change(&batch));
+        assert!(!is_link_change(&[0; 8]));
+    }
+
+    #[test]
+    fn plan_adds_new_and_replaces_changed_links() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let current = HashMap::from([
+            ("eth0".to_string(), ip("192.168.1.10")),
+            ("wwan0".to_string(), ip("10.64.0.2")),
+            ("wwan2".to_string(), ip("2001:db8::2")),
+        ]);
+        let found = vec![
+            ("eth0".to_string(), ip("192.168.1.10")),
+            ("wwan0".to_string(), ip("10.64.0.3")),
+            ("wwan1".to_string(), ip("10.64.0.2")),
+            ("wwan2".to_string(), ip("2001:db8::3")),
+        ];
+        let (removed, added) = plan(&current, &found);
+        assert_eq!(removed, ["wwan0", "wwan2"]);
+        assert_eq!(added, found[1..]);
+        assert_eq!(plan(&current, &[]).0, ["eth0", "wwan0", "wwan2"]);
+    }
+
+    #[test]
+    fn unplugged_links_leave_only_their_usage_behind() {
+        use crate::congestion::{CongestionControl, PacerConfig};
+        use crate::health::TrafficCounters;
+        use crate::usage::{CapLimits, UsageTracker};
+
+        let dir = tempfile::tempdir().unwrap();
+        let usage = UsageTracker::new(dir.path().join("usage.json"));
+        let congestion = CongestionControl::new(PacerConfig {
+            enabled: true,
+            queue_delay_target: std::time::Duration::from_millis(50),
+            max_rate: None,
+        });
+        let counters = TrafficCounters::new();
+        // wwan0 comes and goes the way `LinkRuntime` adds and removes links.
+        usage.register("wwan0", CapLimits::default());
+        congestion.register("wwan0", None);
+        counters.register("wwan0");
+        usage.record("wwan0", 1000);
+
+        usage.unregister("wwan0", 100);
+        congestion.unregister("wwan0");
+        counters.unregister("wwan0");
+        assert!(usage.flush(100).is_empty());
+        assert_eq!(congestion.rate("wwan0"), None);
+        assert_eq!(counters.get("wwan0"), None);
+
+        // Back again, it keeps what it used.
+        usage.register("wwan0", CapLimits::default());
+        let (link_usage, _) = usage.snapshot("wwan0").unwrap();
+        assert!(link_usage.total_bytes >= 1000);
+    }
+}
@@ -0,0 +1,118 @@
+//! A record, kept on disk, of the routes and rules the client has changed.
+//!
+//! Every change is written to the journal as soon as it is made and
+//! dropped once undone, so whatever is left in the file when the client
+//! starts was left by a run that crashed, and is undone before anything else.
+
+use onebox_core::netconfig::{Change, NetConfig};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// The network changes the client has made and not yet undone.
+#[derive(Debug)]
+pub struct RouteJournal {
+    path: PathBuf,
+    changes: Mutex<Vec<Change>>,
+}
+
+impl RouteJournal {
+    /// Opens the journal at `path`, first undoing the changes a previous run
+    /// left in it.
+    pub async fn recover<P: AsRef<Path>>(net: &NetConfig, path: P) -> Self {
+        let journal = Self {
+            path: path.as_ref().to_path_buf(),
+            changes: Mutex::new(Vec::new()),
+        };
+        match journal.load() {
+            Ok(changes) if !changes.is_empty() => {
+                warn!(
+                    "Restoring {} route changes left by an earlier run",
+                    changes.len()
+                );
+                net.revert_all(changes).await;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read route journal {:?}: {}", journal.path, e),
+        }
+        journal.save();
+        journal
+    }
+
+    /// Records changes that have been made.
+    pub fn record(&self, changes: &[Change]) {
+        self.changes.lock().unwrap().extend_from_slice(changes);
+        self.save();
+    }
+
+    /// Drops changes that have been undone.
+    pub fn forget(&self, changes: &[Change]) {
+        self.changes
+            .lock()
+            .unwrap()
+            .retain(|change| !changes.contains(change));
+        self.save();
+    }
+
+    /// Undoes every recorded change, latest first, and removes the journal.
+    pub async fn restore(&self, net: &NetConfig) {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        if !changes.is_empty() {
+            info!("Restoring {} route changes", changes.len());
+        }
+        net.revert_all(changes).await;
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove route journal {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<Change>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the journal out. A journal that can't be written only loses
+    /// crash recovery, so failures are logged rather than returned.
+    fn save(&self) {
+        let result = (|| -> anyhow::Result<()> {
+            let content = serde_json::to_string_pretty(&*self.changes.lock().unwrap())?;
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let tmp_path = self.path.with_extension("tmp");
+            std::fs::write(&tmp_path, content)?;
+            std::fs::rename(&tmp_path, &self.path)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            warn!("Failed to write route journal {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use onebox_core::netconfig::Route;
+
+    #[test]
+    fn changes_are_kept_until_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = RouteJournal {
+            path: dir.path().join("routes.json"),
+            changes: Mutex::new(Vec::new()),
+        };
+        let pin = Change::Route(Route::host("203.0.113.7".parse().unwrap()).dev("eth0"));
+        let tun = Change::Route(Route::new("0.0.0.0".parse().unwrap(), 1).dev("onebox0"));
+        journal.record(&[pin.clone(), tun.clone()]);
+        assert_eq!(journal.load().unwrap(), [pin.clone(), tun.clone()]);
+
+        journal.forget(&[pin]);
+        assert_eq!(journal.load().unwrap(), [tun]);
+    }
+}
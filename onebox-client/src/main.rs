@@ -2,34 +2,95 @@
 
 use clap::{Parser, Subcommand};
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+pub mod bandwidth;
+pub mod checks;
+pub mod congestion;
+pub mod dns;
 pub mod health;
+pub mod hooks;
+pub mod hotplug;
+pub mod journal;
+pub mod resolv;
+pub mod routing;
+pub mod split;
+pub mod usage;
+use bandwidth::{BandwidthEstimator, CapacityEstimate};
 use chacha20poly1305::Key;
-use health::LinkStats;
-use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+use congestion::{CongestionControl, PacerConfig};
+use health::{
+    DataAcks, DataFailure, Direction, FailbackPolicy, HealthPolicy, LinkStats, TrafficCounters,
+};
+use hooks::{LinkEvent, LinkHooks};
+use journal::RouteJournal;
+use nix::sys::socket::{
+    setsockopt,
+    sockopt::{BindToDevice, Mark},
+};
+use onebox_core::bandwidth::{BandwidthProbe, BandwidthReport};
+use onebox_core::config::{ClientConfig, LinkBinding};
+use onebox_core::control::{self, ControlMessage, DirectionInfo, LinkAdvert, NetworkSettings};
 use onebox_core::crypto::{decrypt_in_place, encrypt_in_place};
+use onebox_core::netconfig::{Change, NetConfig};
 use onebox_core::packet::{PacketHeader, PacketType};
 use onebox_core::prelude::*;
-use onebox_core::types::ClientId;
+use onebox_core::scheduler::WeightedRoundRobin;
+use onebox_core::types::{ClientId, HealthCheck};
+use routing::PolicyRouting;
+use split::SplitTunnel;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::process::Command;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream, UdpSocket};
-use tokio::sync::{Mutex, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::JoinHandle;
 use tokio_tun::TunBuilder;
 use tracing::{debug, error, info, warn, Level};
+use usage::{CapLimits, CapState, UsageTracker, BYTES_PER_MB};
 
 const STATUS_SOCKET_PATH: &str = "/tmp/onebox_status.sock";
 
+/// The longest request line accepted on the status socket.
+const MAX_REQUEST_LEN: u64 = 256;
+
+/// How long a requester has to send its request line. One that sends none
+/// gets the status report.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A WAN interface name paired with the UDP socket bound to it.
+type LinkSocket = (String, Arc<UdpSocket>);
+/// Every link the client has, whether or not it carries data.
+type AllSockets = Arc<RwLock<Vec<LinkSocket>>>;
+/// The pool of links currently carrying data traffic.
+type ActiveSockets = Arc<RwLock<Vec<LinkSocket>>>;
+
+/// Shared client state needed to answer requests on the status socket and to
+/// run bandwidth measurements.
+#[derive(Clone)]
+struct ControlContext {
+    link_stats: Arc<Mutex<HashMap<String, LinkStats>>>,
+    all_sockets: AllSockets,
+    active_sockets: ActiveSockets,
+    usage: Arc<UsageTracker>,
+    congestion: Arc<CongestionControl>,
+    estimator: Arc<BandwidthEstimator>,
+    hooks: LinkHooks,
+    key: Arc<Key>,
+    client_id: ClientId,
+}
+
+/// Authenticates with the server. Returns the network settings the server
+/// pushed in its AuthResponse, which only counts once it decrypts under our
+/// key and decodes.
 async fn perform_handshake(
     socket: &UdpSocket,
     key: &Key,
     client_id: ClientId,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<NetworkSettings> {
     info!("Performing handshake...");
     let auth_request_header = PacketHeader::new(0, PacketType::AuthRequest, client_id);
     let header_bytes = bincode::serialize(&auth_request_header)?;
@@ -48,11 +109,16 @@ async fn perform_handshake(
         .await
         {
             Ok(Ok(len)) => {
-                let response_packet = &recv_buf[..len];
-                if let Ok(header) = bincode::deserialize::<PacketHeader>(response_packet) {
+                if let Ok(header) = bincode::deserialize::<PacketHeader>(&recv_buf[..len]) {
                     if header.packet_type == PacketType::AuthResponse {
-                        info!("Handshake successful: received AuthResponse from server.");
-                        return Ok(());
+                        let settings = open_payload(&header, &mut recv_buf, len, key)
+                            .and_then(|payload| bincode::deserialize(payload).ok());
+                        if let Some(settings) = settings {
+                            info!("Handshake successful: received AuthResponse from server.");
+                            return Ok(settings);
+                        }
+                        warn!("Received an AuthResponse that failed to authenticate, retrying...");
+                        continue;
                     }
                 }
                 warn!("Received unexpected packet during handshake.");
@@ -68,43 +134,115 @@ async fn perform_handshake(
     Err(anyhow::anyhow!("Handshake failed after multiple attempts."))
 }
 
-async fn discover_and_bind_sockets(
-    server_addr: SocketAddr,
-) -> anyhow::Result<Vec<(String, Arc<UdpSocket>)>> {
-    info!("Discovering WAN interfaces and binding sockets...");
-    let mut sockets = Vec::new();
-    let ifaces = NetworkInterface::show()?;
-
-    for iface in ifaces {
+/// Finds the interfaces that become WAN links, as chosen by the link and
+/// discovery configuration, with the source address each link uses: the one
+/// its `local_addr` names, or else the interface's first IPv4 or IPv6
+/// address the server can be reached on.
+fn find_wan_interfaces(
+    client_config: &ClientConfig,
+    servers: &[SocketAddr],
+) -> anyhow::Result<Vec<(String, IpAddr)>> {
+    let mut interfaces: Vec<(String, Vec<IpAddr>)> = Vec::new();
+    for iface in NetworkInterface::show()? {
         debug!("Processing interface: {:?}", iface);
-        if iface.name.starts_with("lo")
-            || iface.name.contains("docker")
-            || iface.name.starts_with("onebox")
-        {
-            debug!("Skipping interface {}: loopback/virtual", iface.name);
+        let addrs = iface.addr.iter().map(|addr| addr.ip());
+        match interfaces.iter_mut().find(|(name, _)| *name == iface.name) {
+            Some((_, known)) => known.extend(addrs),
+            None => interfaces.push((iface.name.clone(), addrs.collect())),
+        }
+    }
+
+    let names = interfaces.iter().map(|(name, _)| name.as_str());
+    let mut found = Vec::new();
+    for link in client_config.select_links(names) {
+        let Some((_, addrs)) = interfaces.iter().find(|(name, _)| *name == link.name) else {
             continue;
+        };
+        match link.source_address(addrs, servers) {
+            Some(address) => found.push((link.name, address)),
+            None => debug!("Skipping interface {}: no usable address", link.name),
         }
+    }
+    Ok(found)
+}
 
-        for addr in &iface.addr {
-            if let std::net::IpAddr::V4(ipv4) = addr.ip() {
-                info!(
-                    "Found potential WAN interface {} with IP {}",
-                    iface.name, ipv4
-                );
-                let socket = UdpSocket::bind("0.0.0.0:0").await?;
-                let device_name = OsString::from(iface.name.as_str());
-                if let Err(e) = setsockopt(&socket, BindToDevice, &device_name) {
-                    error!("Failed to bind socket to device {}: {}", iface.name, e);
-                    continue;
+/// Binds a UDP socket for a link and connects it to the server address in
+/// the family of the link's source address. In device mode the socket is
+/// bound to the interface and to `local_addr`, or the unspecified address of
+/// that family when `local_addr` names none. Otherwise it is bound to the
+/// source address, and the link's policy routing is installed. Either way,
+/// routes to the server through the link are pinned first.
+async fn bind_link_socket(
+    iface_name: &str,
+    local_addr: SocketAddr,
+    source: IpAddr,
+    servers: &[SocketAddr],
+    routing: &PolicyRouting,
+) -> anyhow::Result<UdpSocket> {
+    let server_addr = *servers
+        .iter()
+        .find(|addr| addr.is_ipv4() == source.is_ipv4())
+        .ok_or_else(|| anyhow::anyhow!("No server address reachable from {}", source))?;
+    let socket = match routing.mode() {
+        LinkBinding::Device => {
+            let bind_addr = match (local_addr.ip().is_unspecified(), source) {
+                (false, _) => local_addr,
+                (true, IpAddr::V4(_)) => {
+                    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_addr.port())
                 }
-                info!("Successfully bound UDP socket to device {}", iface.name);
-                socket.connect(server_addr).await?;
-                info!(
-                    "Socket for {} connected to server at {}",
-                    iface.name, server_addr
-                );
-                sockets.push((iface.name.clone(), Arc::new(socket)));
-                break;
+                (true, IpAddr::V6(_)) => {
+                    SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_addr.port())
+                }
+            };
+            routing.install(iface_name, source).await?;
+            let socket = UdpSocket::bind(bind_addr).await?;
+            let device_name = OsString::from(iface_name);
+            setsockopt(&socket, BindToDevice, &device_name).map_err(|e| {
+                anyhow::anyhow!("Failed to bind socket to device {}: {}", iface_name, e)
+            })?;
+            info!("Successfully bound UDP socket to device {}", iface_name);
+            socket
+        }
+        mode => {
+            let table = routing.install(iface_name, source).await?;
+            let socket = UdpSocket::bind(SocketAddr::new(source, local_addr.port())).await?;
+            if mode == LinkBinding::Mark {
+                setsockopt(&socket, Mark, &table).map_err(|e| {
+                    anyhow::anyhow!("Failed to set mark on socket for {}: {}", iface_name, e)
+                })?;
+            }
+            info!("Bound UDP socket for {} to {}", iface_name, source);
+            socket
+        }
+    };
+    socket.connect(server_addr).await?;
+    info!(
+        "Socket for {} connected to server at {}",
+        iface_name, server_addr
+    );
+    Ok(socket)
+}
+
+/// Binds a socket on every WAN interface found at startup. Returns each link
+/// with the interface address it was found with.
+async fn discover_and_bind_sockets(
+    servers: &[SocketAddr],
+    client_config: &ClientConfig,
+    routing: &PolicyRouting,
+) -> anyhow::Result<Vec<(LinkSocket, IpAddr)>> {
+    info!("Discovering WAN interfaces and binding sockets...");
+    let mut sockets = Vec::new();
+    for (iface_name, address) in find_wan_interfaces(client_config, servers)? {
+        info!(
+            "Found potential WAN interface {} with IP {}",
+            iface_name, address
+        );
+        let local_addr = client_config.link_config(&iface_name).local_addr;
+        match bind_link_socket(&iface_name, local_addr, address, servers, routing).await {
+            Ok(socket) => sockets.push(((iface_name, Arc::new(socket)), address)),
+            Err(e) => {
+                error!("{}", e);
+                routing.remove(&iface_name).await;
             }
         }
     }
@@ -137,80 +275,431 @@ enum Commands {
     Start,
     Stop,
     Status,
+    /// Print link health metrics in the Prometheus text format
+    Metrics,
     Config,
+    /// Measure the up/down capacity of each link
+    Bandwidth {
+        /// Only measure this link
+        #[arg(short, long)]
+        link: Option<String>,
+    },
+}
+
+/// Formats a rate in bytes per second as Mbit/s.
+fn format_mbps(bytes_per_sec: Option<f64>) -> String {
+    bytes_per_sec
+        .map(|rate| format!("{:.2}", rate * 8.0 / 1_000_000.0))
+        .unwrap_or_else(|| "-".to_string())
 }
 
-async fn handle_status_connection(
+/// Formats a duration in milliseconds.
+fn format_ms(duration: Option<Duration>) -> String {
+    duration
+        .map(|duration| format!("{:.2}", duration.as_secs_f64() * 1000.0))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Answers one request on the status socket. The requester writes a command
+/// line ("status" or "bandwidth [link]") and closes its write half.
+async fn handle_control_connection(
     mut stream: UnixStream,
-    link_stats: Arc<Mutex<HashMap<String, LinkStats>>>,
+    ctx: ControlContext,
 ) -> anyhow::Result<()> {
-    let stats = link_stats.lock().await;
+    let mut request = String::new();
+    let mut reader = BufReader::new(&mut stream).take(MAX_REQUEST_LEN);
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut request)).await {
+        Ok(read) => {
+            read?;
+        }
+        Err(_) => request.clear(),
+    }
+    let mut words = request.split_whitespace();
+    let response = match words.next() {
+        None | Some("status") => status_report(&ctx).await,
+        Some("bandwidth") => bandwidth_report(&ctx, words.next()).await,
+        Some("metrics") => metrics_report(&ctx).await,
+        Some(command) => format!("Unknown command: {}\n", command),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+async fn status_report(ctx: &ControlContext) -> String {
+    let stats = ctx.link_stats.lock().await;
     let mut response = String::new();
+    let active = ctx.active_sockets.read().await;
     response.push_str(&format!(
-        "{:<15} {:<10} {:<15} {:<10}\n",
-        "Link", "Status", "Latency (ms)", "Loss (%)"
+        "{:<15} {:<10} {:<15} {:<10} {:<12} {:<12} {:<12} {:<10} {:<10} {:<12} {:<12} {:<12} {:<16} {:<16} {:<6}\n",
+        "Link",
+        "Status",
+        "Latency (ms)",
+        "Loss (%)",
+        "SRTT (ms)",
+        "Jitter (ms)",
+        "Min RTT (ms)",
+        "Priority",
+        "Role",
+        "Usage (MB)",
+        "Left (MB)",
+        "Rate (Mbps)",
+        "Capacity (Mbps)",
+        "Up/Down Loss (%)",
+        "MOS"
     ));
     response.push_str(&format!(
-        "{:-<15} {:-<10} {:-<15} {:-<10}\n",
-        "", "", "", ""
+        "{:-<15} {:-<10} {:-<15} {:-<10} {:-<12} {:-<12} {:-<12} {:-<10} {:-<10} {:-<12} {:-<12} {:-<12} {:-<16} {:-<16} {:-<6}\n",
+        "", "", "", "", "", "", "", "", "", "", "", "", "", "", ""
     ));
 
     for (name, stats) in stats.iter() {
-        let status_str = format!("{:?}", stats.status);
-        let rtt_str = if stats.status == health::LinkStatus::Up {
-            format!("{:.2}", stats.rtt.as_secs_f32() * 1000.0)
+        let monitor = &stats.monitor;
+        let status_str = format!("{:?}", monitor.status);
+        let rtt_str = if matches!(
+            monitor.status,
+            health::LinkStatus::Up | health::LinkStatus::Degraded
+        ) {
+            format!("{:.2}", monitor.rtt.as_secs_f32() * 1000.0)
         } else {
             "-".to_string()
         };
-        let loss_str = format!("{:.2}", stats.packet_loss_percent());
+        let loss_str = format!("{:.2}", monitor.packet_loss_percent());
+        let srtt_str = format_ms(monitor.history.srtt());
+        let jitter_str = format_ms(monitor.history.srtt().map(|_| monitor.history.jitter()));
+        let min_rtt_str = format_ms(monitor.history.min_rtt());
+        let role_str = if active.iter().any(|(active_name, _)| active_name == name) {
+            "active"
+        } else {
+            match stats.cap_state {
+                CapState::Normal => "standby",
+                CapState::Soft => "soft-cap",
+                CapState::Hard => "capped",
+            }
+        };
+        let (usage_str, left_str) = match ctx.usage.snapshot(name) {
+            Some((link_usage, limits)) => (
+                format!("{:.1}", link_usage.cycle_bytes as f64 / BYTES_PER_MB as f64),
+                link_usage
+                    .remaining(&limits)
+                    .map(|left| format!("{:.1}", left as f64 / BYTES_PER_MB as f64))
+                    .unwrap_or_else(|| "-".to_string()),
+            ),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let rate_str = format_mbps(ctx.congestion.rate(name));
+        let capacity_str = format!(
+            "{}/{}",
+            format_mbps(stats.capacity.upstream),
+            format_mbps(stats.capacity.downstream)
+        );
+        let format_loss = |loss: Option<f32>| {
+            loss.map(|loss| format!("{:.1}", loss))
+                .unwrap_or_else(|| "-".to_string())
+        };
+        let direction_loss_str = format!(
+            "{}/{}",
+            format_loss(stats.upstream.loss_percent),
+            format_loss(stats.downstream.loss_percent)
+        );
+        let quality_str = monitor
+            .history
+            .quality()
+            .map(|quality| format!("{:.2}", quality))
+            .unwrap_or_else(|| "-".to_string());
         response.push_str(&format!(
-            "{:<15} {:<10} {:<15} {:<10}\n",
-            name, status_str, rtt_str, loss_str
+            "{:<15} {:<10} {:<15} {:<10} {:<12} {:<12} {:<12} {:<10} {:<10} {:<12} {:<12} {:<12} {:<16} {:<16} {:<6}\n",
+            name,
+            status_str,
+            rtt_str,
+            loss_str,
+            srtt_str,
+            jitter_str,
+            min_rtt_str,
+            stats.priority,
+            role_str,
+            usage_str,
+            left_str,
+            rate_str,
+            capacity_str,
+            direction_loss_str,
+            quality_str
         ));
     }
 
-    stream.write_all(response.as_bytes()).await?;
-    Ok(())
+    drop(active);
+    drop(stats);
+    response
+}
+
+/// Link health in the Prometheus text exposition format.
+async fn metrics_report(ctx: &ControlContext) -> String {
+    let stats = ctx.link_stats.lock().await;
+    let active = ctx.active_sockets.read().await;
+    let mut names: Vec<&String> = stats.keys().collect();
+    names.sort();
+    let metrics: [(&str, &str, &str); 6] = [
+        (
+            "onebox_link_up",
+            "gauge",
+            "Whether the link is Up (1) or not (0).",
+        ),
+        (
+            "onebox_link_active",
+            "gauge",
+            "Whether the link carries data.",
+        ),
+        (
+            "onebox_link_rtt_seconds",
+            "gauge",
+            "Smoothed probe round-trip time.",
+        ),
+        ("onebox_link_jitter_seconds", "gauge", "Probe RTT jitter."),
+        (
+            "onebox_link_loss_percent",
+            "gauge",
+            "Probe loss over the recent window.",
+        ),
+        (
+            "onebox_link_quality_mos",
+            "gauge",
+            "Estimated MOS quality score (1.0 to 4.5).",
+        ),
+    ];
+    let mut response = String::new();
+    for (metric, kind, help) in metrics {
+        response.push_str(&format!("# HELP {metric} {help}\n# TYPE {metric} {kind}\n"));
+        for name in &names {
+            let monitor = &stats[*name].monitor;
+            let value = match metric {
+                "onebox_link_up" => Some(f64::from(monitor.status == health::LinkStatus::Up)),
+                "onebox_link_active" => Some(f64::from(
+                    active.iter().any(|(active_name, _)| active_name == *name),
+                )),
+                "onebox_link_rtt_seconds" => monitor.history.srtt().map(|srtt| srtt.as_secs_f64()),
+                "onebox_link_jitter_seconds" => monitor
+                    .history
+                    .srtt()
+                    .map(|_| monitor.history.jitter().as_secs_f64()),
+                "onebox_link_loss_percent" => Some(f64::from(monitor.packet_loss_percent())),
+                _ => monitor.history.quality().map(f64::from),
+            };
+            if let Some(value) = value {
+                response.push_str(&format!("{metric}{{link=\"{name}\"}} {value}\n"));
+            }
+        }
+    }
+    response
+}
+
+async fn bandwidth_report(ctx: &ControlContext, link: Option<&str>) -> String {
+    if let Some(link) = link {
+        let known = ctx
+            .all_sockets
+            .read()
+            .await
+            .iter()
+            .any(|(name, _)| name == link);
+        if !known {
+            return format!("Unknown link: {}\n", link);
+        }
+    }
+    let mut response = format!(
+        "{:<15} {:<16} {:<18}\n{:-<15} {:-<16} {:-<18}\n",
+        "Link", "Upload (Mbps)", "Download (Mbps)", "", "", ""
+    );
+    for (name, result) in measure_links(ctx, |name, _| link.is_none_or(|link| link == name)).await {
+        match result {
+            Ok(estimate) => response.push_str(&format!(
+                "{:<15} {:<16} {:<18}\n",
+                name,
+                format_mbps(estimate.upstream),
+                format_mbps(estimate.downstream)
+            )),
+            Err(e) => response.push_str(&format!("{:<15} error: {}\n", name, e)),
+        }
+    }
+    response
+}
+
+/// Measures the capacity of every link accepted by `filter`, one at a time so
+/// that the trains don't compete for a shared bottleneck. Each estimate is
+/// stored in the link's stats and resets its pacing rate.
+async fn measure_links(
+    ctx: &ControlContext,
+    filter: impl Fn(&str, &LinkStats) -> bool,
+) -> Vec<(String, anyhow::Result<CapacityEstimate>)> {
+    let mut results = Vec::new();
+    let sockets = ctx.all_sockets.read().await.clone();
+    for (name, socket) in sockets.iter() {
+        let selected = ctx
+            .link_stats
+            .lock()
+            .await
+            .get(name)
+            .is_some_and(|stats| filter(name, stats));
+        if !selected {
+            continue;
+        }
+        let result = ctx
+            .estimator
+            .measure(name, socket, &ctx.key, ctx.client_id, &ctx.usage)
+            .await;
+        if let Ok(estimate) = &result {
+            info!(
+                "Measured capacity of {}: up {} Mbps, down {} Mbps",
+                name,
+                format_mbps(estimate.upstream),
+                format_mbps(estimate.downstream)
+            );
+            if let Some(upstream) = estimate.upstream {
+                ctx.congestion.set_capacity(name, upstream);
+            }
+            if let Some(stats) = ctx.link_stats.lock().await.get_mut(name) {
+                stats.capacity = *estimate;
+            }
+        }
+        results.push((name.clone(), result));
+    }
+    results
+}
+
+/// Recomputes which links carry data traffic, honoring link priority tiers.
+///
+/// Only the eligible links of the selected tier are kept in the active pool;
+/// links of lower-priority tiers stay on standby. Returns the
+/// active tier, or `None` if every link is down.
+async fn refresh_active_pool(
+    link_stats: &Mutex<HashMap<String, LinkStats>>,
+    all_sockets: &RwLock<Vec<LinkSocket>>,
+    active_sockets: &RwLock<Vec<LinkSocket>>,
+    failback: &FailbackPolicy,
+) -> Option<u8> {
+    let stats_guard = link_stats.lock().await;
+    let current_tier = active_sockets
+        .read()
+        .await
+        .iter()
+        .filter_map(|(name, _)| stats_guard.get(name))
+        .map(|stats| stats.effective_priority())
+        .min();
+    let tier = health::select_active_tier(&stats_guard, current_tier, failback, Instant::now());
+    let desired: Vec<LinkSocket> = all_sockets
+        .read()
+        .await
+        .iter()
+        .filter(|(name, _)| {
+            stats_guard.get(name).is_some_and(|stats| {
+                Some(stats.effective_priority()) == tier && stats.is_eligible()
+            })
+        })
+        .cloned()
+        .collect();
+    drop(stats_guard);
+
+    let mut active_links_guard = active_sockets.write().await;
+    let unchanged = active_links_guard.len() == desired.len()
+        && active_links_guard
+            .iter()
+            .zip(desired.iter())
+            .all(|((a, _), (b, _))| a == b);
+    if !unchanged {
+        if tier != current_tier {
+            info!(
+                "Active priority tier changed from {:?} to {:?}",
+                current_tier, tier
+            );
+        }
+        *active_links_guard = desired;
+        let names: Vec<&str> = active_links_guard
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        info!("Active links ({}): {:?}", names.len(), names);
+    }
+    tier
+}
+
+/// Logs a link status change and fires the link hooks for it.
+fn report_status_change(hooks: &LinkHooks, event: LinkEvent) {
+    match event.new {
+        health::LinkStatus::Up => info!("Link {} has recovered and is now UP.", event.link),
+        health::LinkStatus::Degraded => {
+            warn!("Link {} is DEGRADED (was {:?}).", event.link, event.old)
+        }
+        health::LinkStatus::Down => warn!(
+            "Link {} marked as DOWN. Removing from active pool.",
+            event.link
+        ),
+        health::LinkStatus::Unknown => {}
+    }
+    hooks.fire(event);
 }
 
 async fn handle_probe_response(
     header: &PacketHeader,
     iface_name: &str,
-    stats_mutex: Arc<Mutex<HashMap<String, LinkStats>>>,
-    all_sockets: &Arc<Vec<(String, Arc<UdpSocket>)>>,
-    active_sockets: &Arc<RwLock<Vec<(String, Arc<UdpSocket>)>>>,
+    ctx: &ControlContext,
+    failback: &FailbackPolicy,
 ) {
-    let mut should_mark_up = false;
-    let mut stats_guard = stats_mutex.lock().await;
+    let mut event = None;
+    let mut stats_guard = ctx.link_stats.lock().await;
     if let Some(stats) = stats_guard.get_mut(iface_name) {
-        if let Some(sent_at) = stats.in_flight_probes.remove(&header.sequence_number) {
-            stats.probes_received += 1;
-            stats.rtt = sent_at.elapsed();
-            stats.consecutive_failures = 0;
-            if stats.status != health::LinkStatus::Up {
-                stats.status = health::LinkStatus::Up;
-                should_mark_up = true;
-            }
+        if let Some(echo) = stats.monitor.on_echo(header.sequence_number) {
+            event = echo
+                .change
+                .map(|change| LinkEvent::new(iface_name, change, &stats.monitor.health()));
+            ctx.congestion.on_rtt_sample(iface_name, echo.rtt);
         }
     }
     drop(stats_guard);
 
-    if should_mark_up {
-        info!(
-            "Link {} has recovered and is now UP. Adding back to active pool.",
-            iface_name
+    if let Some(event) = event {
+        report_status_change(&ctx.hooks, event);
+        refresh_active_pool(
+            &ctx.link_stats,
+            &ctx.all_sockets,
+            &ctx.active_sockets,
+            failback,
+        )
+        .await;
+    }
+}
+
+/// Applies a failure seen on the data path to a link, if the link is
+/// monitored passively. Spawned off the data path, which must not wait for
+/// the link stats lock.
+async fn handle_data_failure(
+    link_stats: Arc<Mutex<HashMap<String, LinkStats>>>,
+    all_sockets: AllSockets,
+    active_sockets: ActiveSockets,
+    failback: FailbackPolicy,
+    hooks: LinkHooks,
+    iface_name: String,
+    failure: DataFailure,
+) {
+    let mut stats_guard = link_stats.lock().await;
+    let Some(stats) = stats_guard.get_mut(&iface_name) else {
+        return;
+    };
+    if !stats.monitor.policy().passive_monitoring {
+        return;
+    }
+    let event = stats
+        .monitor
+        .on_data_failure(failure)
+        .map(|change| LinkEvent::new(&iface_name, change, &stats.monitor.health()));
+    if failure == DataFailure::Unacknowledged {
+        warn!(
+            "Data on {} went unacknowledged for {:?}",
+            iface_name,
+            stats.monitor.stall_timeout()
         );
-        if let Some(socket_to_add) = all_sockets.iter().find(|(name, _)| name == iface_name) {
-            let mut active_links_guard = active_sockets.write().await;
-            if !active_links_guard.iter().any(|(name, _)| name == iface_name) {
-                active_links_guard.push(socket_to_add.clone());
-                info!(
-                    "Link {} re-added. Active links: {}",
-                    iface_name,
-                    active_links_guard.len()
-                );
-            }
-        }
+    }
+    drop(stats_guard);
+
+    if let Some(event) = event {
+        report_status_change(&hooks, event);
+        refresh_active_pool(&link_stats, &all_sockets, &active_sockets, &failback).await;
     }
 }
 
@@ -234,6 +723,462 @@ async fn handle_data_packet(
     Ok(())
 }
 
+/// Describes every link for the server, and refreshes the upstream weights
+/// used by the data path from the same per-direction state.
+async fn build_link_table(
+    link_stats: &Mutex<HashMap<String, LinkStats>>,
+    active_sockets: &RwLock<Vec<LinkSocket>>,
+    config: &ClientConfig,
+    upstream_weights: &std::sync::Mutex<WeightedRoundRobin>,
+) -> Vec<LinkAdvert> {
+    let stats_guard = link_stats.lock().await;
+    let active = active_sockets.read().await;
+    let mut links: Vec<LinkAdvert> = stats_guard
+        .iter()
+        .map(|(name, stats)| {
+            let (upstream_weight, downstream_weight) = config.link_config(name).weights();
+            LinkAdvert {
+                name: name.clone(),
+                active: active.iter().any(|(active_name, _)| active_name == name),
+                upstream: DirectionInfo {
+                    capacity: stats.capacity.upstream,
+                    weight: stats.weight(Direction::Upstream, upstream_weight),
+                },
+                downstream: DirectionInfo {
+                    capacity: stats.capacity.downstream,
+                    weight: stats.weight(Direction::Downstream, downstream_weight),
+                },
+            }
+        })
+        .collect();
+    drop(active);
+    drop(stats_guard);
+
+    links.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut upstream_weights = upstream_weights.lock().unwrap();
+    for link in &links {
+        upstream_weights.set_weight(&link.name, link.upstream.weight);
+    }
+    links
+}
+
+/// Applies the server's packet counters for a link to its per-direction loss.
+async fn handle_link_feedback(
+    link: &str,
+    server_received: u64,
+    server_sent: u64,
+    link_stats: &Mutex<HashMap<String, LinkStats>>,
+    counters: &TrafficCounters,
+) {
+    let Some((sent, received)) = counters.get(link) else {
+        return;
+    };
+    if let Some(stats) = link_stats.lock().await.get_mut(link) {
+        stats.upstream.update(sent, server_received);
+        stats.downstream.update(server_sent, received);
+    }
+}
+
+/// Decrypts the payload of a received packet in place.
+fn open_payload<'a>(
+    header: &PacketHeader,
+    packet_buf: &'a mut [u8],
+    len: usize,
+    key: &Key,
+) -> Option<&'a [u8]> {
+    let header_size = bincode::serialized_size(header).ok()? as usize;
+    if len < header_size {
+        return None;
+    }
+    decrypt_in_place(
+        key,
+        &mut packet_buf[header_size..len],
+        header.sequence_number,
+    )
+    .ok()
+}
+
+/// Sends a probe from the server back over the link it arrived on, so the
+/// server can judge the link's downstream health. Only authentic probes are
+/// echoed.
+async fn echo_server_probe(
+    header: &PacketHeader,
+    packet_buf: &mut [u8],
+    len: usize,
+    iface_name: &str,
+    ctx: &ControlContext,
+    counters: &TrafficCounters,
+) {
+    let packet = packet_buf[..len].to_vec();
+    if open_payload(header, packet_buf, len, &ctx.key).is_none() {
+        return;
+    }
+    let socket = ctx
+        .all_sockets
+        .read()
+        .await
+        .iter()
+        .find(|(name, _)| name == iface_name)
+        .map(|(_, socket)| socket.clone());
+    let Some(socket) = socket else {
+        return;
+    };
+    match socket.send(&packet).await {
+        Ok(len) => {
+            ctx.usage.record(iface_name, len);
+            counters.count_sent(iface_name);
+        }
+        Err(e) => debug!("Failed to echo server probe on {}: {}", iface_name, e),
+    }
+}
+
+/// Size of the buffers datagrams from the server are received into.
+const DOWNSTREAM_BUF_SIZE: usize = 2048;
+
+/// A datagram received on a link: its length and bytes, the link, and when
+/// it arrived.
+type DownstreamPacket = (usize, [u8; DOWNSTREAM_BUF_SIZE], String, Instant);
+
+/// Links in use, by interface name: the interface address each was set up
+/// with and its tasks.
+type LinkTasks = HashMap<String, (IpAddr, Vec<JoinHandle<()>>)>;
+
+/// How long interface changes are left to settle before links are updated.
+const HOTPLUG_SETTLE: Duration = Duration::from_secs(1);
+
+/// What it takes to bring links into service, or take them out, while the
+/// client runs.
+#[derive(Clone)]
+struct LinkRuntime {
+    ctx: ControlContext,
+    config: Arc<ClientConfig>,
+    counters: Arc<TrafficCounters>,
+    data_acks: Arc<DataAcks>,
+    upstream_weights: Arc<std::sync::Mutex<WeightedRoundRobin>>,
+    failback: FailbackPolicy,
+    backup_probe_divisor: u32,
+    downstream: mpsc::Sender<DownstreamPacket>,
+    routing: Arc<PolicyRouting>,
+}
+
+impl LinkRuntime {
+    /// Sets up the state of a newly bound link and adds it to the links that
+    /// may carry traffic. Its tasks are started by `spawn_link_tasks`.
+    async fn add_link(&self, iface_name: &str, socket: Arc<UdpSocket>) {
+        let link_config = self.config.link_config(iface_name);
+        self.ctx
+            .usage
+            .register(iface_name, CapLimits::from(&link_config));
+        self.counters.register(iface_name);
+        let health_policy = HealthPolicy::from(&self.config.link_health(iface_name));
+        let mut stats = LinkStats::new(link_config.priority, health_policy);
+        stats.cost = link_config.cost.max(1);
+        if health_policy.passive_monitoring {
+            self.data_acks
+                .register(iface_name, stats.monitor.stall_timeout());
+        }
+        self.ctx.congestion.register(
+            iface_name,
+            link_config
+                .max_rate_kbps
+                .map(|kbps| kbps as f64 * 1000.0 / 8.0),
+        );
+        self.ctx
+            .link_stats
+            .lock()
+            .await
+            .insert(iface_name.to_string(), stats);
+        self.ctx
+            .all_sockets
+            .write()
+            .await
+            .push((iface_name.to_string(), socket));
+    }
+
+    /// Starts the tasks that receive on, probe and check a link.
+    fn spawn_link_tasks(&self, iface_name: &str, socket: &Arc<UdpSocket>) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![
+            tokio::spawn(run_receiver(
+                self.clone(),
+                iface_name.to_string(),
+                socket.clone(),
+            )),
+            tokio::spawn(run_prober(
+                self.clone(),
+                iface_name.to_string(),
+                socket.clone(),
+            )),
+        ];
+        let link_checks = self.config.link_config(iface_name).checks;
+        if !link_checks.is_empty() {
+            tasks.push(tokio::spawn(run_checker(
+                self.clone(),
+                iface_name.to_string(),
+                link_checks,
+            )));
+        }
+        tasks
+    }
+
+    /// Stops a link's tasks and removes it, closing its socket. Only its
+    /// data usage totals are kept, for when it comes back.
+    async fn remove_link(&self, iface_name: &str, tasks: Vec<JoinHandle<()>>) {
+        for task in tasks {
+            task.abort();
+        }
+        self.ctx
+            .all_sockets
+            .write()
+            .await
+            .retain(|(name, _)| name != iface_name);
+        self.ctx.link_stats.lock().await.remove(iface_name);
+        self.data_acks.unregister(iface_name);
+        self.ctx.usage.unregister(iface_name, usage::today());
+        self.ctx.congestion.unregister(iface_name);
+        self.counters.unregister(iface_name);
+        self.upstream_weights.lock().unwrap().forget(iface_name);
+        self.routing.remove(iface_name).await;
+        refresh_active_pool(
+            &self.ctx.link_stats,
+            &self.ctx.all_sockets,
+            &self.ctx.active_sockets,
+            &self.failback,
+        )
+        .await;
+    }
+}
+
+/// Receives datagrams on a link and hands them to the downstream task.
+async fn run_receiver(rt: LinkRuntime, iface_name: String, socket: Arc<UdpSocket>) {
+    let mut buf = [0u8; DOWNSTREAM_BUF_SIZE];
+    while let Ok(len) = socket.recv(&mut buf).await {
+        let received_at = Instant::now();
+        rt.ctx.usage.record(&iface_name, len);
+        if rt
+            .downstream
+            .send((len, buf, iface_name.clone(), received_at))
+            .await
+            .is_err()
+        {
+            // Channel closed, receiver is gone.
+            break;
+        }
+    }
+}
+
+/// Probes a link, expires its unanswered probes and keeps the active pool up
+/// to date, for as long as the link exists.
+async fn run_prober(rt: LinkRuntime, iface_name: String, socket: Arc<UdpSocket>) {
+    let ctx = &rt.ctx;
+    let health_policy = HealthPolicy::from(&rt.config.link_health(&iface_name));
+    // Timeouts and the active pool are checked on every tick,
+    // probes are only sent every so many ticks.
+    let tick = if health_policy.adaptive_probing {
+        health_policy.min_probe_interval
+    } else {
+        health_policy.probe_interval
+    };
+    let mut interval = tokio::time::interval(tick);
+    let mut ticks_until_probe: u32 = 0;
+    let mut last_data_packets = None;
+    loop {
+        interval.tick().await;
+        let mut events = Vec::new();
+        let priority;
+        let capped;
+        let mut stats_guard = ctx.link_stats.lock().await;
+        if let Some(stats) = stats_guard.get_mut(&iface_name) {
+            for (probe_seq, change) in stats.monitor.on_tick() {
+                events
+                    .extend(change.map(|change| {
+                        LinkEvent::new(&iface_name, change, &stats.monitor.health())
+                    }));
+                ctx.congestion.on_loss(&iface_name);
+                warn!(
+                    "Probe timeout on {} (seq={}), consecutive failures: {}",
+                    iface_name, probe_seq, stats.monitor.consecutive_failures
+                );
+            }
+            rt.data_acks
+                .set_timeout(&iface_name, stats.monitor.stall_timeout());
+            priority = stats.effective_priority();
+            capped = stats.cap_state == CapState::Hard;
+        } else {
+            error!("Could not find stats for iface {}", iface_name);
+            continue;
+        }
+        drop(stats_guard);
+        for event in events {
+            report_status_change(&ctx.hooks, event);
+        }
+        // Re-evaluated on every tick so that a pending failback
+        // happens as soon as its delay has elapsed.
+        let tier = refresh_active_pool(
+            &ctx.link_stats,
+            &ctx.all_sockets,
+            &ctx.active_sockets,
+            &rt.failback,
+        )
+        .await;
+
+        ticks_until_probe = ticks_until_probe.saturating_sub(1);
+        if ticks_until_probe > 0 {
+            continue;
+        }
+        let data_packets = rt.counters.data_packets(&iface_name);
+        let idle = data_packets == last_data_packets;
+        last_data_packets = data_packets;
+
+        // Data the server keeps acknowledging shows the link
+        // is alive as well as a probe would.
+        let acked = rt
+            .data_acks
+            .last_ack(&iface_name)
+            .is_some_and(|at| at.elapsed() < health_policy.probe_interval);
+        let (seq, wait) = {
+            let mut stats_guard = ctx.link_stats.lock().await;
+            match stats_guard.get_mut(&iface_name) {
+                Some(stats) => {
+                    let wait = stats.monitor.next_probe_interval(idle);
+                    if health_policy.suppress_probes
+                        && acked
+                        && stats.status() == health::LinkStatus::Up
+                    {
+                        (None, wait)
+                    } else {
+                        (Some(stats.monitor.on_probe_sent()), wait)
+                    }
+                }
+                None => continue,
+            }
+        };
+        // Standby links in a lower-priority tier, and links past
+        // their data cap, are probed less often.
+        let standby = capped || tier.is_some_and(|tier| priority > tier);
+        let divisor = if standby { rt.backup_probe_divisor } else { 1 };
+        ticks_until_probe = ((wait.as_secs_f64() / tick.as_secs_f64()).round() as u32)
+            .max(1)
+            .saturating_mul(divisor);
+        let Some(seq) = seq else {
+            continue;
+        };
+        let probe_header = PacketHeader::new(seq, PacketType::Probe, ctx.client_id);
+        let header_bytes = bincode::serialize(&probe_header).unwrap();
+        let encrypted_payload = encrypt(&ctx.key, b"", probe_header.sequence_number).unwrap();
+        let probe_packet = [header_bytes.as_slice(), encrypted_payload.as_slice()].concat();
+        if socket.send(&probe_packet).await.is_ok() {
+            ctx.usage.record(&iface_name, probe_packet.len());
+            rt.counters.count_sent(&iface_name);
+        } else {
+            error!("Failed to send probe on {}", iface_name);
+            let mut stats_guard = ctx.link_stats.lock().await;
+            if let Some(stats) = stats_guard.get_mut(&iface_name) {
+                let change = stats.monitor.on_probe_send_failed(seq);
+                warn!(
+                    "Send failure on {} (seq={}), consecutive failures: {}",
+                    iface_name, seq, stats.monitor.consecutive_failures
+                );
+                if let Some(change) = change {
+                    let event = LinkEvent::new(&iface_name, change, &stats.monitor.health());
+                    report_status_change(&ctx.hooks, event);
+                }
+            }
+        }
+    }
+}
+
+/// Runs a link's extra health checks, keeping the link Down while any fails.
+async fn run_checker(rt: LinkRuntime, iface_name: String, link_checks: Vec<HealthCheck>) {
+    let ctx = &rt.ctx;
+    let check_interval = Duration::from_millis(rt.config.health.check_interval_ms);
+    let check_timeout = Duration::from_millis(rt.config.health.check_timeout_ms);
+    let mut interval = tokio::time::interval(check_interval.max(check_timeout));
+    loop {
+        interval.tick().await;
+        let passed = checks::run_all(&link_checks, &iface_name, check_timeout).await;
+        let mut stats_guard = ctx.link_stats.lock().await;
+        let Some(stats) = stats_guard.get_mut(&iface_name) else {
+            continue;
+        };
+        let event = stats
+            .monitor
+            .on_checks(passed)
+            .map(|change| LinkEvent::new(&iface_name, change, &stats.monitor.health()));
+        drop(stats_guard);
+        if let Some(event) = event {
+            report_status_change(&ctx.hooks, event);
+            refresh_active_pool(
+                &ctx.link_stats,
+                &ctx.all_sockets,
+                &ctx.active_sockets,
+                &rt.failback,
+            )
+            .await;
+        }
+    }
+}
+
+/// Follows interfaces coming and going over rtnetlink: links are added for
+/// new WAN interfaces and removed when their interface or its address goes
+/// away.
+async fn run_hotplug(rt: LinkRuntime, servers: Vec<SocketAddr>, mut links: LinkTasks) {
+    let mut watcher = match hotplug::NetlinkWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Interface hotplug disabled, could not watch netlink: {}", e);
+            return;
+        }
+    };
+    loop {
+        if let Err(e) = watcher.changed().await {
+            warn!("Failed to read interface changes: {}", e);
+        }
+        // Interfaces and their addresses tend to change in bursts.
+        tokio::time::sleep(HOTPLUG_SETTLE).await;
+        let found = match find_wan_interfaces(&rt.config, &servers) {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Failed to list interfaces: {}", e);
+                continue;
+            }
+        };
+        let current = links
+            .iter()
+            .map(|(name, (address, _))| (name.clone(), *address))
+            .collect();
+        let (removed, added) = hotplug::plan(&current, &found);
+        for iface_name in removed {
+            if let Some((address, tasks)) = links.remove(&iface_name) {
+                warn!(
+                    "Interface {} lost address {}. Removing link.",
+                    iface_name, address
+                );
+                rt.remove_link(&iface_name, tasks).await;
+            }
+        }
+        for (iface_name, address) in added {
+            info!(
+                "Found new WAN interface {} with IP {}. Adding link.",
+                iface_name, address
+            );
+            let local_addr = rt.config.link_config(&iface_name).local_addr;
+            let bound =
+                bind_link_socket(&iface_name, local_addr, address, &servers, &rt.routing).await;
+            let socket = match bound {
+                Ok(socket) => Arc::new(socket),
+                Err(e) => {
+                    error!("Failed to add link {}: {}", iface_name, e);
+                    rt.routing.remove(&iface_name).await;
+                    continue;
+                }
+            };
+            rt.add_link(&iface_name, socket.clone()).await;
+            let tasks = rt.spawn_link_tasks(&iface_name, &socket);
+            links.insert(iface_name, (address, tasks));
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
@@ -261,47 +1206,176 @@ async fn main() -> anyhow::Result<()> {
             info!("Configuration loaded from {}", cli.config);
             info!("Starting onebox client...");
 
-            let server_addr_str = format!(
-                "{}:{}",
-                config.client.server_address, config.client.server_port
+            let servers = config.client.server_addrs()?;
+            info!("Will connect to server at {:?}", servers);
+
+            let net = NetConfig::new()?;
+            let journal = Arc::new(RouteJournal::recover(&net, &config.client.route_journal).await);
+            let routing = Arc::new(
+                PolicyRouting::new(
+                    net.clone(),
+                    journal.clone(),
+                    config.client.link_binding,
+                    config.client.routing_table_base()?,
+                    servers.clone(),
+                )
+                .await,
             );
-            let server_addr: SocketAddr = server_addr_str.parse()?;
-            info!("Will connect to server at {}", server_addr);
+            let bound_links = discover_and_bind_sockets(&servers, &config.client, &routing).await?;
 
-            let all_sockets = Arc::new(discover_and_bind_sockets(server_addr).await?);
-            if all_sockets.is_empty() {
-                return Err(anyhow::anyhow!("No sockets bound, cannot proceed."));
+            let failback = FailbackPolicy {
+                enabled: config.client.failback,
+                delay: Duration::from_secs(config.client.failback_delay_secs),
+            };
+            let hooks = LinkHooks::new(&config.client.hooks);
+            let usage = match UsageTracker::load(&config.client.usage_file) {
+                Ok(usage) => usage,
+                Err(e) => {
+                    warn!(
+                        "Failed to load data usage from {}: {}. Starting from zero.",
+                        config.client.usage_file, e
+                    );
+                    UsageTracker::new(&config.client.usage_file)
+                }
+            };
+            let usage = Arc::new(usage);
+            let congestion = Arc::new(CongestionControl::new(PacerConfig {
+                enabled: config.client.congestion_control,
+                queue_delay_target: Duration::from_millis(config.client.queue_delay_target_ms),
+                max_rate: None,
+            }));
+            let link_stats = Arc::new(Mutex::new(HashMap::<String, health::LinkStats>::new()));
+            let counters = Arc::new(TrafficCounters::new());
+            let data_acks = Arc::new(DataAcks::new());
+            let all_sockets = Arc::new(RwLock::new(Vec::new()));
+            let active_sockets = Arc::new(RwLock::new(Vec::new()));
+            let upstream_weights = Arc::new(std::sync::Mutex::new(WeightedRoundRobin::new()));
+            let key = Arc::new(derive_key(&config.preshared_key));
+            let client_id = ClientId(u128::from(config.client.client_id));
+            let estimator = Arc::new(BandwidthEstimator::new());
+            let control = ControlContext {
+                link_stats: link_stats.clone(),
+                all_sockets: all_sockets.clone(),
+                active_sockets: active_sockets.clone(),
+                usage: usage.clone(),
+                congestion: congestion.clone(),
+                estimator: estimator.clone(),
+                hooks: hooks.clone(),
+                key: key.clone(),
+                client_id,
+            };
+            let (tx, mut rx) = mpsc::channel::<DownstreamPacket>(1024);
+            let runtime = LinkRuntime {
+                ctx: control.clone(),
+                config: Arc::new(config.client.clone()),
+                counters: counters.clone(),
+                data_acks: data_acks.clone(),
+                upstream_weights: upstream_weights.clone(),
+                failback,
+                backup_probe_divisor: config.client.backup_probe_divisor.max(1),
+                downstream: tx,
+                routing: routing.clone(),
+            };
+            for ((iface_name, socket), _) in &bound_links {
+                runtime.add_link(iface_name, socket.clone()).await;
             }
+            refresh_active_pool(&link_stats, &all_sockets, &active_sockets, &failback).await;
+            let (iface_name, handshake_socket) = active_sockets
+                .read()
+                .await
+                .first()
+                .or(bound_links.first().map(|(link, _)| link))
+                .cloned()
+                .unwrap();
+            info!("Performing handshake over interface '{}'", iface_name);
+            let pushed = Some(perform_handshake(&handshake_socket, &key, client_id).await?)
+                .filter(|_| config.client.use_pushed_settings);
 
-            let tun_ip: Ipv4Addr = config.client.tun_ip.parse()?;
-            let tun_netmask: Ipv4Addr = config.client.tun_netmask.parse()?;
+            let (tun_ip, tun_netmask, tun_ipv6) = match &pushed {
+                Some(settings) => (
+                    settings.address,
+                    Ipv4Addr::from(u32::MAX << (32 - u32::from(settings.prefix_len))),
+                    settings.address_v6,
+                ),
+                None => (
+                    config.client.tun_ip.parse()?,
+                    config.client.tun_netmask.parse()?,
+                    config.client.tun_ipv6()?,
+                ),
+            };
+            let tun_mtu = pushed
+                .as_ref()
+                .map(|settings| settings.mtu)
+                .or(config.client.tun_mtu);
             let tun_name = &config.client.tun_name;
             info!("Ensuring old TUN device '{}' is cleaned up...", tun_name);
-            let _ = Command::new("ip").args(["link", "delete", tun_name]).status(); // Ignore result, it's fine if it doesn't exist
-            info!("Creating TUN device '{}'...", tun_name);
-            let tun = TunBuilder::new()
+            net.delete_link(tun_name).await?;
+            info!(
+                "Creating TUN device '{}' with address {}/{}{}...",
+                tun_name,
+                tun_ip,
+                tun_netmask,
+                tun_mtu.map_or(String::new(), |mtu| format!(" and MTU {mtu}"))
+            );
+            let mut tun_builder = TunBuilder::new()
                 .name(tun_name)
                 .tap(false)
                 .packet_info(false)
                 .up()
                 .address(tun_ip)
-                .netmask(tun_netmask)
-                .try_build()
-                .map_err(|e| anyhow::anyhow!(e))?;
-            info!("TUN device created. Setting as default route...");
-            set_default_route(tun_name)?;
-
-            let active_sockets = Arc::new(RwLock::new(all_sockets.to_vec()));
-            let key = Arc::new(derive_key(&config.preshared_key));
-            let client_id = ClientId(1);
-            let (iface_name, handshake_socket) = all_sockets.first().unwrap();
-            info!("Performing handshake over interface '{}'", iface_name);
-            perform_handshake(handshake_socket, &key, client_id).await?;
+                .netmask(tun_netmask);
+            if let Some(mtu) = tun_mtu {
+                tun_builder = tun_builder.mtu(i32::from(mtu));
+            }
+            let tun = tun_builder.try_build().map_err(|e| anyhow::anyhow!(e))?;
+            if let Some((address, prefix_len)) = tun_ipv6 {
+                journal.record(&[add_tun_ipv6(&net, tun_name, address, prefix_len).await?]);
+            }
+            info!("TUN device created. Setting up tunnel routes...");
+            let split_config = &config.client.split_tunnel;
+            let split = SplitTunnel::new(
+                net.clone(),
+                journal.clone(),
+                tun_name,
+                tun_ipv6.is_some(),
+                pushed
+                    .as_ref()
+                    .map_or(Vec::new(), |settings| settings.routes.clone()),
+                split_config,
+            )
+            .await;
+            let split = match split {
+                Ok(split) => Arc::new(split),
+                Err(e) => {
+                    routing.remove_all().await;
+                    journal.restore(&net).await;
+                    return Err(e);
+                }
+            };
+            let dns_forwarder = split_config.dns_forwarder()?;
+            if let Some((listen, upstream)) = dns_forwarder {
+                tokio::spawn(dns::run_forwarder(listen, upstream, split.clone()));
+            }
+            tokio::spawn(run_reload(cli.config.clone(), split));
+            // With the forwarder running, the system already asks it.
+            let applied_dns = match (&pushed, dns_forwarder) {
+                (Some(settings), None) => resolv::apply(
+                    config.client.dns_mode,
+                    tun_name,
+                    &settings.dns_servers,
+                    &settings.dns_search,
+                    &net,
+                    &journal,
+                )
+                .unwrap_or_else(|e| {
+                    warn!("Failed to apply pushed DNS servers: {}", e);
+                    None
+                }),
+                _ => None,
+            };
             info!("Handshake complete. Starting data plane...");
 
-            let link_stats = Arc::new(Mutex::new(HashMap::<String, health::LinkStats>::new()));
-
-            let status_listener_stats = link_stats.clone();
+            let status_listener_control = control.clone();
             tokio::spawn(async move {
                 let _ = tokio::fs::remove_file(STATUS_SOCKET_PATH).await;
                 let listener = match UnixListener::bind(STATUS_SOCKET_PATH) {
@@ -314,9 +1388,9 @@ async fn main() -> anyhow::Result<()> {
                 info!("Status socket listening on {}", STATUS_SOCKET_PATH);
                 loop {
                     if let Ok((stream, _)) = listener.accept().await {
-                        let stats_clone = status_listener_stats.clone();
+                        let control_clone = status_listener_control.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = handle_status_connection(stream, stats_clone).await {
+                            if let Err(e) = handle_control_connection(stream, control_clone).await {
                                 warn!("Error handling status connection: {}", e);
                             }
                         });
@@ -324,102 +1398,150 @@ async fn main() -> anyhow::Result<()> {
                 }
             });
 
-            for (iface_name, socket) in all_sockets.iter() {
-                link_stats
-                    .lock()
-                    .await
-                    .insert(iface_name.clone(), health::LinkStats::new());
-                let prober_socket = socket.clone();
-                let prober_key = key.clone();
-                let prober_stats = link_stats.clone();
-                let prober_iface_name = iface_name.clone();
-                let prober_active_sockets = active_sockets.clone();
-                tokio::spawn(async move {
-                    const PROBE_INTERVAL: Duration = Duration::from_millis(500);
-                    const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
-                    let mut interval = tokio::time::interval(PROBE_INTERVAL);
-                    loop {
-                        interval.tick().await;
-                        let mut should_mark_down = false;
-                        let seq;
-                        let mut stats_guard = prober_stats.lock().await;
-                        if let Some(stats) = stats_guard.get_mut(&prober_iface_name) {
-                            let now = std::time::Instant::now();
-                            let timed_out_probes: Vec<u64> = stats
-                                .in_flight_probes
-                                .iter()
-                                .filter(|(_, &sent_at)| now.duration_since(sent_at) > PROBE_TIMEOUT)
-                                .map(|(&seq, _)| seq)
-                                .collect();
-                            for probe_seq in timed_out_probes {
-                                stats.in_flight_probes.remove(&probe_seq);
-                                stats.consecutive_failures += 1;
+            let accounting_usage = usage.clone();
+            let accounting_stats = link_stats.clone();
+            let accounting_all_sockets = all_sockets.clone();
+            let accounting_active_sockets = active_sockets.clone();
+            tokio::spawn(async move {
+                const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+                const SAVE_EVERY_FLUSHES: u64 = 6;
+                let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+                let mut flushes: u64 = 0;
+                loop {
+                    interval.tick().await;
+                    flushes = flushes.wrapping_add(1);
+                    let cap_states = accounting_usage.flush(usage::today());
+                    let mut changed = false;
+                    let mut stats_guard = accounting_stats.lock().await;
+                    for (name, cap_state) in cap_states {
+                        if let Some(stats) = stats_guard.get_mut(&name) {
+                            if stats.cap_state != cap_state {
                                 warn!(
-                                    "Probe timeout on {} (seq={}), consecutive failures: {}",
-                                    prober_iface_name, probe_seq, stats.consecutive_failures
+                                    "Link {} data cap state changed from {:?} to {:?}",
+                                    name, stats.cap_state, cap_state
                                 );
+                                stats.cap_state = cap_state;
+                                changed = true;
                             }
-                            if stats.consecutive_failures >= health::MAX_CONSECUTIVE_FAILURES
-                                && stats.status != health::LinkStatus::Down
-                            {
-                                stats.status = health::LinkStatus::Down;
-                                should_mark_down = true;
-                            }
-                            seq = stats.next_probe_seq;
-                            stats.next_probe_seq = stats.next_probe_seq.wrapping_add(1);
-                        } else {
-                            error!("Could not find stats for iface {}", prober_iface_name);
-                            continue;
                         }
-                        drop(stats_guard);
-                        if should_mark_down {
-                            warn!(
-                                "Link {} marked as DOWN. Removing from active pool.",
-                                prober_iface_name
-                            );
-                            let mut active_links_guard = prober_active_sockets.write().await;
-                            active_links_guard.retain(|(name, _)| name != &prober_iface_name);
-                            info!(
-                                "Link {} removed. Active links: {}",
-                                prober_iface_name,
-                                active_links_guard.len()
-                            );
+                    }
+                    drop(stats_guard);
+                    if changed {
+                        refresh_active_pool(
+                            &accounting_stats,
+                            &accounting_all_sockets,
+                            &accounting_active_sockets,
+                            &failback,
+                        )
+                        .await;
+                    }
+                    if flushes.is_multiple_of(SAVE_EVERY_FLUSHES) {
+                        if let Err(e) = accounting_usage.save() {
+                            warn!("Failed to save data usage: {}", e);
                         }
-                        let probe_header = PacketHeader::new(seq, PacketType::Probe, client_id);
-                        let header_bytes = bincode::serialize(&probe_header).unwrap();
-                        let encrypted_payload =
-                            encrypt(&prober_key, b"", probe_header.sequence_number).unwrap();
-                        let probe_packet =
-                            [header_bytes.as_slice(), encrypted_payload.as_slice()].concat();
-                        let sent_at = std::time::Instant::now();
-                        if prober_socket.send(&probe_packet).await.is_ok() {
-                            let mut stats_guard = prober_stats.lock().await;
-                            if let Some(stats) = stats_guard.get_mut(&prober_iface_name) {
-                                stats.probes_sent += 1;
-                                stats.in_flight_probes.insert(seq, sent_at);
-                            }
-                        } else {
-                            error!("Failed to send probe on {}", prober_iface_name);
-                            let mut stats_guard = prober_stats.lock().await;
-                            if let Some(stats) = stats_guard.get_mut(&prober_iface_name) {
-                                stats.consecutive_failures += 1;
-                                warn!(
-                                    "Send failure on {} (seq={}), consecutive failures: {}",
-                                    prober_iface_name, seq, stats.consecutive_failures
-                                );
+                    }
+                }
+            });
+
+            if config.client.bandwidth_probe_interval_secs > 0 {
+                let bandwidth_control = control.clone();
+                let period = Duration::from_secs(config.client.bandwidth_probe_interval_secs);
+                tokio::spawn(async move {
+                    // Give the links a moment to come up before the first round.
+                    const INITIAL_DELAY: Duration = Duration::from_secs(10);
+                    let mut interval = tokio::time::interval_at(
+                        tokio::time::Instant::now() + INITIAL_DELAY,
+                        period,
+                    );
+                    loop {
+                        interval.tick().await;
+                        for (name, result) in measure_links(&bandwidth_control, |_, stats| {
+                            stats.status() == health::LinkStatus::Up
+                                && stats.cap_state != CapState::Hard
+                        })
+                        .await
+                        {
+                            if let Err(e) = result {
+                                warn!("Bandwidth probe on {} failed: {}", name, e);
                             }
                         }
                     }
                 });
             }
 
-            let round_robin_counter = Arc::new(AtomicUsize::new(0));
+            // Tell the server about every link over every link, so that it can
+            // weight downstream traffic and knows each link's source address.
+            let table_stats = link_stats.clone();
+            let table_active_sockets = active_sockets.clone();
+            let table_all_sockets = all_sockets.clone();
+            let table_config = runtime.config.clone();
+            let table_upstream_weights = upstream_weights.clone();
+            let table_key = key.clone();
+            let table_usage = usage.clone();
+            let table_counters = counters.clone();
+            tokio::spawn(async move {
+                const LINK_TABLE_INTERVAL: Duration = Duration::from_secs(5);
+                let mut interval = tokio::time::interval(LINK_TABLE_INTERVAL);
+                // Started from the clock, so that after a restart control
+                // sequence numbers, and with them nonces, aren't reused.
+                let mut control_counter = control::counter_seed();
+                loop {
+                    interval.tick().await;
+                    let links = build_link_table(
+                        &table_stats,
+                        &table_active_sockets,
+                        &table_config,
+                        &table_upstream_weights,
+                    )
+                    .await;
+                    let sockets = table_all_sockets.read().await.clone();
+                    for (iface_name, socket) in sockets.iter() {
+                        let message = ControlMessage::LinkTable {
+                            via: iface_name.clone(),
+                            links: links.clone(),
+                        };
+                        let seq = control::control_sequence(control_counter, false);
+                        control_counter += 1;
+                        let header = PacketHeader::new(seq, PacketType::Control, client_id);
+                        let Ok(payload) = bincode::serialize(&message) else {
+                            continue;
+                        };
+                        let Ok(ciphertext) = encrypt(&table_key, &payload, seq) else {
+                            continue;
+                        };
+                        let header_bytes = bincode::serialize(&header).unwrap();
+                        let packet = [header_bytes.as_slice(), ciphertext.as_slice()].concat();
+                        match socket.send(&packet).await {
+                            Ok(len) => {
+                                table_usage.record(iface_name, len);
+                                table_counters.count_sent(iface_name);
+                            }
+                            Err(e) => debug!("Failed to send link table on {}: {}", iface_name, e),
+                        }
+                    }
+                }
+            });
+
+            let mut links = HashMap::new();
+            for ((iface_name, socket), address) in &bound_links {
+                let tasks = runtime.spawn_link_tasks(iface_name, socket);
+                links.insert(iface_name.clone(), (*address, tasks));
+            }
+            tokio::spawn(run_hotplug(runtime.clone(), servers, links));
+
             let sequence_number = Arc::new(AtomicU64::new(0));
             let (mut tun_reader, mut tun_writer) = tokio::io::split(tun);
             let tun_to_udp_active_sockets = active_sockets.clone();
-            let tun_to_udp_counter = round_robin_counter.clone();
+            let tun_to_udp_weights = upstream_weights.clone();
+            let tun_to_udp_counters = counters.clone();
             let tun_to_udp_seq = sequence_number.clone();
             let tun_to_udp_key = key.clone();
+            let tun_to_udp_usage = usage.clone();
+            let tun_to_udp_congestion = congestion.clone();
+            let tun_to_udp_acks = data_acks.clone();
+            let tun_to_udp_stats = link_stats.clone();
+            let tun_to_udp_all_sockets = all_sockets.clone();
+            let tun_to_udp_hooks = hooks.clone();
             let tun_to_udp = tokio::spawn(async move {
                 const MTU: usize = 1500;
                 const HEADER_SIZE: usize = PacketHeader::size();
@@ -456,21 +1578,69 @@ async fn main() -> anyhow::Result<()> {
                                 .expect("Serialization into a fixed-size buffer should not fail");
                             let packet_to_send = &packet_buf[..HEADER_SIZE + ciphertext_len];
 
-                            // Send the packet over a chosen link using round-robin
+                            // Send the packet over a link chosen by weighted round-robin,
+                            // skipping links whose pacer is already saturated.
                             let active_links_guard = tun_to_udp_active_sockets.read().await;
-                            if active_links_guard.is_empty() {
+                            let start = tun_to_udp_weights
+                                .lock()
+                                .unwrap()
+                                .pick(&active_links_guard)
+                                .unwrap_or(0);
+                            let Some((index, delay)) = tun_to_udp_congestion.schedule(
+                                &active_links_guard,
+                                start,
+                                packet_to_send.len(),
+                            ) else {
                                 drop(active_links_guard);
                                 warn!("No active links available to send data. Waiting...");
                                 tokio::time::sleep(Duration::from_secs(1)).await;
                                 continue;
-                            }
+                            };
+                            let (iface_name, socket) = active_links_guard[index].clone();
+                            drop(active_links_guard);
 
-                            let index = tun_to_udp_counter.fetch_add(1, Ordering::Relaxed)
-                                % active_links_guard.len();
-                            let (iface_name, socket) = &active_links_guard[index];
+                            // Every link is saturated: wait for the chosen one to free up.
+                            if !delay.is_zero() {
+                                tokio::time::sleep(delay).await;
+                            }
 
-                            if let Err(e) = socket.send(packet_to_send).await {
-                                warn!("Failed to send packet on {}: {}", iface_name, e);
+                            let failure = match socket.send(packet_to_send).await {
+                                Ok(len) => {
+                                    tun_to_udp_usage.record(&iface_name, len);
+                                    tun_to_udp_counters.count_sent(&iface_name);
+                                    tun_to_udp_counters.count_data(&iface_name);
+                                    tun_to_udp_acks
+                                        .on_sent(&iface_name, seq, Instant::now())
+                                        .then_some(DataFailure::Unacknowledged)
+                                }
+                                Err(e) => {
+                                    warn!("Failed to send packet on {}: {}", iface_name, e);
+                                    Some(DataFailure::SendError)
+                                }
+                            };
+                            // A link failing every send gets one failure applied at
+                            // a time, not a task per packet.
+                            let failure =
+                                failure.filter(|_| tun_to_udp_acks.begin_failure(&iface_name));
+                            if let Some(failure) = failure {
+                                let failure_acks = tun_to_udp_acks.clone();
+                                let failure_stats = tun_to_udp_stats.clone();
+                                let failure_all_sockets = tun_to_udp_all_sockets.clone();
+                                let failure_active_sockets = tun_to_udp_active_sockets.clone();
+                                let failure_hooks = tun_to_udp_hooks.clone();
+                                tokio::spawn(async move {
+                                    handle_data_failure(
+                                        failure_stats,
+                                        failure_all_sockets,
+                                        failure_active_sockets,
+                                        failback,
+                                        failure_hooks,
+                                        iface_name.clone(),
+                                        failure,
+                                    )
+                                    .await;
+                                    failure_acks.end_failure(&iface_name);
+                                });
                             }
                         }
                     }
@@ -478,47 +1648,54 @@ async fn main() -> anyhow::Result<()> {
             });
 
             // Downstream Data Plane: UDP -> TUN
-            // One task per socket to receive, a single task to process and write to TUN
-            const DOWNSTREAM_BUF_SIZE: usize = 2048;
-            let (tx, mut rx) =
-                tokio::sync::mpsc::channel::<(usize, [u8; DOWNSTREAM_BUF_SIZE], String)>(1024);
-
-            for (iface_name, socket) in all_sockets.iter() {
-                let tx_clone = tx.clone();
-                let iface_name_clone = iface_name.clone();
-                let socket_clone = socket.clone();
-                tokio::spawn(async move {
-                    let mut buf = [0u8; DOWNSTREAM_BUF_SIZE];
-                    while let Ok(len) = socket_clone.recv(&mut buf).await {
-                        if tx_clone.send((len, buf, iface_name_clone.clone())).await.is_err() {
-                            // Channel closed, receiver is gone.
-                            break;
-                        }
-                    }
-                });
-            }
-            drop(tx); // Drop the original sender so the channel closes when all clones are dropped.
+            // One task per link receives (see `run_receiver`), a single task
+            // processes and writes to TUN.
 
             let udp_to_tun_stats = link_stats.clone();
             let downstream_key = key.clone();
-            let downstream_active_sockets = active_sockets.clone();
-            let downstream_all_sockets = all_sockets.clone();
+            let downstream_estimator = estimator.clone();
+            let downstream_counters = counters.clone();
+            let downstream_acks = data_acks.clone();
+            let downstream_congestion = congestion.clone();
+            let downstream_control = control.clone();
 
             let udp_to_tun = tokio::spawn(async move {
-                while let Some((len, mut packet_buf, iface_name)) = rx.recv().await {
+                while let Some((len, mut packet_buf, iface_name, received_at)) = rx.recv().await {
                     if let Ok(header) = bincode::deserialize::<PacketHeader>(&packet_buf[..len]) {
+                        // Bandwidth probe trains are bursts that would skew the
+                        // loss windows, so both ends leave them out of the counters.
+                        if matches!(
+                            header.packet_type,
+                            PacketType::Data | PacketType::Probe | PacketType::Control
+                        ) {
+                            downstream_counters.count_received(&iface_name);
+                        }
                         match header.packet_type {
+                            PacketType::Probe
+                                if control::server_probe_counter(header.sequence_number)
+                                    .is_some() =>
+                            {
+                                echo_server_probe(
+                                    &header,
+                                    &mut packet_buf,
+                                    len,
+                                    &iface_name,
+                                    &downstream_control,
+                                    &downstream_counters,
+                                )
+                                .await;
+                            }
                             PacketType::Probe => {
                                 handle_probe_response(
                                     &header,
                                     &iface_name,
-                                    udp_to_tun_stats.clone(),
-                                    &downstream_all_sockets,
-                                    &downstream_active_sockets,
+                                    &downstream_control,
+                                    &failback,
                                 )
                                 .await;
                             }
                             PacketType::Data => {
+                                downstream_counters.count_data(&iface_name);
                                 if let Err(e) = handle_data_packet(
                                     &header,
                                     &mut packet_buf,
@@ -528,10 +1705,68 @@ async fn main() -> anyhow::Result<()> {
                                 )
                                 .await
                                 {
-                                    warn!("Error handling data packet: {}. Stopping downstream task.", e);
+                                    warn!(
+                                        "Error handling data packet: {}. Stopping downstream task.",
+                                        e
+                                    );
                                     break; // Exit loop on critical error (e.g., TUN write failure)
                                 }
                             }
+                            PacketType::BandwidthProbe => {
+                                if let Some(probe) =
+                                    open_payload(&header, &mut packet_buf, len, &downstream_key)
+                                        .and_then(|payload| {
+                                            bincode::deserialize::<BandwidthProbe>(payload).ok()
+                                        })
+                                {
+                                    downstream_estimator.on_probe_packet(&probe, len, received_at);
+                                }
+                            }
+                            PacketType::BandwidthReport => {
+                                if let Some(report) =
+                                    open_payload(&header, &mut packet_buf, len, &downstream_key)
+                                        .and_then(|payload| {
+                                            bincode::deserialize::<BandwidthReport>(payload).ok()
+                                        })
+                                {
+                                    downstream_estimator.on_report(report);
+                                }
+                            }
+                            PacketType::Control => {
+                                let message =
+                                    open_payload(&header, &mut packet_buf, len, &downstream_key)
+                                        .and_then(|payload| {
+                                            bincode::deserialize::<ControlMessage>(payload).ok()
+                                        });
+                                match message {
+                                    Some(ControlMessage::LinkFeedback {
+                                        link,
+                                        received,
+                                        sent,
+                                    }) => {
+                                        handle_link_feedback(
+                                            &link,
+                                            received,
+                                            sent,
+                                            &udp_to_tun_stats,
+                                            &downstream_counters,
+                                        )
+                                        .await;
+                                    }
+                                    Some(ControlMessage::DataAck { highest }) => {
+                                        // Acknowledged data paces the link even when no
+                                        // probes are sent.
+                                        if let Some(rtt) = downstream_acks.on_ack(
+                                            &iface_name,
+                                            highest,
+                                            received_at,
+                                        ) {
+                                            downstream_congestion.on_rtt_sample(&iface_name, rtt);
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            }
                             _ => {
                                 // Ignore other packet types like AuthRequest, etc.
                             }
@@ -542,20 +1777,26 @@ async fn main() -> anyhow::Result<()> {
             tokio::select! {
                 _ = tun_to_udp => info!("TUN->UDP task finished."),
                 _ = udp_to_tun => info!("UDP->TUN task finished."),
+                _ = shutdown_signal() => info!("Shutting down."),
             };
+            // Bytes counted since the last periodic save are billed too.
+            usage.flush(usage::today());
+            if let Err(e) = usage.save() {
+                warn!("Failed to save data usage: {}", e);
+            }
+            resolv::restore(applied_dns, tun_name);
+            routing.remove_all().await;
+            journal.restore(&net).await;
         }
         Commands::Stop => info!("Client stop not yet implemented"),
-        Commands::Status => {
-            match UnixStream::connect(STATUS_SOCKET_PATH).await {
-                Ok(mut stream) => {
-                    let mut response = String::new();
-                    stream.read_to_string(&mut response).await?;
-                    print!("{}", response);
-                }
-                Err(_) => {
-                    eprintln!("Could not get client status. Is the client running?");
-                }
-            }
+        Commands::Status => query_client("status").await?,
+        Commands::Metrics => query_client("metrics").await?,
+        Commands::Bandwidth { link } => {
+            let request = match link {
+                Some(link) => format!("bandwidth {}", link),
+                None => "bandwidth".to_string(),
+            };
+            query_client(&request).await?
         }
         Commands::Config => {
             println!("Configuration loaded from: {}", &cli.config);
@@ -565,18 +1806,78 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn set_default_route(tun_name: &str) -> anyhow::Result<()> {
-    info!("Setting default route to {}", tun_name);
-    let commands = [
-        vec!["route", "add", "0.0.0.0/1", "dev", tun_name],
-        vec!["route", "add", "128.0.0.0/1", "dev", tun_name],
-    ];
-    for args in &commands {
-        let status = Command::new("ip").args(args).status()?;
-        if !status.success() {
-            return Err(anyhow::anyhow!("Failed to set default route"));
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Sends a request to the running client over the status socket and prints
+/// its answer.
+async fn query_client(request: &str) -> anyhow::Result<()> {
+    match UnixStream::connect(STATUS_SOCKET_PATH).await {
+        Ok(mut stream) => {
+            stream.write_all(format!("{request}\n").as_bytes()).await?;
+            stream.shutdown().await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            print!("{}", response);
+        }
+        Err(_) => {
+            eprintln!("Could not get client status. Is the client running?");
         }
     }
-    info!("Default route successfully set to {}", tun_name);
     Ok(())
 }
+
+/// Adds the TUN device's IPv6 address. The tunnel is point-to-point, so
+/// duplicate address detection is skipped.
+async fn add_tun_ipv6(
+    net: &NetConfig,
+    tun_name: &str,
+    address: Ipv6Addr,
+    prefix_len: u8,
+) -> anyhow::Result<Change> {
+    info!(
+        "Adding IPv6 address {}/{} to {}",
+        address, prefix_len, tun_name
+    );
+    net.add_address(tun_name, address.into(), prefix_len)
+        .await?;
+    Ok(Change::Address {
+        dev: tun_name.to_string(),
+        address: address.into(),
+        prefix_len,
+    })
+}
+
+/// Re-reads the split tunnel rules from the configuration file on SIGHUP.
+async fn run_reload(config_path: String, split: Arc<SplitTunnel>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Reloading split tunnel rules from {}", config_path);
+        let result = match Config::from_file(&config_path) {
+            Ok(config) => split.update(&config.client.split_tunnel).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Failed to reload split tunnel rules: {}", e);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! DNS servers pushed by the server, applied either as per-link DNS on the
+//! TUN device through systemd-resolved, or by rewriting `/etc/resolv.conf`.
+//!
+//! systemd-resolved forgets a link's DNS when the link goes away, so only the
+//! rewritten `/etc/resolv.conf` goes in the route journal to be put back.
+
+use crate::journal::RouteJournal;
+use onebox_core::config::DnsMode;
+use onebox_core::netconfig::NetConfig;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+use tracing::{info, warn};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Present while systemd-resolved runs.
+const RESOLVED_RUNTIME_DIR: &str = "/run/systemd/resolve";
+
+/// How the pushed DNS settings were applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppliedDns {
+    Resolved,
+    File,
+}
+
+/// Applies the DNS servers and search domains. Returns how, or `None` when
+/// there are no servers or `mode` is off.
+pub fn apply(
+    mode: DnsMode,
+    tun_name: &str,
+    servers: &[IpAddr],
+    search: &[String],
+    net: &NetConfig,
+    journal: &RouteJournal,
+) -> anyhow::Result<Option<AppliedDns>> {
+    if servers.is_empty() {
+        return Ok(None);
+    }
+    let applied = match mode {
+        DnsMode::Off => return Ok(None),
+        DnsMode::Resolved => AppliedDns::Resolved,
+        DnsMode::File => AppliedDns::File,
+        DnsMode::Auto if Path::new(RESOLVED_RUNTIME_DIR).is_dir() => AppliedDns::Resolved,
+        DnsMode::Auto => AppliedDns::File,
+    };
+    match applied {
+        AppliedDns::Resolved => {
+            let servers: Vec<String> = servers.iter().map(ToString::to_string).collect();
+            resolvectl("dns", tun_name, &servers)?;
+            // "~." makes the tunnel's servers answer for every domain.
+            let mut domains = vec!["~.".to_string()];
+            domains.extend(search.iter().cloned());
+            resolvectl("domain", tun_name, &domains)?;
+            resolvectl("default-route", tun_name, &["true".to_string()])?;
+        }
+        AppliedDns::File => {
+            let mut transaction = net.transaction();
+            transaction.write_file(RESOLV_CONF, &resolv_conf(servers, search))?;
+            journal.record(&transaction.commit());
+        }
+    }
+    info!("DNS servers {:?} applied via {:?}", servers, applied);
+    Ok(Some(applied))
+}
+
+/// Drops the TUN device's DNS settings in systemd-resolved. A rewritten
+/// `/etc/resolv.conf` is restored with the rest of the journal.
+pub fn restore(applied: Option<AppliedDns>, tun_name: &str) {
+    if applied == Some(AppliedDns::Resolved) {
+        if let Err(e) = resolvectl("revert", tun_name, &[]) {
+            warn!("{}", e);
+        }
+    }
+}
+
+fn resolvectl(command: &str, tun_name: &str, args: &[String]) -> anyhow::Result<()> {
+    let output = Command::new("resolvectl")
+        .arg(command)
+        .arg(tun_name)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run resolvectl: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "resolvectl {} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn resolv_conf(servers: &[IpAddr], search: &[String]) -> String {
+    let mut conf = String::from("# Written by onebox-client, and restored when it exits.\n");
+    for server in servers {
+        conf.push_str(&format!("nameserver {server}\n"));
+    }
+    if !search.is_empty() {
+        conf.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolv_conf_lists_servers_and_search_domains() {
+        let servers = ["10.99.99.1".parse().unwrap(), "fd00:8::1".parse().unwrap()];
+        assert_eq!(
+            resolv_conf(&servers, &["corp.example".to_string(), "lan".to_string()]),
+            "# Written by onebox-client, and restored when it exits.\n\
+             nameserver 10.99.99.1\n\
+             nameserver fd00:8::1\n\
+             search corp.example lan\n"
+        );
+        assert!(!resolv_conf(&servers[..1], &[]).contains("search"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-link routing. Every link gets a slot numbered from
+//! `routing_table_base`, and in the main table a host route to each server
+//! address through the link's gateway, with the slot as its metric. These
+//! pinned routes keep the tunnel's own packets out of the tunnel even where
+//! a socket isn't bound to its interface.
+//!
+//! In the policy and mark binding modes, the alternatives to binding link
+//! sockets to their interface, the slot is also a routing table with a
+//! default route through the link's gateway, and the priority of a rule
+//! sending the link's traffic to that table, matched by source address or
+//! by firewall mark. Whatever an earlier run left at those priorities is
+//! cleared first.
+//!
+//! Every route and rule is recorded in the route journal until removed.
+
+use crate::journal::RouteJournal;
+use onebox_core::config::{LinkBinding, ROUTING_TABLES};
+use onebox_core::netconfig::{Change, Family, NetConfig, Route, Rule, Transaction};
+use onebox_core::OneboxResult;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// The per-link routes and rules the client has installed.
+#[derive(Debug)]
+pub struct PolicyRouting {
+    net: NetConfig,
+    journal: Arc<RouteJournal>,
+    mode: LinkBinding,
+    table_base: u32,
+    servers: Vec<SocketAddr>,
+    /// Each link's slot, with the changes made for it.
+    tables: Mutex<BTreeMap<String, (u32, Vec<Change>)>>,
+}
+
+impl PolicyRouting {
+    /// Sets up routing for the binding mode, clearing rules and tables a
+    /// previous run left behind.
+    pub async fn new(
+        net: NetConfig,
+        journal: Arc<RouteJournal>,
+        mode: LinkBinding,
+        table_base: u32,
+        servers: Vec<SocketAddr>,
+    ) -> Self {
+        let routing = Self {
+            net,
+            journal,
+            mode,
+            table_base,
+            servers,
+            tables: Mutex::new(BTreeMap::new()),
+        };
+        if mode != LinkBinding::Device {
+            routing.clear_stale().await;
+        }
+        routing
+    }
+
+    pub fn mode(&self) -> LinkBinding {
+        self.mode
+    }
+
+    /// Installs the routes, and the table and rule outside device mode, of a
+    /// link with the given source address, replacing any it had. Returns the
+    /// slot, which in mark mode is also the mark its socket must set.
+    pub async fn install(&self, iface: &str, source: IpAddr) -> anyhow::Result<u32> {
+        let mut tables = self.tables.lock().await;
+        let table = match tables.remove(iface) {
+            Some((table, changes)) => {
+                self.undo(changes).await;
+                table
+            }
+            None => free_table(&tables, self.table_base)
+                .ok_or_else(|| anyhow::anyhow!("No free routing table for {}", iface))?,
+        };
+        if self.mode != LinkBinding::Device {
+            self.clear_table(table).await;
+        }
+
+        let family = Family::of(source);
+        let gateway = self
+            .net
+            .default_route(family, Some(iface))
+            .await?
+            .and_then(|route| route.gateway);
+        let mut transaction = self.net.transaction();
+        let result = self
+            .add_routes(&mut transaction, iface, source, table, gateway)
+            .await;
+        if let Err(e) = result {
+            transaction.rollback().await;
+            return Err(e.into());
+        }
+        let changes = transaction.commit();
+        self.journal.record(&changes);
+
+        let via = gateway.map_or("its interface".to_string(), |gw| gw.to_string());
+        match self.mode {
+            LinkBinding::Device => info!("Server routes pinned to link {} via {}", iface, via),
+            _ => info!("Link {} routed by table {} via {}", iface, table, via),
+        }
+        tables.insert(iface.to_string(), (table, changes));
+        Ok(table)
+    }
+
+    async fn add_routes(
+        &self,
+        transaction: &mut Transaction<'_>,
+        iface: &str,
+        source: IpAddr,
+        table: u32,
+        gateway: Option<IpAddr>,
+    ) -> OneboxResult<()> {
+        let family = Family::of(source);
+        for server in self
+            .servers
+            .iter()
+            .filter(|s| s.is_ipv4() == source.is_ipv4())
+        {
+            let pin = Route::host(server.ip())
+                .via(gateway)
+                .dev(iface)
+                .metric(table);
+            transaction.add_route(pin).await?;
+        }
+        if self.mode == LinkBinding::Device {
+            return Ok(());
+        }
+        let route = Route::default_for(family)
+            .via(gateway)
+            .dev(iface)
+            .table(table);
+        transaction.add_route(route).await?;
+        let rule = Rule {
+            family,
+            priority: table,
+            table,
+            from: (self.mode != LinkBinding::Mark).then_some(source),
+            fwmark: (self.mode == LinkBinding::Mark).then_some(table),
+        };
+        transaction.add_rule(rule).await
+    }
+
+    /// Removes a link's routes, rule and table.
+    pub async fn remove(&self, iface: &str) {
+        let removed = self.tables.lock().await.remove(iface);
+        if let Some((table, changes)) = removed {
+            debug!("Removing routing table {} of link {}", table, iface);
+            self.undo(changes).await;
+        }
+    }
+
+    /// Removes every link's routes, rule and table, on the way out.
+    pub async fn remove_all(&self) {
+        let tables = std::mem::take(&mut *self.tables.lock().await);
+        for (iface, (table, changes)) in tables {
+            debug!("Removing routing table {} of link {}", table, iface);
+            self.undo(changes).await;
+        }
+    }
+
+    async fn undo(&self, changes: Vec<Change>) {
+        self.net.revert_all(changes.clone()).await;
+        self.journal.forget(&changes);
+    }
+
+    async fn clear_stale(&self) {
+        let range = self.table_base..=self.table_base + ROUTING_TABLES - 1;
+        let mut tables = match self.net.delete_rules(range).await {
+            Ok(tables) => tables,
+            Err(e) => {
+                warn!("Failed to clear rules left by an earlier run: {}", e);
+                return;
+            }
+        };
+        tables.sort_unstable();
+        tables.dedup();
+        for table in tables {
+            warn!("Removing routing table {} left by an earlier run", table);
+            self.clear_table(table).await;
+        }
+    }
+
+    /// Deletes the rules at a table's priority and flushes the table. Either
+    /// may already be gone.
+    async fn clear_table(&self, table: u32) {
+        if let Err(e) = self.net.delete_rules(table..=table).await {
+            warn!("Failed to delete rules of table {}: {}", table, e);
+        }
+        if let Err(e) = self.net.flush_table(table).await {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// The lowest slot from the base that no link uses.
+fn free_table(tables: &BTreeMap<String, (u32, Vec<Change>)>, table_base: u32) -> Option<u32> {
+    (table_base..table_base + ROUTING_TABLES)
+        .find(|table| !tables.values().any(|(used, _)| used == table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_take_the_lowest_free_table() {
+        let mut tables = BTreeMap::new();
+        assert_eq!(free_table(&tables, 7000), Some(7000));
+        tables.insert("eth0".to_string(), (7000, Vec::new()));
+        tables.insert("wlan0".to_string(), (7002, Vec::new()));
+        assert_eq!(free_table(&tables, 7000), Some(7001));
+        let full = (0..ROUTING_TABLES)
+            .map(|i| (format!("eth{i}"), (7000 + i, Vec::new())))
+            .collect();
+        assert_eq!(free_table(&full, 7000), None);
+    }
+}
@@ -0,0 +1,613 @@
+//! Split tunneling: the routes that decide which traffic enters the TUN
+//! device and which bypasses it.
+//!
+//! The routes wanted are worked out from the `[client.split_tunnel]` rules
+//! and the addresses learned for domain rules, then compared with those
+//! installed, so that rules can change while the client runs. Routes through
+//! the tunnel come in first, so nothing leaks while they are swapped.
+//!
+//! Learned addresses are kept for their records' TTL, but at least an hour so
+//! that connections outlive short TTLs, and only so many are kept. Each DNS
+//! answer only adds and removes the host routes of the addresses it touches.
+
+use crate::journal::RouteJournal;
+use onebox_core::config::SplitTunnelConfig;
+use onebox_core::netconfig::{Change, Family, NetConfig, Route};
+use onebox_core::types::glob_match;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// The shortest time a learned address is kept, whatever its TTL.
+const MIN_LEARNED_TTL: Duration = Duration::from_secs(3600);
+
+/// The most addresses kept learned at once.
+const MAX_LEARNED: usize = 4096;
+
+/// Where a destination's traffic is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Via {
+    Tunnel,
+    Bypass,
+}
+
+/// The parsed split tunneling rules.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Rules {
+    /// Networks the server pushed. They go through the tunnel like included
+    /// ones, but don't turn off tunneling everything else.
+    pushed: Vec<(IpAddr, u8)>,
+    include: Vec<(IpAddr, u8)>,
+    exclude: Vec<(IpAddr, u8)>,
+    bypass_link: Option<String>,
+    include_domains: Vec<String>,
+    exclude_domains: Vec<String>,
+}
+
+impl Rules {
+    fn from_config(config: &SplitTunnelConfig, pushed: &[(IpAddr, u8)]) -> anyhow::Result<Self> {
+        let domains = |domains: &[String]| domains.iter().map(|d| normalize(d)).collect();
+        Ok(Self {
+            pushed: pushed.to_vec(),
+            include: config.include_networks()?,
+            exclude: config.exclude_networks()?,
+            bypass_link: config.bypass_link.clone(),
+            include_domains: domains(&config.include_domains),
+            exclude_domains: domains(&config.exclude_domains),
+        })
+    }
+
+    /// Whether everything goes through the tunnel but what is excluded.
+    fn tunnel_by_default(&self) -> bool {
+        self.include.is_empty() && self.include_domains.is_empty()
+    }
+
+    /// Where a domain rule sends the named host's traffic, if one matches.
+    fn domain_via(&self, name: &str) -> Option<Via> {
+        let name = normalize(name);
+        let matches = |domains: &[String]| domains.iter().any(|domain| in_domain(&name, domain));
+        if matches(&self.exclude_domains) {
+            Some(Via::Bypass)
+        } else if matches(&self.include_domains) {
+            Some(Via::Tunnel)
+        } else {
+            None
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `name` is `domain` or one of its subdomains. Domains may also be
+/// globs such as `*.example.com`.
+fn in_domain(name: &str, domain: &str) -> bool {
+    if domain.contains('*') {
+        return glob_match(domain, name);
+    }
+    name == domain
+        || name
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// An address resolved for a name matching a domain rule.
+#[derive(Debug, Clone, PartialEq)]
+struct Learned {
+    name: String,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    rules: Rules,
+    learned: BTreeMap<IpAddr, Learned>,
+    installed: Vec<Route>,
+}
+
+/// Records the addresses `name` resolved to, with their TTLs in seconds,
+/// then drops the expired ones and, beyond `max`, those expiring soonest,
+/// i.e. seen least recently. Returns the addresses whose route changes: those
+/// new or now learned for another name, and those dropped.
+fn record(
+    learned: &mut BTreeMap<IpAddr, Learned>,
+    name: &str,
+    addrs: &[(IpAddr, u32)],
+    now: Instant,
+    max: usize,
+) -> (Vec<IpAddr>, Vec<IpAddr>) {
+    let name = normalize(name);
+    let mut added = Vec::new();
+    for &(addr, ttl) in addrs {
+        let expires = now + Duration::from_secs(u64::from(ttl)).max(MIN_LEARNED_TTL);
+        let entry = Learned {
+            name: name.clone(),
+            expires,
+        };
+        match learned.insert(addr, entry) {
+            Some(old) if old.name == name => {}
+            _ => added.push(addr),
+        }
+    }
+    let mut dropped: Vec<IpAddr> = learned
+        .iter()
+        .filter(|(_, learned)| learned.expires <= now)
+        .map(|(&addr, _)| addr)
+        .collect();
+    for addr in &dropped {
+        learned.remove(addr);
+    }
+    if learned.len() > max {
+        let mut by_expiry: Vec<(Instant, IpAddr)> = learned
+            .iter()
+            .map(|(&addr, learned)| (learned.expires, addr))
+            .collect();
+        by_expiry.sort();
+        for &(_, addr) in &by_expiry[..learned.len() - max] {
+            learned.remove(&addr);
+            dropped.push(addr);
+        }
+    }
+    added.retain(|addr| learned.contains_key(addr));
+    (added, dropped)
+}
+
+/// The split tunneling routes the client has installed.
+#[derive(Debug)]
+pub struct SplitTunnel {
+    net: NetConfig,
+    journal: Arc<RouteJournal>,
+    tun_name: String,
+    /// Whether the tunnel carries IPv6.
+    ipv6: bool,
+    /// Networks the server pushed.
+    pushed: Vec<(IpAddr, u8)>,
+    state: Mutex<State>,
+}
+
+impl SplitTunnel {
+    /// Installs the routes the rules and the networks the server pushed
+    /// ask for.
+    pub async fn new(
+        net: NetConfig,
+        journal: Arc<RouteJournal>,
+        tun_name: &str,
+        ipv6: bool,
+        pushed: Vec<(IpAddr, u8)>,
+        config: &SplitTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let split = Self {
+            net,
+            journal,
+            tun_name: tun_name.to_string(),
+            ipv6,
+            pushed,
+            state: Mutex::new(State::default()),
+        };
+        split.update(config).await?;
+        Ok(split)
+    }
+
+    /// Switches to new rules, changing only the routes that differ.
+    pub async fn update(&self, config: &SplitTunnelConfig) -> anyhow::Result<()> {
+        let rules = Rules::from_config(config, &self.pushed)?;
+        let mut state = self.state.lock().await;
+        if state.rules == rules && !state.installed.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        state.learned.retain(|_, learned| {
+            learned.expires > now && rules.domain_via(&learned.name).is_some()
+        });
+        state.rules = rules;
+        self.sync(&mut state).await
+    }
+
+    /// Takes note of the addresses a name resolved to, with their TTLs in
+    /// seconds. Those of a name matching a domain rule are routed
+    /// accordingly before the answer is passed on, and expired ones lose
+    /// their routes.
+    pub async fn learn(&self, name: &str, addrs: &[(IpAddr, u32)]) {
+        let mut state = self.state.lock().await;
+        // Every answer drops expired addresses, but only those of matching
+        // names are learned.
+        let via = state.rules.domain_via(name);
+        let addrs = if via.is_some() { addrs } else { &[] };
+        let (added, dropped) = record(&mut state.learned, name, addrs, Instant::now(), MAX_LEARNED);
+        for addr in dropped {
+            let host = Route::host(addr);
+            let installed = state
+                .installed
+                .iter()
+                .find(|route| same_destination(route, &host))
+                .cloned();
+            if let Some(route) = installed {
+                debug!("Forgot {}", addr);
+                self.remove(&mut state, &route).await;
+            }
+        }
+        let Some(via) = via else {
+            return;
+        };
+        for addr in added {
+            debug!("Learned {} for {}", addr, name);
+            let family = Family::of(addr);
+            let mut bypass = [None, None];
+            if via == Via::Bypass {
+                bypass[usize::from(family == Family::Ipv6)] =
+                    self.bypass_route(&state.rules, family).await;
+            }
+            let host = Route::host(addr);
+            let Some(route) = route_for(
+                (host.destination, host.prefix_len),
+                via,
+                &self.tun_name,
+                self.ipv6,
+                &bypass,
+            ) else {
+                continue;
+            };
+            if let Err(e) = self.install(&mut state, route).await {
+                warn!("Failed to route {} for {}: {}", addr, name, e);
+            }
+        }
+    }
+
+    /// Adds a route, in place of any installed one to the same destination.
+    async fn install(&self, state: &mut State, route: Route) -> anyhow::Result<()> {
+        if state.installed.contains(&route) {
+            return Ok(());
+        }
+        let old = state
+            .installed
+            .iter()
+            .find(|old| same_destination(old, &route))
+            .cloned();
+        if let Some(old) = old {
+            self.remove(state, &old).await;
+        }
+        self.net.add_route(&route).await?;
+        debug!("Added split tunnel route {}", route);
+        self.journal.record(&[Change::Route(route.clone())]);
+        state.installed.push(route);
+        Ok(())
+    }
+
+    async fn sync(&self, state: &mut State) -> anyhow::Result<()> {
+        let mut bypass = [None, None];
+        for (slot, family) in bypass.iter_mut().zip([Family::Ipv4, Family::Ipv6]) {
+            *slot = self.bypass_route(&state.rules, family).await;
+        }
+        let wanted = plan(
+            &state.rules,
+            &state.learned,
+            &self.tun_name,
+            self.ipv6,
+            &bypass,
+        );
+        let (mut stale, mut new) = diff(&state.installed, &wanted);
+
+        // Tunnel routes first, so that traffic meant for the tunnel never
+        // takes the host's default route while routes are swapped.
+        new.sort_by_key(|route| route.dev.as_deref() != Some(self.tun_name.as_str()));
+        let mut result = Ok(());
+        for route in new {
+            // A route to the same network, e.g. through a gateway that has
+            // changed, must go before its replacement can be added.
+            if let Some(i) = stale.iter().position(|old| same_destination(old, &route)) {
+                let old = stale.swap_remove(i);
+                self.remove(state, &old).await;
+            }
+            match self.net.add_route(&route).await {
+                Ok(()) => {
+                    debug!("Added split tunnel route {}", route);
+                    self.journal.record(&[Change::Route(route.clone())]);
+                    state.installed.push(route);
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+        }
+        // Routes no longer wanted stay until their replacements are in.
+        if result.is_ok() {
+            for old in stale {
+                self.remove(state, &old).await;
+            }
+            info!("Split tunnel: {} routes installed", state.installed.len());
+        }
+        result
+    }
+
+    async fn remove(&self, state: &mut State, route: &Route) {
+        match self.net.delete_route(route).await {
+            Ok(()) => debug!("Removed split tunnel route {}", route),
+            Err(e) => warn!("{}", e),
+        }
+        self.journal.forget(&[Change::Route(route.clone())]);
+        state.installed.retain(|installed| installed != route);
+    }
+
+    /// The route bypassing traffic of the family takes: the default route
+    /// through the bypass link, or the host's own default route.
+    async fn bypass_route(&self, rules: &Rules, family: Family) -> Option<Route> {
+        match self
+            .net
+            .default_route(family, rules.bypass_link.as_deref())
+            .await
+        {
+            Ok(Some(route)) => Some(route),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to find the {:?} bypass route: {}", family, e);
+                None
+            }
+        }
+    }
+}
+
+/// The routes the rules and learned addresses call for. `bypass` holds the
+/// IPv4 and IPv6 default routes bypassing traffic follows; networks of a
+/// family without one are left to the host's routing table.
+fn plan(
+    rules: &Rules,
+    learned: &BTreeMap<IpAddr, Learned>,
+    tun_name: &str,
+    ipv6: bool,
+    bypass: &[Option<Route>; 2],
+) -> Vec<Route> {
+    let mut networks: Vec<((IpAddr, u8), Via)> = Vec::new();
+    if rules.tunnel_by_default() {
+        // Two half-size routes per family take precedence over the default
+        // route without replacing it.
+        networks.push(((Ipv4Addr::UNSPECIFIED.into(), 1), Via::Tunnel));
+        networks.push(((Ipv4Addr::new(128, 0, 0, 0).into(), 1), Via::Tunnel));
+        networks.push(((Ipv6Addr::UNSPECIFIED.into(), 1), Via::Tunnel));
+        networks.push((
+            (Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0).into(), 1),
+            Via::Tunnel,
+        ));
+    }
+    networks.extend(rules.pushed.iter().map(|&net| (net, Via::Tunnel)));
+    networks.extend(rules.include.iter().map(|&net| (net, Via::Tunnel)));
+    networks.extend(rules.exclude.iter().map(|&net| (net, Via::Bypass)));
+    for (&addr, learned) in learned {
+        if let Some(via) = rules.domain_via(&learned.name) {
+            let host = Route::host(addr);
+            networks.push(((host.destination, host.prefix_len), via));
+        }
+    }
+
+    let mut routes: Vec<Route> = Vec::new();
+    for (network, via) in networks {
+        let Some(route) = route_for(network, via, tun_name, ipv6, bypass) else {
+            continue;
+        };
+        // A later rule for the same network replaces an earlier one, so
+        // exclusions win over inclusions.
+        routes.retain(|existing| !same_destination(existing, &route));
+        routes.push(route);
+    }
+    routes
+}
+
+/// The route sending a network's traffic `via` the tunnel or the bypass
+/// route of its family, if there is one.
+fn route_for(
+    (destination, prefix_len): (IpAddr, u8),
+    via: Via,
+    tun_name: &str,
+    ipv6: bool,
+    bypass: &[Option<Route>; 2],
+) -> Option<Route> {
+    let family = Family::of(destination);
+    match via {
+        Via::Tunnel if family == Family::Ipv6 && !ipv6 => None,
+        Via::Tunnel => Some(Route::new(destination, prefix_len).dev(tun_name)),
+        Via::Bypass => {
+            let index = if family == Family::Ipv4 { 0 } else { 1 };
+            let default = bypass[index].as_ref()?;
+            let mut route = Route::new(destination, prefix_len).via(default.gateway);
+            route.dev = default.dev.clone();
+            Some(route)
+        }
+    }
+}
+
+fn same_destination(a: &Route, b: &Route) -> bool {
+    a.destination == b.destination && a.prefix_len == b.prefix_len && a.table == b.table
+}
+
+/// The installed routes no longer wanted, and the wanted routes missing.
+fn diff(installed: &[Route], wanted: &[Route]) -> (Vec<Route>, Vec<Route>) {
+    let stale = installed
+        .iter()
+        .filter(|route| !wanted.contains(route))
+        .cloned()
+        .collect();
+    let new = wanted
+        .iter()
+        .filter(|route| !installed.contains(route))
+        .cloned()
+        .collect();
+    (stale, new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn rules(config: SplitTunnelConfig) -> Rules {
+        Rules::from_config(&config, &[]).unwrap()
+    }
+
+    fn learned(names: &[(&str, &str)]) -> BTreeMap<IpAddr, Learned> {
+        let expires = Instant::now() + MIN_LEARNED_TTL;
+        names
+            .iter()
+            .map(|&(addr, name)| {
+                let name = name.to_string();
+                (ip(addr), Learned { name, expires })
+            })
+            .collect()
+    }
+
+    fn bypass() -> [Option<Route>; 2] {
+        [
+            Some(
+                Route::default_for(Family::Ipv4)
+                    .via(Some(ip("192.168.1.1")))
+                    .dev("eth0"),
+            ),
+            None,
+        ]
+    }
+
+    #[test]
+    fn domain_rules_match_subdomains_and_exclusions_win() {
+        let rules = rules(SplitTunnelConfig {
+            include_domains: vec!["Example.com.".to_string(), "*.corp".to_string()],
+            exclude_domains: vec!["video.example.com".to_string()],
+            ..SplitTunnelConfig::default()
+        });
+        assert_eq!(rules.domain_via("example.com"), Some(Via::Tunnel));
+        assert_eq!(rules.domain_via("www.EXAMPLE.com."), Some(Via::Tunnel));
+        assert_eq!(rules.domain_via("cdn.video.example.com"), Some(Via::Bypass));
+        assert_eq!(rules.domain_via("intranet.corp"), Some(Via::Tunnel));
+        assert_eq!(rules.domain_via("notexample.com"), None);
+        assert!(!rules.tunnel_by_default());
+    }
+
+    #[test]
+    fn everything_but_exclusions_goes_through_the_tunnel_by_default() {
+        let rules = rules(SplitTunnelConfig {
+            exclude: vec!["192.168.0.0/16".to_string(), "fd00::/8".to_string()],
+            ..SplitTunnelConfig::default()
+        });
+        let routes = plan(&rules, &BTreeMap::new(), "onebox0", false, &bypass());
+        let shown: Vec<String> = routes.iter().map(ToString::to_string).collect();
+        // No IPv6 through the tunnel, and no IPv6 route to bypass with.
+        assert_eq!(
+            shown,
+            [
+                "0.0.0.0/1 dev onebox0",
+                "128.0.0.0/1 dev onebox0",
+                "192.168.0.0/16 via 192.168.1.1 dev eth0",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_included_and_learned_networks_go_through_the_tunnel() {
+        let rules = rules(SplitTunnelConfig {
+            include: vec!["10.20.0.0/16".to_string()],
+            exclude: vec!["10.20.30.0/24".to_string()],
+            include_domains: vec!["example.com".to_string()],
+            ..SplitTunnelConfig::default()
+        });
+        let learned = learned(&[
+            ("93.184.216.34", "example.com"),
+            ("2606:2800::1", "www.example.com"),
+            ("198.51.100.1", "other.org"),
+        ]);
+        let routes = plan(&rules, &learned, "onebox0", true, &bypass());
+        let shown: Vec<String> = routes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            shown,
+            [
+                "10.20.0.0/16 dev onebox0",
+                "10.20.30.0/24 via 192.168.1.1 dev eth0",
+                "93.184.216.34/32 dev onebox0",
+                "2606:2800::1/128 dev onebox0",
+            ]
+        );
+    }
+
+    #[test]
+    fn pushed_networks_go_through_the_tunnel_but_exclusions_win() {
+        let config = SplitTunnelConfig {
+            exclude: vec!["172.16.5.0/24".to_string()],
+            ..SplitTunnelConfig::default()
+        };
+        let rules = Rules::from_config(&config, &[(ip("172.16.0.0"), 12)]).unwrap();
+        assert!(rules.tunnel_by_default());
+        let routes = plan(&rules, &BTreeMap::new(), "onebox0", false, &bypass());
+        let shown: Vec<String> = routes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            shown[2..],
+            [
+                "172.16.0.0/12 dev onebox0",
+                "172.16.5.0/24 via 192.168.1.1 dev eth0",
+            ]
+        );
+    }
+
+    #[test]
+    fn learned_addresses_expire_and_are_capped() {
+        let now = Instant::now();
+        let mut learned = BTreeMap::new();
+        let (added, dropped) = record(
+            &mut learned,
+            "Example.com.",
+            &[(ip("192.0.2.1"), 60), (ip("192.0.2.2"), 7200)],
+            now,
+            2,
+        );
+        assert_eq!(added, [ip("192.0.2.1"), ip("192.0.2.2")]);
+        assert!(dropped.is_empty());
+        assert_eq!(learned[&ip("192.0.2.1")].name, "example.com");
+        // Short TTLs are stretched.
+        assert_eq!(learned[&ip("192.0.2.1")].expires, now + MIN_LEARNED_TTL);
+
+        // Seen again, an address needs no new route.
+        let (added, _) = record(
+            &mut learned,
+            "example.com",
+            &[(ip("192.0.2.2"), 7200)],
+            now,
+            2,
+        );
+        assert!(added.is_empty());
+
+        // Over the cap, the address expiring soonest goes.
+        let (added, dropped) = record(
+            &mut learned,
+            "example.net",
+            &[(ip("192.0.2.3"), 3600)],
+            now,
+            2,
+        );
+        assert_eq!(added, [ip("192.0.2.3")]);
+        assert_eq!(dropped, [ip("192.0.2.1")]);
+
+        // Once expired, addresses go at the next answer.
+        let later = now + Duration::from_secs(3601);
+        let (added, dropped) = record(&mut learned, "example.org", &[], later, 2);
+        assert!(added.is_empty());
+        assert_eq!(dropped, [ip("192.0.2.3")]);
+        assert_eq!(learned.keys().collect::<Vec<_>>(), [&ip("192.0.2.2")]);
+    }
+
+    #[test]
+    fn changed_routes_are_replaced() {
+        let tunnel = Route::new(ip("10.20.0.0"), 16).dev("onebox0");
+        let old = Route::new(ip("192.168.0.0"), 16)
+            .via(Some(ip("192.168.1.1")))
+            .dev("eth0");
+        let new = Route::new(ip("192.168.0.0"), 16)
+            .via(Some(ip("10.0.0.1")))
+            .dev("wwan0");
+        let (stale, added) = diff(&[tunnel.clone(), old.clone()], &[tunnel, new.clone()]);
+        assert_eq!(stale, [old]);
+        assert_eq!(added, [new]);
+    }
+}
@@ -0,0 +1,424 @@
+//! Persistent data usage accounting for metered links.
+
+use onebox_core::types::LinkConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of bytes in a megabyte, as used by carriers for data caps.
+pub const BYTES_PER_MB: u64 = 1_000_000;
+
+/// IPv4 and UDP header bytes that the carrier counts on top of each datagram.
+const PACKET_OVERHEAD: u64 = 28;
+
+/// Where a link stands relative to its configured data caps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CapState {
+    /// Below every soft cap, or the link is not metered.
+    Normal,
+    /// Past the soft cap of at least one limit; the link is deprioritised.
+    Soft,
+    /// Past a hard cap; the link must not carry data.
+    Hard,
+}
+
+/// The data caps of a single link, derived from its `LinkConfig`.
+#[derive(Debug, Clone, Copy)]
+pub struct CapLimits {
+    /// Bytes allowed per billing cycle.
+    pub monthly_bytes: Option<u64>,
+    /// Bytes allowed per day.
+    pub daily_bytes: Option<u64>,
+    /// Day of the month on which the billing cycle resets.
+    pub billing_cycle_day: u8,
+    /// Percentage of a cap at which the soft cap kicks in.
+    pub soft_cap_percent: u8,
+}
+
+impl From<&LinkConfig> for CapLimits {
+    fn from(config: &LinkConfig) -> Self {
+        Self {
+            monthly_bytes: config
+                .monthly_cap_mb
+                .map(|mb| mb.saturating_mul(BYTES_PER_MB)),
+            daily_bytes: config
+                .daily_cap_mb
+                .map(|mb| mb.saturating_mul(BYTES_PER_MB)),
+            billing_cycle_day: config.billing_cycle_day,
+            soft_cap_percent: config.soft_cap_percent,
+        }
+    }
+}
+
+impl Default for CapLimits {
+    fn default() -> Self {
+        Self::from(&LinkConfig::default())
+    }
+}
+
+/// Usage counters for a single link, as persisted to disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkUsage {
+    /// Bytes sent and received since the start of the current billing cycle.
+    pub cycle_bytes: u64,
+    /// First day of the current billing cycle, in days since the Unix epoch.
+    pub cycle_start: i64,
+    /// Bytes sent and received on `day`.
+    pub day_bytes: u64,
+    /// The day `day_bytes` refers to, in days since the Unix epoch.
+    pub day: i64,
+    /// Bytes sent and received over the lifetime of the link.
+    pub total_bytes: u64,
+}
+
+impl LinkUsage {
+    /// Adds `bytes` of traffic seen on `today`.
+    pub fn record(&mut self, bytes: u64, today: i64, limits: &CapLimits) {
+        self.roll_over(today, limits);
+        self.cycle_bytes += bytes;
+        self.day_bytes += bytes;
+        self.total_bytes += bytes;
+    }
+
+    /// Resets the counters of any period that ended before `today`.
+    pub fn roll_over(&mut self, today: i64, limits: &CapLimits) {
+        let cycle_start = billing_cycle_start(today, limits.billing_cycle_day);
+        if cycle_start != self.cycle_start {
+            self.cycle_start = cycle_start;
+            self.cycle_bytes = 0;
+        }
+        if today != self.day {
+            self.day = today;
+            self.day_bytes = 0;
+        }
+    }
+
+    /// Compares the current usage against the configured caps.
+    pub fn cap_state(&self, limits: &CapLimits) -> CapState {
+        let check = |used: u64, cap: Option<u64>| match cap {
+            Some(cap) if used >= cap => CapState::Hard,
+            Some(cap)
+                if u128::from(used) * 100
+                    >= u128::from(cap) * u128::from(limits.soft_cap_percent) =>
+            {
+                CapState::Soft
+            }
+            _ => CapState::Normal,
+        };
+        check(self.cycle_bytes, limits.monthly_bytes).max(check(self.day_bytes, limits.daily_bytes))
+    }
+
+    /// Bytes left before the tightest cap is reached, or `None` if unmetered.
+    pub fn remaining(&self, limits: &CapLimits) -> Option<u64> {
+        let monthly = limits
+            .monthly_bytes
+            .map(|cap| cap.saturating_sub(self.cycle_bytes));
+        let daily = limits
+            .daily_bytes
+            .map(|cap| cap.saturating_sub(self.day_bytes));
+        monthly.into_iter().chain(daily).min()
+    }
+}
+
+/// Counts traffic per link and persists the totals across restarts.
+///
+/// The data path only bumps an atomic counter per packet; `flush` folds those
+/// counters into the persisted `LinkUsage` and is meant to run periodically.
+pub struct UsageTracker {
+    path: PathBuf,
+    pending: RwLock<HashMap<String, Arc<AtomicU64>>>,
+    limits: RwLock<HashMap<String, CapLimits>>,
+    usage: Mutex<HashMap<String, LinkUsage>>,
+}
+
+impl UsageTracker {
+    /// Creates a tracker with no recorded usage that saves to `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            pending: RwLock::new(HashMap::new()),
+            limits: RwLock::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Loads previously saved usage from `path`, if the file exists.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let tracker = Self::new(path);
+        match std::fs::read_to_string(&tracker.path) {
+            Ok(content) => {
+                *tracker.usage.lock().unwrap() = serde_json::from_str(&content)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(tracker)
+    }
+
+    /// Starts accounting for a link with the given caps.
+    pub fn register(&self, name: &str, limits: CapLimits) {
+        self.pending
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default();
+        self.limits
+            .write()
+            .unwrap()
+            .insert(name.to_string(), limits);
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default();
+    }
+
+    /// Stops accounting for a link, folding in what it used so far. Its
+    /// totals are kept, and saved, for when it comes back.
+    pub fn unregister(&self, name: &str, today: i64) {
+        let counter = self.pending.write().unwrap().remove(name);
+        let limits = self.limits.write().unwrap().remove(name);
+        if let Some(counter) = counter {
+            let limits = limits.unwrap_or_default();
+            if let Some(link_usage) = self.usage.lock().unwrap().get_mut(name) {
+                link_usage.record(counter.load(Ordering::Relaxed), today, &limits);
+            }
+        }
+    }
+
+    /// Records a datagram of `len` bytes sent or received on a link.
+    pub fn record(&self, name: &str, len: usize) {
+        if let Some(counter) = self.pending.read().unwrap().get(name) {
+            counter.fetch_add(len as u64 + PACKET_OVERHEAD, Ordering::Relaxed);
+        }
+    }
+
+    /// Folds the pending counters into the usage totals and returns the
+    /// resulting cap state of every link.
+    pub fn flush(&self, today: i64) -> HashMap<String, CapState> {
+        let pending = self.pending.read().unwrap();
+        let limits = self.limits.read().unwrap();
+        let mut usage = self.usage.lock().unwrap();
+        let mut states = HashMap::new();
+        for (name, counter) in pending.iter() {
+            let limits = limits.get(name).copied().unwrap_or_default();
+            let link_usage = usage.entry(name.clone()).or_default();
+            link_usage.record(counter.swap(0, Ordering::Relaxed), today, &limits);
+            states.insert(name.clone(), link_usage.cap_state(&limits));
+        }
+        states
+    }
+
+    /// Returns the usage of a link along with its caps.
+    pub fn snapshot(&self, name: &str) -> Option<(LinkUsage, CapLimits)> {
+        let limits = self.limits.read().unwrap().get(name).copied()?;
+        let usage = self.usage.lock().unwrap().get(name).cloned()?;
+        Some((usage, limits))
+    }
+
+    /// Writes the usage totals to disk, replacing the previous file atomically.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let content = serde_json::to_string_pretty(&*self.usage.lock().unwrap())?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Returns the current UTC date as days since the Unix epoch.
+pub fn today() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+        / 86_400
+}
+
+/// Returns the first day of the billing cycle that contains `today`.
+///
+/// A `billing_day` past the end of a short month resets on that month's last day.
+pub fn billing_cycle_start(today: i64, billing_day: u8) -> i64 {
+    let billing_day = billing_day.clamp(1, 31) as u32;
+    let (year, month, day) = civil_from_days(today);
+    let this_month = billing_day.min(days_in_month(year, month));
+    if day >= this_month {
+        days_from_civil(year, month, this_month)
+    } else {
+        let (year, month) = if month == 1 {
+            (year - 1, 12)
+        } else {
+            (year, month - 1)
+        };
+        days_from_civil(year, month, billing_day.min(days_in_month(year, month)))
+    }
+}
+
+/// Converts days since the Unix epoch into a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a proleptic Gregorian date into days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = BYTES_PER_MB;
+
+    fn limits(monthly_mb: Option<u64>, daily_mb: Option<u64>) -> CapLimits {
+        CapLimits {
+            monthly_bytes: monthly_mb.map(|mb| mb * MB),
+            daily_bytes: daily_mb.map(|mb| mb * MB),
+            ..CapLimits::default()
+        }
+    }
+
+    #[test]
+    fn civil_date_roundtrip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        for days in [-1, 0, 59, 365, 11_016, 19_782, 30_000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn billing_cycle_starts_on_configured_day() {
+        let march_10 = days_from_civil(2024, 3, 10);
+        assert_eq!(
+            billing_cycle_start(march_10, 15),
+            days_from_civil(2024, 2, 15)
+        );
+        assert_eq!(
+            billing_cycle_start(march_10, 1),
+            days_from_civil(2024, 3, 1)
+        );
+        let jan_3 = days_from_civil(2024, 1, 3);
+        assert_eq!(billing_cycle_start(jan_3, 5), days_from_civil(2023, 12, 5));
+    }
+
+    #[test]
+    fn billing_day_is_clamped_to_short_months() {
+        let feb_29 = days_from_civil(2024, 2, 29);
+        assert_eq!(billing_cycle_start(feb_29, 31), feb_29);
+        let feb_28 = days_from_civil(2024, 2, 28);
+        assert_eq!(
+            billing_cycle_start(feb_28, 31),
+            days_from_civil(2024, 1, 31)
+        );
+    }
+
+    #[test]
+    fn counters_reset_at_period_boundaries() {
+        let limits = limits(Some(100), Some(10));
+        let day = days_from_civil(2024, 3, 31);
+        let mut usage = LinkUsage::default();
+        usage.record(5 * MB, day, &limits);
+        usage.record(3 * MB, day, &limits);
+        assert_eq!(usage.day_bytes, 8 * MB);
+        assert_eq!(usage.cycle_bytes, 8 * MB);
+
+        usage.record(MB, day + 1, &limits);
+        assert_eq!(usage.day_bytes, MB);
+        assert_eq!(usage.cycle_bytes, MB);
+        assert_eq!(usage.total_bytes, 9 * MB);
+    }
+
+    #[test]
+    fn cap_state_follows_soft_and_hard_caps() {
+        let limits = limits(Some(100), None);
+        let mut usage = LinkUsage::default();
+        assert_eq!(usage.cap_state(&limits), CapState::Normal);
+        usage.cycle_bytes = 80 * MB;
+        assert_eq!(usage.cap_state(&limits), CapState::Soft);
+        usage.cycle_bytes = 100 * MB;
+        assert_eq!(usage.cap_state(&limits), CapState::Hard);
+        assert_eq!(usage.remaining(&limits), Some(0));
+    }
+
+    #[test]
+    fn tightest_cap_wins() {
+        let limits = limits(Some(100), Some(10));
+        let usage = LinkUsage {
+            cycle_bytes: 20 * MB,
+            day_bytes: 10 * MB,
+            ..LinkUsage::default()
+        };
+        assert_eq!(usage.cap_state(&limits), CapState::Hard);
+        assert_eq!(usage.remaining(&limits), Some(0));
+        assert_eq!(LinkUsage::default().remaining(&CapLimits::default()), None);
+    }
+
+    #[test]
+    fn huge_caps_saturate() {
+        let config = LinkConfig {
+            monthly_cap_mb: Some(u64::MAX),
+            ..LinkConfig::default()
+        };
+        let limits = CapLimits::from(&config);
+        assert_eq!(limits.monthly_bytes, Some(u64::MAX));
+        let usage = LinkUsage {
+            cycle_bytes: u64::MAX / 2,
+            ..LinkUsage::default()
+        };
+        assert_eq!(usage.cap_state(&limits), CapState::Normal);
+    }
+
+    #[test]
+    fn usage_persists_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("usage.json");
+        let limits = limits(Some(100), None);
+
+        let tracker = UsageTracker::load(&path).unwrap();
+        tracker.register("wwan0", limits);
+        tracker.record("wwan0", 1000);
+        tracker.record("unknown0", 1000);
+        let states = tracker.flush(days_from_civil(2024, 3, 10));
+        assert_eq!(states.get("wwan0"), Some(&CapState::Normal));
+        tracker.save().unwrap();
+
+        let reloaded = UsageTracker::load(&path).unwrap();
+        reloaded.register("wwan0", limits);
+        let (usage, _) = reloaded.snapshot("wwan0").unwrap();
+        assert_eq!(usage.total_bytes, 1000 + PACKET_OVERHEAD);
+        assert!(reloaded.snapshot("unknown0").is_none());
+    }
+}
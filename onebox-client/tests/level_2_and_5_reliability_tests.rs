@@ -79,6 +79,7 @@ fn test_hard_link_failure() {
     assert!(re_down.is_match(&status_output), "Client status does not show wan1 as Down");
 
     ping_process.kill().expect("Failed to kill ping process");
+    let _ = ping_process.wait();
     println!("--- Hard Link Failure Test Successful ---");
 }
 
@@ -141,5 +142,6 @@ fn test_flapping_link_resilience() {
     }
 
     ping_process.kill().expect("Failed to kill ping process");
+    let _ = ping_process.wait();
     println!("--- Flapping Link Resilience Test Successful ---");
 }
@@ -20,7 +20,7 @@ fn test_bandwidth_aggregation() {
 
     // This test requires iperf3 to be installed on the host and in the namespaces.
     // The setup script should handle this. We also need an iperf3 server in the 'internet_endpoint' ns.
-    let _iperf_server = Command::new("sudo")
+    let mut iperf_server = Command::new("sudo")
         .arg("ip")
         .arg("netns")
         .arg("exec")
@@ -83,4 +83,7 @@ fn test_bandwidth_aggregation() {
 
     println!("--- Bandwidth aggregation test successful ---");
     */
+
+    // The launcher exits once iperf3 has daemonized.
+    let _ = iperf_server.wait();
 }
@@ -0,0 +1,184 @@
+//! Link capacity estimation via packet trains.
+//!
+//! The sender transmits a short train of equally sized packets back to back.
+//! The receiver notes when each one arrives; the rate at which the train
+//! drains through the bottleneck link approximates that link's capacity.
+
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Number of packets in a bandwidth probe train.
+pub const TRAIN_LENGTH: u16 = 16;
+
+/// Size in bytes of the (padded) plaintext payload of each train packet.
+pub const TRAIN_PACKET_SIZE: usize = 1200;
+
+/// Payload of a `PacketType::BandwidthProbe` packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthProbe {
+    /// Identifies the train this packet belongs to.
+    pub train_id: u32,
+    /// Position of this packet within the train.
+    pub index: u16,
+    /// Total number of packets in the train.
+    pub count: u16,
+}
+
+/// Payload of a `PacketType::BandwidthReport` packet, sent by the receiver
+/// of a train back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthReport {
+    /// The train this report is about.
+    pub train_id: u32,
+    /// How many packets of the train arrived.
+    pub received: u16,
+    /// Measured arrival rate in bytes per second, if it could be computed.
+    pub bytes_per_sec: Option<f64>,
+}
+
+/// Returns the sequence number (and thus nonce) of a train packet.
+///
+/// Train packets use their own sequence space, split by direction, so that
+/// they never reuse a nonce of regular traffic or of the opposite direction.
+pub fn train_sequence(train_id: u32, index: u16, from_server: bool) -> u64 {
+    let direction = if from_server { 1 << 62 } else { 0 };
+    (1 << 63) | direction | ((train_id as u64) << 16) | index as u64
+}
+
+/// Builds the padded plaintext payload of a train packet.
+pub fn train_payload(probe: &BandwidthProbe) -> Vec<u8> {
+    let mut payload = bincode::serialize(probe).expect("BandwidthProbe always serializes");
+    payload.resize(TRAIN_PACKET_SIZE, 0);
+    payload
+}
+
+/// Collects the arrival times of a train's packets.
+#[derive(Debug, Clone)]
+pub struct TrainArrivals {
+    /// Total number of packets in the train.
+    pub count: u16,
+    /// How many packets have arrived so far.
+    pub received: u16,
+    first: Option<(Instant, usize)>,
+    last: Option<Instant>,
+    total_bytes: usize,
+}
+
+impl TrainArrivals {
+    /// Starts tracking a train of `count` packets.
+    pub fn new(count: u16) -> Self {
+        Self {
+            count,
+            received: 0,
+            first: None,
+            last: None,
+            total_bytes: 0,
+        }
+    }
+
+    /// Records the arrival of a packet of `len` bytes at `at`.
+    pub fn record(&mut self, len: usize, at: Instant) {
+        self.received = self.received.saturating_add(1);
+        self.total_bytes += len;
+        match self.first {
+            // A packet that was processed late but arrived earlier.
+            Some((first, _)) if at < first => {
+                self.last = Some(self.last.map_or(first, |last| last.max(first)));
+                self.first = Some((at, len));
+            }
+            Some(_) => self.last = Some(self.last.map_or(at, |last| last.max(at))),
+            None => self.first = Some((at, len)),
+        }
+    }
+
+    /// Whether every packet of the train has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.received >= self.count
+    }
+
+    /// The arrival rate in bytes per second. The first packet only marks the
+    /// start of the train, so it is not counted towards the transferred bytes.
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        let (first, first_len) = self.first?;
+        let elapsed = self.last?.duration_since(first).as_secs_f64();
+        if elapsed > 0.0 {
+            Some((self.total_bytes - first_len) as f64 / elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// When the first packet of the train arrived.
+    pub fn started_at(&self) -> Option<Instant> {
+        self.first.map(|(at, _)| at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn train_sequences_do_not_collide() {
+        let up = train_sequence(7, 3, false);
+        let down = train_sequence(7, 3, true);
+        assert_ne!(up, down);
+        assert_ne!(train_sequence(7, 4, false), up);
+        assert_ne!(train_sequence(8, 3, false), up);
+        assert!(up >= 1 << 63);
+    }
+
+    #[test]
+    fn train_payload_is_padded_and_decodable() {
+        let probe = BandwidthProbe {
+            train_id: 9,
+            index: 2,
+            count: TRAIN_LENGTH,
+        };
+        let payload = train_payload(&probe);
+        assert_eq!(payload.len(), TRAIN_PACKET_SIZE);
+        let decoded: BandwidthProbe = bincode::deserialize(&payload).unwrap();
+        assert_eq!(decoded, probe);
+    }
+
+    #[test]
+    fn arrival_rate_ignores_first_packet() {
+        let start = Instant::now();
+        let mut train = TrainArrivals::new(3);
+        train.record(1000, start);
+        assert!(train.bytes_per_sec().is_none());
+        train.record(1000, start + Duration::from_millis(1));
+        train.record(1000, start + Duration::from_millis(2));
+        assert!(train.is_complete());
+        let rate = train.bytes_per_sec().unwrap();
+        assert!((rate - 1_000_000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn out_of_order_processing_uses_earliest_arrival() {
+        let start = Instant::now();
+        let mut train = TrainArrivals::new(3);
+        train.record(500, start + Duration::from_millis(1));
+        train.record(500, start);
+        train.record(500, start + Duration::from_millis(2));
+        assert_eq!(train.started_at(), Some(start));
+        let rate = train.bytes_per_sec().unwrap();
+        assert!((rate - 500_000.0).abs() < 1.0);
+
+        let mut pair = TrainArrivals::new(2);
+        pair.record(500, start + Duration::from_millis(1));
+        pair.record(500, start);
+        let rate = pair.bytes_per_sec().unwrap();
+        assert!((rate - 500_000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn simultaneous_arrivals_give_no_estimate() {
+        let start = Instant::now();
+        let mut train = TrainArrivals::new(2);
+        train.record(1000, start);
+        train.record(1000, start);
+        assert!(train.bytes_per_sec().is_none());
+    }
+}
@@ -4,7 +4,10 @@
 //! file, adhering to the specification in `docs/SRS.md (SI-2)`.
 
 use crate::error::{OneboxError, OneboxResult};
+use crate::netconfig::network;
+use crate::types::{glob_match, ClientId, LinkConfig};
 use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 
 /// Represents the entire configuration loaded from `config.toml`.
@@ -22,11 +25,448 @@ pub struct Config {
 /// Contains client-specific configuration.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClientConfig {
+    /// The server's IPv4 or IPv6 address, or a host name whose A and AAAA
+    /// records are all used.
     pub server_address: String,
     pub server_port: u16,
+    /// A second server address, usually IPv6 next to an IPv4
+    /// `server_address`, for links that only have the other family.
+    #[serde(default)]
+    pub server_address_v6: Option<String>,
+    /// The ID the client authenticates with. The server tells clients apart,
+    /// and matches `[[server.reservations]]`, by it.
+    #[serde(default = "default_client_id")]
+    pub client_id: u64,
     pub tun_name: String,
+    /// Address of the TUN device, used unless the server pushes one.
+    #[serde(default = "default_client_tun_ip")]
     pub tun_ip: String,
+    #[serde(default = "default_tun_netmask")]
     pub tun_netmask: String,
+    /// MTU of the TUN device, used unless the server pushes one. The
+    /// system's default when unset.
+    #[serde(default)]
+    pub tun_mtu: Option<u16>,
+    /// Whether the tunnel address, MTU, routes and DNS servers the server
+    /// pushes in its handshake replace the configured ones.
+    #[serde(default = "default_use_pushed_settings")]
+    pub use_pushed_settings: bool,
+    /// How pushed DNS servers are applied.
+    #[serde(default)]
+    pub dns_mode: DnsMode,
+    /// IPv6 address of the TUN device. When set, IPv6 traffic is routed
+    /// through the tunnel too.
+    #[serde(default)]
+    pub tun_ipv6: Option<String>,
+    /// Prefix length of `tun_ipv6`.
+    #[serde(default = "default_tun_ipv6_prefix_len")]
+    pub tun_ipv6_prefix_len: u8,
+    /// Whether traffic moves back to a higher-priority link once it recovers.
+    #[serde(default = "default_failback")]
+    pub failback: bool,
+    /// How long a recovered higher-priority link must stay up before failback.
+    #[serde(default = "default_failback_delay_secs")]
+    pub failback_delay_secs: u64,
+    /// Standby (backup) links are probed this many times less often than active ones.
+    #[serde(default = "default_backup_probe_divisor")]
+    pub backup_probe_divisor: u32,
+    /// Which interfaces become links when not listed in `links`.
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    /// Per-link settings, matched by interface name or glob.
+    #[serde(default)]
+    pub links: Vec<LinkConfig>,
+    /// Which traffic goes through the tunnel and which bypasses it.
+    #[serde(default)]
+    pub split_tunnel: SplitTunnelConfig,
+    /// Where per-link data usage is persisted across restarts.
+    #[serde(default = "default_usage_file")]
+    pub usage_file: String,
+    /// Where the routes and rules the client has installed are recorded, so
+    /// that a run after a crash can remove them.
+    #[serde(default = "default_route_journal")]
+    pub route_journal: String,
+    /// Whether sends are paced per link by the congestion controller.
+    #[serde(default = "default_congestion_control")]
+    pub congestion_control: bool,
+    /// Queueing delay above a link's minimum RTT at which it is slowed down.
+    #[serde(default = "default_queue_delay_target_ms")]
+    pub queue_delay_target_ms: u64,
+    /// How often each link's capacity is measured with a probe train (0 disables).
+    #[serde(default = "default_bandwidth_probe_interval_secs")]
+    pub bandwidth_probe_interval_secs: u64,
+    /// How each link's socket is tied to its interface.
+    #[serde(default)]
+    pub link_binding: LinkBinding,
+    /// First routing table (and rule priority) used for per-link policy
+    /// routing; each link takes the next free one.
+    #[serde(default = "default_routing_table_base")]
+    pub routing_table_base: u32,
+    /// Thresholds for link health states.
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Commands run when a link changes status.
+    #[serde(default)]
+    pub hooks: HooksConfig,
+}
+
+/// Thresholds that drive link health states, set in `[client.health]`, and
+/// in `[server.health]` for the server's view of client links.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// How often each link is probed.
+    pub probe_interval_ms: u64,
+    /// How long a probe may go unanswered before it counts as lost.
+    pub probe_timeout_ms: u64,
+    /// Whether the probe interval adapts to the link: it backs off towards
+    /// `max_probe_interval_ms` while a healthy link is idle and drops to
+    /// `min_probe_interval_ms` as soon as probes are lost.
+    pub adaptive_probing: bool,
+    /// Shortest probe interval in adaptive mode.
+    pub min_probe_interval_ms: u64,
+    /// Longest probe interval in adaptive mode.
+    pub max_probe_interval_ms: u64,
+    /// Consecutive lost probes after which a link is Down.
+    pub down_after_failures: u32,
+    /// Smoothed RTT above which a link is Degraded.
+    pub degraded_rtt_ms: u64,
+    /// RTT jitter above which a link is Degraded.
+    pub degraded_jitter_ms: u64,
+    /// Windowed probe loss above which a link is Degraded, in percent.
+    pub degraded_loss_percent: f32,
+    /// Quality score, an estimated MOS from 1.0 to 4.5, below which a link
+    /// is Degraded. 0 disables the threshold.
+    pub degraded_quality: f32,
+    /// Consecutive good probes a Degraded or Down link needs to recover.
+    pub recovery_probes: u32,
+    /// How long a link stays Degraded or Down at least, before it may recover.
+    pub recovery_hold_secs: u64,
+    /// Share of its normal scheduling weight a Degraded link keeps, in percent.
+    pub degraded_weight_percent: u8,
+    /// Whether data traffic is watched for signs of a failing link: data the
+    /// server doesn't acknowledge in time, and errors sending on the socket.
+    pub passive_monitoring: bool,
+    /// The least time data may go unacknowledged before its link is Down.
+    /// The link's RTT timeout is used when longer.
+    pub passive_timeout_ms: u64,
+    /// Whether probes are skipped on Up links while the server acknowledges
+    /// their data. Saves data, but RTT and jitter are then only measured
+    /// while the link is idle.
+    pub suppress_probes: bool,
+    /// How often the extra health checks of a link are run.
+    pub check_interval_ms: u64,
+    /// How long a single health check may take before it fails.
+    pub check_timeout_ms: u64,
+}
+
+/// How interfaces are picked as links, set in `[client.discovery]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Whether interfaces are discovered or only taken from `[[client.links]]`.
+    pub mode: DiscoveryMode,
+    /// Globs of interface names that may become links in auto mode.
+    pub include: Vec<String>,
+    /// Globs of interface names that never become links in auto mode, unless
+    /// a `[[client.links]]` entry matches them.
+    pub exclude: Vec<String>,
+}
+
+/// How DNS servers pushed by the server are applied, set by `dns_mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsMode {
+    /// systemd-resolved when it is running, `/etc/resolv.conf` otherwise.
+    #[default]
+    Auto,
+    /// Per-link DNS on the TUN device through `resolvectl`.
+    Resolved,
+    /// `/etc/resolv.conf` is rewritten, and restored on exit.
+    File,
+    /// Pushed DNS servers are ignored.
+    Off,
+}
+
+/// How a link's socket is tied to its interface, set by `link_binding`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkBinding {
+    /// `SO_BINDTODEVICE`: sockets send out of the interface whatever the
+    /// routing table says. Needs CAP_NET_RAW.
+    #[default]
+    Device,
+    /// Sockets bind the link's source address, and an `ip rule` per link
+    /// sends that source to a table routing through the interface's gateway.
+    Policy,
+    /// Like `Policy`, but the rule matches a firewall mark the socket sets
+    /// with `SO_MARK`. Needs CAP_NET_ADMIN.
+    Mark,
+}
+
+/// Where the client's links come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    /// Every included interface with an address, plus those in `[[client.links]]`.
+    Auto,
+    /// Only the interfaces matched by `[[client.links]]` entries.
+    Explicit,
+}
+
+impl DiscoveryConfig {
+    /// Whether auto discovery picks up the named interface.
+    pub fn includes(&self, iface: &str) -> bool {
+        self.include.iter().any(|glob| glob_match(glob, iface))
+            && !self.exclude.iter().any(|glob| glob_match(glob, iface))
+    }
+}
+
+/// Split tunneling, set in `[client.split_tunnel]`. Networks are CIDRs such
+/// as `10.0.0.0/8`, or single addresses.
+///
+/// All traffic goes through the tunnel unless `include` or `include_domains`
+/// is set, in which case only what they match does. Whatever `exclude` or
+/// `exclude_domains` matches bypasses the tunnel, through `bypass_link`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SplitTunnelConfig {
+    /// Networks routed through the tunnel.
+    pub include: Vec<String>,
+    /// Networks routed around the tunnel.
+    pub exclude: Vec<String>,
+    /// Interface bypassing traffic leaves through. When unset, it takes the
+    /// default route the host had without the tunnel.
+    pub bypass_link: Option<String>,
+    /// Domains, with their subdomains, whose addresses are routed through
+    /// the tunnel once resolved through the DNS forwarder.
+    pub include_domains: Vec<String>,
+    /// Domains whose resolved addresses bypass the tunnel. These win over
+    /// `include_domains`.
+    pub exclude_domains: Vec<String>,
+    /// Address the DNS forwarder listens on. Domain rules only see names
+    /// resolved through it.
+    pub dns_listen: Option<String>,
+    /// Resolver the DNS forwarder passes queries on to.
+    pub dns_upstream: String,
+}
+
+impl Default for SplitTunnelConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            bypass_link: None,
+            include_domains: Vec::new(),
+            exclude_domains: Vec::new(),
+            dns_listen: None,
+            dns_upstream: "1.1.1.1:53".to_string(),
+        }
+    }
+}
+
+impl SplitTunnelConfig {
+    /// The networks in `include`.
+    pub fn include_networks(&self) -> OneboxResult<Vec<(IpAddr, u8)>> {
+        self.include.iter().map(|cidr| parse_cidr(cidr)).collect()
+    }
+
+    /// The networks in `exclude`.
+    pub fn exclude_networks(&self) -> OneboxResult<Vec<(IpAddr, u8)>> {
+        self.exclude.iter().map(|cidr| parse_cidr(cidr)).collect()
+    }
+
+    /// The DNS forwarder's listening and upstream addresses, if it is on.
+    pub fn dns_forwarder(&self) -> OneboxResult<Option<(SocketAddr, SocketAddr)>> {
+        let Some(listen) = &self.dns_listen else {
+            return Ok(None);
+        };
+        let parse = |addr: &str| {
+            addr.parse::<SocketAddr>().map_err(|e| {
+                OneboxError::Config(format!("Invalid DNS forwarder address '{addr}': {e}"))
+            })
+        };
+        Ok(Some((parse(listen)?, parse(&self.dns_upstream)?)))
+    }
+}
+
+/// Parses a CIDR such as `10.0.0.0/8`, or a single address, into its network
+/// and prefix length. Host bits are cleared.
+pub fn parse_cidr(cidr: &str) -> OneboxResult<(IpAddr, u8)> {
+    let invalid = || OneboxError::Config(format!("Invalid network '{cidr}'"));
+    let (addr, prefix_len) = match cidr.split_once('/') {
+        Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+        None => (cidr, None),
+    };
+    let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(len) => len.trim().parse().map_err(|_| invalid())?,
+        None => max_len,
+    };
+    if prefix_len > max_len {
+        return Err(invalid());
+    }
+    Ok((network(addr, prefix_len), prefix_len))
+}
+
+/// Hooks fired when a link changes status, set in `[client.hooks]`.
+///
+/// Commands are run with `/bin/sh -c` and get the link name, its old and new
+/// status, RTT and loss in `ONEBOX_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Command run when a link comes Up.
+    pub on_up: Option<String>,
+    /// Command run when a link becomes Degraded.
+    pub on_degraded: Option<String>,
+    /// Command run when a link goes Down.
+    pub on_down: Option<String>,
+    /// Command run on every status change, after the specific one.
+    pub on_change: Option<String>,
+    /// A FIFO (or file) that gets one line per status change. Lines are
+    /// dropped while nothing reads the FIFO.
+    pub fifo: Option<String>,
+    /// How long a command may run before it is killed.
+    pub timeout_secs: u64,
+}
+
+impl ClientConfig {
+    /// Returns the configuration for the named link, from the first
+    /// `[[client.links]]` entry that matches it, falling back to defaults.
+    pub fn link_config(&self, name: &str) -> LinkConfig {
+        let link = self
+            .links
+            .iter()
+            .find(|l| l.matches(name))
+            .cloned()
+            .unwrap_or_default();
+        LinkConfig {
+            name: name.to_string(),
+            ..link
+        }
+    }
+
+    /// The TUN device's IPv6 address and prefix length, if configured.
+    pub fn tun_ipv6(&self) -> OneboxResult<Option<(Ipv6Addr, u8)>> {
+        parse_tun_ipv6(self.tun_ipv6.as_deref(), self.tun_ipv6_prefix_len)
+    }
+
+    /// The first routing table and rule priority, checked to leave the
+    /// kernel's reserved tables and rules alone.
+    pub fn routing_table_base(&self) -> OneboxResult<u32> {
+        if !ROUTING_TABLE_BASES.contains(&self.routing_table_base) {
+            return Err(OneboxError::Config(format!(
+                "routing_table_base {} is outside {}..={}",
+                self.routing_table_base,
+                ROUTING_TABLE_BASES.start(),
+                ROUTING_TABLE_BASES.end()
+            )));
+        }
+        Ok(self.routing_table_base)
+    }
+
+    /// Resolves the server's addresses, IPv4 and IPv6, from
+    /// `server_address` and `server_address_v6`.
+    pub fn server_addrs(&self) -> OneboxResult<Vec<SocketAddr>> {
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        let hosts = std::iter::once(&self.server_address).chain(&self.server_address_v6);
+        for host in hosts {
+            for addr in resolve(host, self.server_port)? {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Chooses which of the given interfaces become links, and returns their
+    /// configuration in the same order. Interfaces a `[[client.links]]` entry
+    /// matches are always chosen, others only in auto mode if
+    /// `[client.discovery]` includes them. Disabled links and the client's
+    /// own TUN device are left out.
+    pub fn select_links<'a>(
+        &self,
+        interfaces: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<LinkConfig> {
+        interfaces
+            .into_iter()
+            .filter(|&iface| iface != self.tun_name)
+            .filter(|&iface| {
+                self.links.iter().any(|l| l.matches(iface))
+                    || (self.discovery.mode == DiscoveryMode::Auto
+                        && self.discovery.includes(iface))
+            })
+            .map(|iface| self.link_config(iface))
+            .filter(|link| link.enabled)
+            .collect()
+    }
+
+    /// Returns the health settings for the named link: the `[client.health]`
+    /// settings with the link's own overrides applied.
+    pub fn link_health(&self, name: &str) -> HealthConfig {
+        let link = self.link_config(name);
+        let mut health = self.health.clone();
+        if let Some(interval) = link.probe_interval_ms {
+            health.probe_interval_ms = interval;
+        }
+        if let Some(timeout) = link.probe_timeout_ms {
+            health.probe_timeout_ms = timeout;
+        }
+        if let Some(failures) = link.down_after_failures {
+            health.down_after_failures = failures;
+        }
+        if let Some(adaptive) = link.adaptive_probing {
+            health.adaptive_probing = adaptive;
+        }
+        health
+    }
+}
+
+fn default_failback() -> bool {
+    true
+}
+
+fn default_failback_delay_secs() -> u64 {
+    10
+}
+
+fn default_backup_probe_divisor() -> u32 {
+    4
+}
+
+fn default_usage_file() -> String {
+    "/var/lib/onebox/usage.json".to_string()
+}
+
+fn default_route_journal() -> String {
+    "/var/lib/onebox/routes.json".to_string()
+}
+
+fn default_congestion_control() -> bool {
+    true
+}
+
+fn default_queue_delay_target_ms() -> u64 {
+    50
+}
+
+fn default_bandwidth_probe_interval_secs() -> u64 {
+    300
+}
+
+/// How many routing tables, and rule priorities, from `routing_table_base`
+/// the client may use.
+pub const ROUTING_TABLES: u32 = 64;
+
+/// The kernel's own tables go up to 255 (`default`, `main` and `local`), and
+/// its `main` and `default` rules sit at priorities 32766 and 32767.
+const ROUTING_TABLE_BASES: std::ops::RangeInclusive<u32> = 256..=32766 - ROUTING_TABLES;
+
+fn default_routing_table_base() -> u32 {
+    7000
 }
 
 /// Contains server-specific configuration.
@@ -34,6 +474,255 @@ pub struct ClientConfig {
 pub struct ServerConfig {
     pub listen_address: String,
     pub listen_port: u16,
+    /// Address of the server's TUN device. Clients get their addresses from
+    /// its network.
+    #[serde(default = "default_server_tun_ip")]
+    pub tun_ip: String,
+    /// Prefix length of the tunnel network.
+    #[serde(default = "default_tun_prefix_len")]
+    pub tun_prefix_len: u8,
+    /// MTU of the server's TUN device, pushed to clients.
+    #[serde(default = "default_mtu")]
+    pub mtu: u16,
+    /// DNS servers pushed to clients.
+    #[serde(default)]
+    pub dns_servers: Vec<String>,
+    /// DNS search domains pushed to clients.
+    #[serde(default)]
+    pub dns_search: Vec<String>,
+    /// Networks clients are told to route through the tunnel, such as LANs
+    /// behind the server.
+    #[serde(default)]
+    pub push_routes: Vec<String>,
+    /// The first and last addresses handed out to clients, as
+    /// `"10.99.99.100-10.99.99.199"`. The whole tunnel network when unset.
+    #[serde(default)]
+    pub address_pool: Option<String>,
+    /// Clients that always get the same tunnel address.
+    #[serde(default)]
+    pub reservations: Vec<Reservation>,
+    /// Where the addresses handed out are kept, so clients get the same ones
+    /// after a restart.
+    #[serde(default = "default_lease_file")]
+    pub lease_file: String,
+    /// IPv6 address of the server's TUN device. Its prefix holds the
+    /// clients' `tun_ipv6` addresses; when unset, the tunnel is IPv4 only.
+    #[serde(default)]
+    pub tun_ipv6: Option<String>,
+    /// Prefix length of `tun_ipv6`.
+    #[serde(default = "default_tun_ipv6_prefix_len")]
+    pub tun_ipv6_prefix_len: u8,
+    /// Whether client IPv6 traffic is masqueraded (NAT66) behind the
+    /// server's address. Turn off when the prefix is routed to the server.
+    #[serde(default = "default_ipv6_nat")]
+    pub ipv6_nat: bool,
+    /// Thresholds for the server's own probes of client links.
+    #[serde(default)]
+    pub health: HealthConfig,
+}
+
+impl ServerConfig {
+    /// The address the server listens on. `::` accepts IPv4 clients too.
+    pub fn listen_addr(&self) -> OneboxResult<SocketAddr> {
+        let ip = parse_ip(&self.listen_address).ok_or_else(|| {
+            OneboxError::Config(format!("Invalid listen address '{}'", self.listen_address))
+        })?;
+        Ok(SocketAddr::new(ip, self.listen_port))
+    }
+
+    /// The TUN device's IPv6 address and prefix length, if configured.
+    pub fn tun_ipv6(&self) -> OneboxResult<Option<(Ipv6Addr, u8)>> {
+        parse_tun_ipv6(self.tun_ipv6.as_deref(), self.tun_ipv6_prefix_len)
+    }
+
+    /// The TUN device's address and the prefix length of the tunnel network.
+    pub fn tun_addr(&self) -> OneboxResult<(Ipv4Addr, u8)> {
+        let addr = self.tun_ip.parse().map_err(|e| {
+            OneboxError::Config(format!("Invalid TUN address '{}': {e}", self.tun_ip))
+        })?;
+        // Clients need addresses besides the server's own.
+        if !(1..=30).contains(&self.tun_prefix_len) {
+            return Err(OneboxError::Config(format!(
+                "Invalid TUN prefix length {}",
+                self.tun_prefix_len
+            )));
+        }
+        Ok((addr, self.tun_prefix_len))
+    }
+
+    /// The DNS servers pushed to clients.
+    pub fn dns_servers(&self) -> OneboxResult<Vec<IpAddr>> {
+        self.dns_servers
+            .iter()
+            .map(|addr| {
+                parse_ip(addr)
+                    .ok_or_else(|| OneboxError::Config(format!("Invalid DNS server '{addr}'")))
+            })
+            .collect()
+    }
+
+    /// The networks pushed to clients.
+    pub fn push_routes(&self) -> OneboxResult<Vec<(IpAddr, u8)>> {
+        self.push_routes
+            .iter()
+            .map(|cidr| parse_cidr(cidr))
+            .collect()
+    }
+
+    /// The first and last addresses handed out to clients, both in the
+    /// tunnel network.
+    pub fn address_pool(&self) -> OneboxResult<(Ipv4Addr, Ipv4Addr)> {
+        let (tun_ip, prefix_len) = self.tun_addr()?;
+        let Some(pool) = &self.address_pool else {
+            // Everything but the network and broadcast addresses.
+            let mask = u32::MAX << (32 - u32::from(prefix_len));
+            let first = (u32::from(tun_ip) & mask) + 1;
+            let last = (u32::from(tun_ip) | !mask) - 1;
+            return Ok((first.into(), last.into()));
+        };
+        let invalid = || OneboxError::Config(format!("Invalid address pool '{pool}'"));
+        let (first, last) = pool.split_once('-').ok_or_else(invalid)?;
+        let first: Ipv4Addr = first.trim().parse().map_err(|_| invalid())?;
+        let last: Ipv4Addr = last.trim().parse().map_err(|_| invalid())?;
+        if first > last || !self.in_tunnel(first)? || !self.in_tunnel(last)? {
+            return Err(invalid());
+        }
+        Ok((first, last))
+    }
+
+    /// The reserved addresses, each in the tunnel network and held by one
+    /// client.
+    pub fn reservations(&self) -> OneboxResult<Vec<(ClientId, Ipv4Addr)>> {
+        let (tun_ip, _) = self.tun_addr()?;
+        let mut reservations: Vec<(ClientId, Ipv4Addr)> = Vec::new();
+        for reservation in &self.reservations {
+            let address: Ipv4Addr = reservation.address.parse().map_err(|e| {
+                OneboxError::Config(format!(
+                    "Invalid reserved address '{}': {e}",
+                    reservation.address
+                ))
+            })?;
+            let client = ClientId(u128::from(reservation.client_id));
+            if address == tun_ip || !self.in_tunnel(address)? {
+                return Err(OneboxError::Config(format!(
+                    "Reserved address {address} is not a client address in the tunnel network"
+                )));
+            }
+            if reservations
+                .iter()
+                .any(|&(other, reserved)| other == client || reserved == address)
+            {
+                return Err(OneboxError::Config(format!(
+                    "Reservation of {address} for client {} conflicts with another",
+                    reservation.client_id
+                )));
+            }
+            reservations.push((client, address));
+        }
+        Ok(reservations)
+    }
+
+    fn in_tunnel(&self, addr: Ipv4Addr) -> OneboxResult<bool> {
+        let (tun_ip, prefix_len) = self.tun_addr()?;
+        Ok(network(addr.into(), prefix_len) == network(tun_ip.into(), prefix_len))
+    }
+}
+
+/// A client's fixed tunnel address, in `[[server.reservations]]`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Reservation {
+    pub client_id: u64,
+    pub address: String,
+}
+
+fn parse_tun_ipv6(addr: Option<&str>, prefix_len: u8) -> OneboxResult<Option<(Ipv6Addr, u8)>> {
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    let addr = addr
+        .parse()
+        .map_err(|e| OneboxError::Config(format!("Invalid TUN IPv6 address '{addr}': {e}")))?;
+    if prefix_len > 128 {
+        return Err(OneboxError::Config(format!(
+            "Invalid TUN IPv6 prefix length {prefix_len}"
+        )));
+    }
+    Ok(Some((addr, prefix_len)))
+}
+
+/// The network an IPv6 address is in, e.g. `fd00:8::/64` for `fd00:8::1/64`.
+pub fn ipv6_network(addr: Ipv6Addr, prefix_len: u8) -> String {
+    let mask = u128::MAX
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or(0);
+    let network = Ipv6Addr::from(u128::from(addr) & mask);
+    format!("{network}/{prefix_len}")
+}
+
+fn default_tun_ipv6_prefix_len() -> u8 {
+    64
+}
+
+fn default_client_id() -> u64 {
+    1
+}
+
+fn default_client_tun_ip() -> String {
+    "10.8.0.1".to_string()
+}
+
+fn default_tun_netmask() -> String {
+    "255.255.255.0".to_string()
+}
+
+fn default_use_pushed_settings() -> bool {
+    true
+}
+
+fn default_server_tun_ip() -> String {
+    "10.99.99.1".to_string()
+}
+
+fn default_tun_prefix_len() -> u8 {
+    24
+}
+
+fn default_lease_file() -> String {
+    "/var/lib/onebox/leases.json".to_string()
+}
+
+fn default_mtu() -> u16 {
+    1400
+}
+
+fn default_ipv6_nat() -> bool {
+    true
+}
+
+/// Parses an IP address, allowing the brackets IPv6 addresses take in URLs.
+fn parse_ip(host: &str) -> Option<IpAddr> {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+        .parse()
+        .ok()
+}
+
+/// Resolves an IP address or host name and a port to socket addresses.
+pub fn resolve(host: &str, port: u16) -> OneboxResult<Vec<SocketAddr>> {
+    if let Some(ip) = parse_ip(host) {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| OneboxError::Config(format!("Failed to resolve '{host}': {e}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(OneboxError::Config(format!(
+            "'{host}' did not resolve to any address"
+        )));
+    }
+    Ok(addrs)
 }
 
 impl Config {
@@ -69,9 +758,83 @@ impl Default for ClientConfig {
         Self {
             server_address: "127.0.0.1".to_string(),
             server_port: 51820,
+            server_address_v6: None,
+            client_id: default_client_id(),
+            tun_ipv6: None,
+            tun_ipv6_prefix_len: default_tun_ipv6_prefix_len(),
             tun_name: "onebox0".to_string(),
-            tun_ip: "10.8.0.1".to_string(),
-            tun_netmask: "255.255.255.0".to_string(),
+            tun_ip: default_client_tun_ip(),
+            tun_netmask: default_tun_netmask(),
+            tun_mtu: None,
+            use_pushed_settings: default_use_pushed_settings(),
+            dns_mode: DnsMode::default(),
+            failback: default_failback(),
+            failback_delay_secs: default_failback_delay_secs(),
+            backup_probe_divisor: default_backup_probe_divisor(),
+            discovery: DiscoveryConfig::default(),
+            links: Vec::new(),
+            split_tunnel: SplitTunnelConfig::default(),
+            usage_file: default_usage_file(),
+            route_journal: default_route_journal(),
+            congestion_control: default_congestion_control(),
+            queue_delay_target_ms: default_queue_delay_target_ms(),
+            bandwidth_probe_interval_secs: default_bandwidth_probe_interval_secs(),
+            link_binding: LinkBinding::default(),
+            routing_table_base: default_routing_table_base(),
+            health: HealthConfig::default(),
+            hooks: HooksConfig::default(),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            mode: DiscoveryMode::Auto,
+            include: vec!["*".to_string()],
+            exclude: [
+                "lo*", "*docker*", "onebox*", "veth*", "br-*", "virbr*", "tun*", "tap*", "wg*",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_up: None,
+            on_degraded: None,
+            on_down: None,
+            on_change: None,
+            fifo: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_ms: 500,
+            probe_timeout_ms: 2000,
+            adaptive_probing: false,
+            min_probe_interval_ms: 200,
+            max_probe_interval_ms: 5000,
+            down_after_failures: 4,
+            degraded_rtt_ms: 500,
+            degraded_jitter_ms: 100,
+            degraded_loss_percent: 10.0,
+            degraded_quality: 0.0,
+            recovery_probes: 5,
+            recovery_hold_secs: 10,
+            degraded_weight_percent: 25,
+            passive_monitoring: true,
+            passive_timeout_ms: 1000,
+            suppress_probes: false,
+            check_interval_ms: 10_000,
+            check_timeout_ms: 3000,
         }
     }
 }
@@ -79,8 +842,21 @@ impl Default for ClientConfig {
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            listen_address: "0.0.0.0".to_string(),
+            listen_address: "::".to_string(),
             listen_port: 51820,
+            tun_ip: default_server_tun_ip(),
+            tun_prefix_len: default_tun_prefix_len(),
+            mtu: default_mtu(),
+            dns_servers: Vec::new(),
+            dns_search: Vec::new(),
+            push_routes: Vec::new(),
+            address_pool: None,
+            reservations: Vec::new(),
+            lease_file: default_lease_file(),
+            tun_ipv6: None,
+            tun_ipv6_prefix_len: default_tun_ipv6_prefix_len(),
+            ipv6_nat: default_ipv6_nat(),
+            health: HealthConfig::default(),
         }
     }
 }
@@ -88,6 +864,7 @@ impl Default for ServerConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::HealthCheck;
     use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
@@ -106,13 +883,35 @@ mod tests {
             [client]
             server_address = "1.2.3.4"
             server_port = 12345
+            client_id = 7
             tun_name = "test_tun"
             tun_ip = "10.0.0.1"
             tun_netmask = "255.255.0.0"
+            tun_ipv6 = "fd00:8::2"
+            link_binding = "policy"
+
+            [client.hooks]
+            on_down = "/etc/onebox/link-down"
+            timeout_secs = 5
 
             [server]
             listen_address = "0.0.0.0"
             listen_port = 54321
+            tun_ipv6 = "2001:db8:8::1"
+            tun_ipv6_prefix_len = 56
+            ipv6_nat = false
+            tun_ip = "10.9.0.1"
+            tun_prefix_len = 16
+            dns_servers = ["10.9.0.1", "2606:4700:4700::1111"]
+            push_routes = ["192.168.50.0/24"]
+            address_pool = "10.9.1.0 - 10.9.1.255"
+
+            [[server.reservations]]
+            client_id = 7
+            address = "10.9.0.7"
+
+            [server.health]
+            down_after_failures = 2
             "#
         )
         .unwrap();
@@ -128,6 +927,283 @@ mod tests {
         assert_eq!(config.client.tun_netmask, "255.255.0.0");
         assert_eq!(config.server.listen_address, "0.0.0.0");
         assert_eq!(config.server.listen_port, 54321);
+        assert_eq!(config.server.health.down_after_failures, 2);
+        assert_eq!(config.server.health.probe_interval_ms, 500);
+        assert!(config.client.failback);
+        assert_eq!(config.client.failback_delay_secs, 10);
+        assert!(config.client.links.is_empty());
+        assert_eq!(
+            config.client.hooks.on_down.as_deref(),
+            Some("/etc/onebox/link-down")
+        );
+        assert_eq!(config.client.hooks.on_up, None);
+        assert_eq!(config.client.hooks.timeout_secs, 5);
+        assert_eq!(
+            config.client.tun_ipv6().unwrap(),
+            Some(("fd00:8::2".parse().unwrap(), 64))
+        );
+        assert_eq!(
+            config.server.tun_ipv6().unwrap(),
+            Some(("2001:db8:8::1".parse().unwrap(), 56))
+        );
+        assert!(!config.server.ipv6_nat);
+        assert_eq!(config.client.link_binding, LinkBinding::Policy);
+        assert_eq!(config.client.routing_table_base().unwrap(), 7000);
+        assert_eq!(config.client.client_id, 7);
+        assert!(config.client.use_pushed_settings);
+        assert_eq!(config.client.dns_mode, DnsMode::Auto);
+        assert_eq!(
+            config.server.tun_addr().unwrap(),
+            (Ipv4Addr::new(10, 9, 0, 1), 16)
+        );
+        assert_eq!(config.server.mtu, 1400);
+        assert_eq!(
+            config.server.dns_servers().unwrap(),
+            [
+                "10.9.0.1".parse::<IpAddr>().unwrap(),
+                "2606:4700:4700::1111".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.server.push_routes().unwrap(),
+            [("192.168.50.0".parse().unwrap(), 24)]
+        );
+        assert_eq!(
+            config.server.address_pool().unwrap(),
+            (Ipv4Addr::new(10, 9, 1, 0), Ipv4Addr::new(10, 9, 1, 255))
+        );
+        assert_eq!(
+            config.server.reservations().unwrap(),
+            [(ClientId(7), Ipv4Addr::new(10, 9, 0, 7))]
+        );
+        assert_eq!(config.server.lease_file, "/var/lib/onebox/leases.json");
+    }
+
+    #[test]
+    fn test_tun_ipv6() {
+        assert_eq!(ClientConfig::default().tun_ipv6().unwrap(), None);
+        let config = ServerConfig {
+            tun_ipv6: Some("10.0.0.1".to_string()),
+            ..ServerConfig::default()
+        };
+        assert!(config.tun_ipv6().is_err());
+        assert_eq!(
+            ipv6_network("fd00:8::1".parse().unwrap(), 64),
+            "fd00:8::/64"
+        );
+        assert_eq!(
+            ipv6_network("2001:db8:8:ff::1".parse().unwrap(), 56),
+            "2001:db8:8::/56"
+        );
+        assert_eq!(ipv6_network("fd00::1".parse().unwrap(), 0), "::/0");
+    }
+
+    #[test]
+    fn test_split_tunnel() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("config.toml");
+        let mut file = File::create(&file_path).unwrap();
+        writeln!(
+            file,
+            r#"
+            preshared_key = "my-test-psk"
+
+            [client]
+            server_address = "1.2.3.4"
+            server_port = 12345
+            tun_name = "test_tun"
+            tun_ip = "10.0.0.1"
+            tun_netmask = "255.255.0.0"
+
+            [client.split_tunnel]
+            include = ["10.20.0.0/16", "2001:db8::1/48"]
+            exclude = ["192.168.1.7"]
+            bypass_link = "eth0"
+            exclude_domains = ["example.com"]
+            dns_listen = "127.0.0.1:5353"
+            "#
+        )
+        .unwrap();
+
+        let client = Config::from_file(&file_path).unwrap().client;
+        assert_eq!(client.client_id, 1);
+        let split = client.split_tunnel;
+        assert_eq!(
+            split.include_networks().unwrap(),
+            [
+                ("10.20.0.0".parse().unwrap(), 16),
+                ("2001:db8::".parse().unwrap(), 48)
+            ]
+        );
+        assert_eq!(
+            split.exclude_networks().unwrap(),
+            [("192.168.1.7".parse().unwrap(), 32)]
+        );
+        assert_eq!(split.bypass_link.as_deref(), Some("eth0"));
+        assert!(split.include_domains.is_empty());
+        assert_eq!(
+            split.dns_forwarder().unwrap(),
+            Some((
+                "127.0.0.1:5353".parse().unwrap(),
+                "1.1.1.1:53".parse().unwrap()
+            ))
+        );
+
+        assert_eq!(SplitTunnelConfig::default().dns_forwarder().unwrap(), None);
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "fd00::/129", "10.0.0.0/"] {
+            assert!(parse_cidr(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_load_link_priorities() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("config.toml");
+        let mut file = File::create(&file_path).unwrap();
+        writeln!(
+            file,
+            r#"
+            preshared_key = "my-test-psk"
+
+            [client]
+            server_address = "1.2.3.4"
+            server_port = 12345
+            tun_name = "test_tun"
+            tun_ip = "10.0.0.1"
+            tun_netmask = "255.255.0.0"
+            failback = false
+            failback_delay_secs = 30
+            queue_delay_target_ms = 80
+            bandwidth_probe_interval_secs = 0
+
+            [client.health]
+            degraded_rtt_ms = 800
+            recovery_probes = 3
+            probe_timeout_ms = 1500
+
+            [[client.links]]
+            name = "wwan0"
+            priority = 200
+            monthly_cap_mb = 10000
+            billing_cycle_day = 15
+            upstream_weight = 1
+            downstream_weight = 8
+            probe_interval_ms = 1000
+            adaptive_probing = true
+
+            [[client.links.checks]]
+            type = "tcp"
+            target = "1.1.1.1:443"
+
+            [[client.links.checks]]
+            type = "http"
+            url = "http://connectivitycheck.gstatic.com/generate_204"
+            expect_status = 204
+
+            [[client.links.checks]]
+            type = "dns"
+            name = "example.com"
+            server = "9.9.9.9"
+
+            [[client.links]]
+            name = "wlan0"
+            enabled = false
+            "#
+        )
+        .unwrap();
+
+        let config = Config::from_file(&file_path).unwrap();
+
+        assert!(!config.client.failback);
+        assert_eq!(config.client.failback_delay_secs, 30);
+        assert!(config.client.congestion_control);
+        assert_eq!(config.client.queue_delay_target_ms, 80);
+        assert_eq!(config.client.bandwidth_probe_interval_secs, 0);
+        assert_eq!(config.client.health.degraded_rtt_ms, 800);
+        assert_eq!(config.client.health.recovery_probes, 3);
+        assert_eq!(config.client.health.down_after_failures, 4);
+        assert_eq!(config.client.links.len(), 2);
+        let wwan0 = config.client.link_config("wwan0");
+        assert_eq!(wwan0.priority, 200);
+        assert_eq!(wwan0.monthly_cap_mb, Some(10000));
+        assert_eq!(wwan0.daily_cap_mb, None);
+        assert_eq!(wwan0.billing_cycle_day, 15);
+        assert_eq!(wwan0.upstream_weight, Some(1));
+        assert_eq!(wwan0.downstream_weight, Some(8));
+        assert_eq!(
+            wwan0.checks,
+            [
+                HealthCheck::Tcp {
+                    target: "1.1.1.1:443".to_string()
+                },
+                HealthCheck::Http {
+                    url: "http://connectivitycheck.gstatic.com/generate_204".to_string(),
+                    expect_status: 204
+                },
+                HealthCheck::Dns {
+                    name: "example.com".to_string(),
+                    server: "9.9.9.9".to_string()
+                },
+            ]
+        );
+        assert!(!config.client.link_config("wlan0").enabled);
+        assert!(config.client.link_config("wlan0").checks.is_empty());
+
+        let wwan0_health = config.client.link_health("wwan0");
+        assert_eq!(wwan0_health.probe_interval_ms, 1000);
+        assert_eq!(wwan0_health.probe_timeout_ms, 1500);
+        assert!(wwan0_health.adaptive_probing);
+        assert_eq!(wwan0_health.degraded_rtt_ms, 800);
+        let wlan0_health = config.client.link_health("wlan0");
+        assert_eq!(wlan0_health.probe_interval_ms, 500);
+        assert!(!wlan0_health.adaptive_probing);
+
+        let eth0 = config.client.link_config("eth0");
+        assert_eq!(eth0.name, "eth0");
+        assert_eq!(eth0.priority, 100);
+        assert!(eth0.enabled);
+    }
+
+    #[test]
+    fn test_select_links() {
+        let mut config = ClientConfig {
+            links: vec![
+                LinkConfig {
+                    name: "wwan*".to_string(),
+                    priority: 200,
+                    ..LinkConfig::default()
+                },
+                LinkConfig {
+                    name: "wlan0".to_string(),
+                    enabled: false,
+                    ..LinkConfig::default()
+                },
+                LinkConfig {
+                    name: "wg0".to_string(),
+                    ..LinkConfig::default()
+                },
+            ],
+            ..ClientConfig::default()
+        };
+        let interfaces = ["lo", "eth0", "wlan0", "wwan1", "wg0", "docker0", "onebox0"];
+        let names = |links: Vec<LinkConfig>| -> Vec<String> {
+            links.into_iter().map(|link| link.name).collect()
+        };
+
+        let links = config.select_links(interfaces);
+        assert_eq!(links[1].name, "wwan1");
+        assert_eq!(links[1].priority, 200);
+        assert_eq!(names(links), ["eth0", "wwan1", "wg0"]);
+
+        config.discovery.mode = DiscoveryMode::Explicit;
+        assert_eq!(names(config.select_links(interfaces)), ["wwan1", "wg0"]);
+
+        config.discovery = DiscoveryConfig {
+            include: vec!["eth*".to_string(), "wlan*".to_string()],
+            ..DiscoveryConfig::default()
+        };
+        config.links.clear();
+        assert_eq!(names(config.select_links(interfaces)), ["eth0", "wlan0"]);
     }
 
     #[test]
@@ -162,12 +1238,106 @@ mod tests {
         let config = ClientConfig::default();
         assert_eq!(config.server_address, "127.0.0.1");
         assert_eq!(config.server_port, 51820);
+        assert_eq!(config.client_id, 1);
     }
 
     #[test]
     fn test_default_server_config() {
         let config = ServerConfig::default();
-        assert_eq!(config.listen_address, "0.0.0.0");
+        assert_eq!(config.listen_address, "::");
         assert_eq!(config.listen_port, 51820);
+        assert_eq!(
+            config.listen_addr().unwrap(),
+            "[::]:51820".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            config.tun_addr().unwrap(),
+            (Ipv4Addr::new(10, 99, 99, 1), 24)
+        );
+        let config = ServerConfig {
+            tun_prefix_len: 31,
+            ..ServerConfig::default()
+        };
+        assert!(config.tun_addr().is_err());
+    }
+
+    #[test]
+    fn test_address_pool_and_reservations() {
+        let config = ServerConfig::default();
+        assert_eq!(
+            config.address_pool().unwrap(),
+            (Ipv4Addr::new(10, 99, 99, 1), Ipv4Addr::new(10, 99, 99, 254))
+        );
+        for pool in [
+            "10.99.99.200-10.99.99.100",
+            "10.99.99.100-10.99.100.1",
+            "10.99.99.100",
+        ] {
+            let config = ServerConfig {
+                address_pool: Some(pool.to_string()),
+                ..ServerConfig::default()
+            };
+            assert!(config.address_pool().is_err(), "{pool}");
+        }
+
+        let reserve = |client_id, address: &str| Reservation {
+            client_id,
+            address: address.to_string(),
+        };
+        let invalid = [
+            vec![reserve(1, "10.99.99.1")],
+            vec![reserve(1, "10.99.98.5")],
+            vec![reserve(1, "10.99.99.5"), reserve(2, "10.99.99.5")],
+            vec![reserve(1, "10.99.99.5"), reserve(1, "10.99.99.6")],
+        ];
+        for reservations in invalid {
+            let config = ServerConfig {
+                reservations,
+                ..ServerConfig::default()
+            };
+            assert!(config.reservations().is_err());
+        }
+    }
+
+    #[test]
+    fn test_routing_table_base_avoids_reserved_tables() {
+        for base in [0, 200, 255, 32703, u32::MAX] {
+            let config = ClientConfig {
+                routing_table_base: base,
+                ..ClientConfig::default()
+            };
+            assert!(config.routing_table_base().is_err(), "{base}");
+        }
+        let config = ClientConfig {
+            routing_table_base: 32702,
+            ..ClientConfig::default()
+        };
+        assert_eq!(config.routing_table_base().unwrap(), 32702);
+    }
+
+    #[test]
+    fn test_server_addrs_accept_ipv6() {
+        let config = ClientConfig {
+            server_address: "1.2.3.4".to_string(),
+            server_address_v6: Some("[2001:db8::1]".to_string()),
+            ..ClientConfig::default()
+        };
+        let addrs: Vec<SocketAddr> = ["1.2.3.4:51820", "[2001:db8::1]:51820"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(config.server_addrs().unwrap(), addrs);
+
+        let config = ClientConfig {
+            server_address: "2001:db8::1".to_string(),
+            ..ClientConfig::default()
+        };
+        assert_eq!(config.server_addrs().unwrap(), addrs[1..]);
+
+        let server = ServerConfig {
+            listen_address: "not an address".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(server.listen_addr().is_err());
     }
 }
@@ -0,0 +1,209 @@
+//! Control messages exchanged between client and server.
+//!
+//! Control messages travel in `PacketType::Control` packets. They use their
+//! own sequence space so that they never share a nonce with data, probes or
+//! bandwidth probe trains.
+
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+/// How often the server acknowledges the data it received from each of a
+/// client's links, at most.
+pub const DATA_ACK_INTERVAL: Duration = Duration::from_millis(25);
+
+/// A message carried by a `PacketType::Control` packet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ControlMessage {
+    /// The client's view of its links. Sent periodically over every link, so
+    /// the server also learns which source address each link uses.
+    LinkTable {
+        /// The link this copy of the table was sent over.
+        via: String,
+        /// Every link of the client.
+        links: Vec<LinkAdvert>,
+    },
+    /// The server's packet counters for the link a `LinkTable` arrived on,
+    /// from which the client derives per-direction loss.
+    LinkFeedback {
+        /// The link the counters are for.
+        link: String,
+        /// Packets the server has received from the link.
+        received: u64,
+        /// Packets the server has sent to the link.
+        sent: u64,
+    },
+    /// Acknowledges data received from the link this message is sent to, so
+    /// the client learns that the link is alive without waiting for probes.
+    DataAck {
+        /// The highest data sequence number received from the link.
+        highest: u64,
+    },
+}
+
+/// How the client wants a single link to be used.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkAdvert {
+    /// The client's name for the link (its interface name).
+    pub name: String,
+    /// Whether the link is in the client's active pool.
+    pub active: bool,
+    /// Client to server direction.
+    pub upstream: DirectionInfo,
+    /// Server to client direction.
+    pub downstream: DirectionInfo,
+}
+
+/// Network settings the server pushes to a client in its `AuthResponse`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkSettings {
+    /// The client's tunnel address.
+    pub address: Ipv4Addr,
+    /// Prefix length of the tunnel network.
+    pub prefix_len: u8,
+    /// The client's tunnel IPv6 address and prefix length, when the tunnel
+    /// carries IPv6.
+    pub address_v6: Option<(Ipv6Addr, u8)>,
+    /// DNS servers to resolve through, reached over the tunnel.
+    pub dns_servers: Vec<IpAddr>,
+    /// DNS search domains.
+    pub dns_search: Vec<String>,
+    /// Networks to route through the tunnel on top of the client's own
+    /// split tunnel rules.
+    pub routes: Vec<(IpAddr, u8)>,
+    /// MTU of the TUN device.
+    pub mtu: u16,
+}
+
+/// Capacity and scheduling weight of one direction of a link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DirectionInfo {
+    /// Measured capacity in bytes per second, if known.
+    pub capacity: Option<f64>,
+    /// Relative share of traffic the link should carry in this direction.
+    pub weight: u32,
+}
+
+/// Milliseconds since the Unix epoch: a starting point for a control counter
+/// that a restarted client or server doesn't go back below, as long as it
+/// sent fewer control packets than a thousand a second on average.
+pub fn counter_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Returns the sequence number (and thus nonce) of the `counter`-th control
+/// packet sent in the given direction.
+pub fn control_sequence(counter: u64, from_server: bool) -> u64 {
+    let direction = if from_server { 1 << 62 } else { 0 };
+    (1 << 63) | direction | (1 << 61) | (counter & COUNTER_MASK)
+}
+
+const COUNTER_MASK: u64 = (1 << 48) - 1;
+const SERVER_PROBE_PREFIX: u64 = (1 << 63) | (1 << 62) | (1 << 60);
+
+/// Returns the sequence number of the `counter`-th probe the server sends to
+/// a client link. The client echoes these back unchanged, while its own
+/// probes keep using plain counters.
+pub fn server_probe_sequence(counter: u64) -> u64 {
+    SERVER_PROBE_PREFIX | (counter & COUNTER_MASK)
+}
+
+/// Returns the counter of a server-initiated probe, or `None` if `seq` is
+/// not one.
+pub fn server_probe_counter(seq: u64) -> Option<u64> {
+    (seq & !COUNTER_MASK == SERVER_PROBE_PREFIX).then_some(seq & COUNTER_MASK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandwidth::train_sequence;
+
+    #[test]
+    fn control_sequences_do_not_collide_with_trains() {
+        let up = control_sequence(5, false);
+        let down = control_sequence(5, true);
+        assert_ne!(up, down);
+        assert_ne!(control_sequence(6, false), up);
+        for train_id in [0, 5, u32::MAX] {
+            for index in [0, 5, u16::MAX] {
+                assert_ne!(train_sequence(train_id, index, false), up);
+                assert_ne!(train_sequence(train_id, index, true), down);
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_taken_apart_do_not_overlap() {
+        let first = counter_seed();
+        std::thread::sleep(Duration::from_millis(5));
+        let second = counter_seed();
+        // A run seeded first that sent up to 5 packets stays below the
+        // counters of a run seeded second.
+        assert!(second >= first + 5);
+        assert_ne!(
+            control_sequence(first + 4, false),
+            control_sequence(second, false)
+        );
+    }
+
+    #[test]
+    fn server_probes_have_their_own_sequence_space() {
+        let seq = server_probe_sequence(7);
+        assert_eq!(server_probe_counter(seq), Some(7));
+        assert_eq!(server_probe_counter(7), None);
+        assert_eq!(server_probe_counter(control_sequence(7, true)), None);
+        assert_eq!(
+            server_probe_counter(train_sequence(u32::MAX, u16::MAX, true)),
+            None
+        );
+        assert_ne!(seq, control_sequence(7, true));
+    }
+
+    #[test]
+    fn link_table_round_trips() {
+        let message = ControlMessage::LinkTable {
+            via: "wwan0".to_string(),
+            links: vec![LinkAdvert {
+                name: "wwan0".to_string(),
+                active: true,
+                upstream: DirectionInfo {
+                    capacity: Some(250_000.0),
+                    weight: 2,
+                },
+                downstream: DirectionInfo {
+                    capacity: None,
+                    weight: 1,
+                },
+            }],
+        };
+        let bytes = bincode::serialize(&message).unwrap();
+        assert_eq!(
+            bincode::deserialize::<ControlMessage>(&bytes).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn network_settings_round_trip() {
+        let settings = NetworkSettings {
+            address: Ipv4Addr::new(10, 99, 99, 2),
+            prefix_len: 24,
+            address_v6: Some(("fd00:8::2".parse().unwrap(), 64)),
+            dns_servers: vec!["10.99.99.1".parse().unwrap()],
+            dns_search: vec!["corp.example".to_string()],
+            routes: vec![("192.168.50.0".parse().unwrap(), 24)],
+            mtu: 1400,
+        };
+        let bytes = bincode::serialize(&settings).unwrap();
+        assert_eq!(
+            bincode::deserialize::<NetworkSettings>(&bytes).unwrap(),
+            settings
+        );
+        // Servers from before pushed settings answered with a bare string.
+        assert!(bincode::deserialize::<NetworkSettings>(b"AUTH_OK").is_err());
+    }
+}
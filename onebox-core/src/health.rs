@@ -0,0 +1,1036 @@
+//! Link health monitoring.
+//!
+//! A `LinkMonitor` follows a single link through probe and data-path events
+//! and decides whether it is Up, Degraded or Down. It reads the time from a
+//! `Clock`, so that failover timing can be tested without waiting for it.
+
+use crate::config::HealthConfig;
+use crate::control::DATA_ACK_INTERVAL;
+use crate::types::LinkHealth;
+pub use crate::types::LinkStatus;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Thresholds that move a link between Up, Degraded and Down.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthPolicy {
+    /// How often the link is probed, or the base interval in adaptive mode.
+    pub probe_interval: Duration,
+    /// How long a probe may go unanswered before it counts as lost.
+    pub probe_timeout: Duration,
+    /// Whether the probe interval adapts to link activity and loss.
+    pub adaptive_probing: bool,
+    /// The interval probes speed up to on loss, in adaptive mode.
+    pub min_probe_interval: Duration,
+    /// The interval probes back off to on an idle link, in adaptive mode.
+    pub max_probe_interval: Duration,
+    /// Consecutive lost probes after which a link is Down.
+    pub down_after_failures: u32,
+    /// Smoothed RTT above which a link is Degraded.
+    pub degraded_rtt: Duration,
+    /// Jitter above which a link is Degraded.
+    pub degraded_jitter: Duration,
+    /// Windowed probe loss above which a link is Degraded, in percent.
+    pub degraded_loss_percent: f32,
+    /// Quality score (MOS) below which a link is Degraded; 0 disables.
+    pub degraded_quality: f32,
+    /// Consecutive good probes needed to leave Degraded or Down.
+    pub recovery_probes: u32,
+    /// Minimum time spent Degraded or Down, restarted by every bad probe.
+    pub recovery_hold: Duration,
+    /// Share of its normal scheduling weight a Degraded link keeps, in percent.
+    pub degraded_weight_percent: u8,
+    /// Whether unacknowledged data and send errors count against the link.
+    pub passive_monitoring: bool,
+    /// The least time data may go unacknowledged before the link is Down.
+    pub passive_timeout: Duration,
+    /// Whether probes are skipped on Up links whose data is acknowledged.
+    pub suppress_probes: bool,
+}
+
+impl From<&HealthConfig> for HealthPolicy {
+    fn from(config: &HealthConfig) -> Self {
+        let probe_interval = Duration::from_millis(config.probe_interval_ms.max(1));
+        Self {
+            probe_interval,
+            probe_timeout: Duration::from_millis(config.probe_timeout_ms.max(1)),
+            adaptive_probing: config.adaptive_probing,
+            min_probe_interval: Duration::from_millis(config.min_probe_interval_ms.max(1))
+                .min(probe_interval),
+            max_probe_interval: Duration::from_millis(config.max_probe_interval_ms)
+                .max(probe_interval),
+            down_after_failures: config.down_after_failures.max(1),
+            degraded_rtt: Duration::from_millis(config.degraded_rtt_ms),
+            degraded_jitter: Duration::from_millis(config.degraded_jitter_ms),
+            degraded_loss_percent: config.degraded_loss_percent,
+            degraded_quality: config.degraded_quality,
+            recovery_probes: config.recovery_probes.max(1),
+            recovery_hold: Duration::from_secs(config.recovery_hold_secs),
+            degraded_weight_percent: config.degraded_weight_percent.min(100),
+            passive_monitoring: config.passive_monitoring,
+            passive_timeout: Duration::from_millis(config.passive_timeout_ms),
+            suppress_probes: config.suppress_probes,
+        }
+    }
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self::from(&HealthConfig::default())
+    }
+}
+
+/// A link status transition, from the old status to the new one.
+pub type StatusChange = (LinkStatus, LinkStatus);
+
+/// How many of the most recent probe outcomes loss and min RTT are computed over.
+pub const PROBE_WINDOW: usize = 40;
+
+/// The fewest probe outcomes loss must be computed over before it can
+/// degrade a link, so that a single early timeout doesn't.
+const MIN_LOSS_SAMPLES: usize = 10;
+
+/// Gain of the smoothed RTT estimator (RFC 6298).
+const SRTT_GAIN: f64 = 1.0 / 8.0;
+/// Gain of the RTT variance estimator (RFC 6298).
+const RTTVAR_GAIN: f64 = 1.0 / 4.0;
+/// Gain of the interarrival jitter estimator (RFC 3550).
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Recent probe outcomes of a link and the RTT statistics derived from them.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeHistory {
+    /// The last `PROBE_WINDOW` probes: their RTT, or `None` if lost.
+    window: VecDeque<Option<Duration>>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    jitter: Duration,
+    last_rtt: Option<Duration>,
+}
+
+impl ProbeHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, outcome: Option<Duration>) {
+        if self.window.len() == PROBE_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(outcome);
+    }
+
+    /// Records a probe answered after `rtt`.
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.push(Some(rtt));
+        let sample = rtt.as_secs_f64();
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let srtt = srtt.as_secs_f64();
+                let rttvar = self.rttvar.as_secs_f64();
+                self.rttvar = Duration::from_secs_f64(
+                    rttvar + RTTVAR_GAIN * ((srtt - sample).abs() - rttvar),
+                );
+                self.srtt = Some(Duration::from_secs_f64(srtt + SRTT_GAIN * (sample - srtt)));
+            }
+        }
+        if let Some(last) = self.last_rtt {
+            let delta = (sample - last.as_secs_f64()).abs();
+            let jitter = self.jitter.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(jitter + JITTER_GAIN * (delta - jitter));
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Records a probe that was never answered.
+    pub fn record_loss(&mut self) {
+        self.push(None);
+    }
+
+    /// The number of probe outcomes in the window.
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Whether no probe outcome has been recorded yet.
+    pub fn is_empty(&self) -> bool {
+        self.window.is_empty()
+    }
+
+    /// Loss over the probe window, in percent.
+    pub fn loss_percent(&self) -> f32 {
+        if self.window.is_empty() {
+            return 0.0;
+        }
+        let lost = self
+            .window
+            .iter()
+            .filter(|outcome| outcome.is_none())
+            .count();
+        lost as f32 / self.window.len() as f32 * 100.0
+    }
+
+    /// The smoothed RTT, once a probe has been answered.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// The mean deviation of RTT samples from the smoothed RTT.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// The smoothed difference between consecutive RTT samples.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The smallest RTT within the probe window.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.window.iter().flatten().min().copied()
+    }
+
+    /// The quality score of the link, once a probe has been answered. See
+    /// [`mos_score`].
+    pub fn quality(&self) -> Option<f32> {
+        let srtt = self.srtt?;
+        Some(mos_score(srtt / 2, self.jitter, self.loss_percent()))
+    }
+}
+
+/// Estimates the Mean Opinion Score (1.0 to 4.5) of a voice call over a path
+/// with the given one-way delay, jitter and loss, using the simplified
+/// E-model of ITU-T G.107: jitter counts twice towards the delay, as a
+/// jitter buffer would add it, and every percent of loss costs 2.5 R points.
+pub fn mos_score(delay: Duration, jitter: Duration, loss_percent: f32) -> f32 {
+    let effective_ms = delay.as_secs_f32() * 1000.0 + jitter.as_secs_f32() * 2000.0 + 10.0;
+    let r = if effective_ms < 160.0 {
+        93.2 - effective_ms / 40.0
+    } else {
+        93.2 - (effective_ms - 120.0) / 10.0
+    };
+    let r = (r - loss_percent * 2.5).clamp(0.0, 100.0);
+    1.0 + 0.035 * r + 7.0e-6 * r * (r - 60.0) * (100.0 - r)
+}
+
+/// Evidence from the data path that a link has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFailure {
+    /// Data sent on the link went unacknowledged for too long.
+    Unacknowledged,
+    /// Sending data on the link's socket failed.
+    SendError,
+}
+
+/// The outcome of a probe echo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo {
+    /// How long the probe took to come back.
+    pub rtt: Duration,
+    /// The status change the echo caused, if any.
+    pub change: Option<StatusChange>,
+}
+
+/// The health state machine of a single link.
+///
+/// It is driven by events: probes being sent, their echoes arriving, ticks
+/// that expire unanswered probes, and failures seen on the data path. Each
+/// event returns the status change it caused, if any.
+#[derive(Debug, Clone)]
+pub struct LinkMonitor<C = SystemClock> {
+    policy: HealthPolicy,
+    clock: C,
+    /// The current status of the link.
+    pub status: LinkStatus,
+    /// The last measured Round-Trip Time (RTT).
+    pub rtt: Duration,
+    /// Windowed loss and smoothed RTT statistics of recent probes.
+    pub history: ProbeHistory,
+    /// The number of probes sent.
+    pub probes_sent: u64,
+    /// The number of probe echoes received.
+    pub probes_received: u64,
+    /// The number of consecutive probes that have failed (timed out).
+    pub consecutive_failures: u32,
+    /// The number of consecutive probes that were answered.
+    pub consecutive_successes: u32,
+    /// The number of consecutive probes that were answered while the link
+    /// met every Degraded threshold.
+    pub consecutive_good: u32,
+    /// A Degraded or Down link may not recover before this time.
+    pub held_until: Option<Instant>,
+    /// A map of sent probe sequence numbers to the time they were sent.
+    pub in_flight_probes: HashMap<u64, Instant>,
+    /// The next sequence number to use for a probe on this link.
+    pub next_probe_seq: u64,
+    /// When the link last transitioned to Up, used to delay failback.
+    pub up_since: Option<Instant>,
+    /// When a probe on the link was last answered.
+    pub last_success: Option<Instant>,
+    /// The current interval between probes; only changes in adaptive mode.
+    pub probe_interval: Option<Duration>,
+    /// Whether the link failed its last round of extra health checks.
+    pub checks_failing: bool,
+}
+
+impl LinkMonitor {
+    /// Creates a monitor for a link that has not been probed yet.
+    pub fn new(policy: HealthPolicy) -> Self {
+        Self::with_clock(policy, SystemClock)
+    }
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new(HealthPolicy::default())
+    }
+}
+
+impl<C: Clock> LinkMonitor<C> {
+    /// Creates a monitor that reads the time from `clock`.
+    pub fn with_clock(policy: HealthPolicy, clock: C) -> Self {
+        Self {
+            policy,
+            clock,
+            status: LinkStatus::Unknown,
+            rtt: Duration::default(),
+            history: ProbeHistory::new(),
+            probes_sent: 0,
+            probes_received: 0,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            consecutive_good: 0,
+            held_until: None,
+            in_flight_probes: HashMap::new(),
+            next_probe_seq: 0,
+            up_since: None,
+            last_success: None,
+            probe_interval: None,
+            checks_failing: false,
+        }
+    }
+
+    /// The thresholds the link is judged by.
+    pub fn policy(&self) -> &HealthPolicy {
+        &self.policy
+    }
+
+    /// Records a probe about to be sent and returns its sequence number.
+    pub fn on_probe_sent(&mut self) -> u64 {
+        let seq = self.next_probe_seq;
+        self.next_probe_seq = self.next_probe_seq.wrapping_add(1);
+        self.probes_sent += 1;
+        self.in_flight_probes.insert(seq, self.clock.now());
+        seq
+    }
+
+    /// Records that the probe `seq` could not be sent after all.
+    pub fn on_probe_send_failed(&mut self, seq: u64) -> Option<StatusChange> {
+        self.in_flight_probes.remove(&seq)?;
+        self.on_probe_failure()
+    }
+
+    /// Records the echo of probe `seq`. Returns `None` for probes that are
+    /// unknown or already timed out.
+    pub fn on_echo(&mut self, seq: u64) -> Option<Echo> {
+        let sent_at = self.in_flight_probes.remove(&seq)?;
+        let rtt = self.clock.now().saturating_duration_since(sent_at);
+        let change = self.on_probe_success(rtt);
+        Some(Echo { rtt, change })
+    }
+
+    /// Expires probes that have gone unanswered for longer than the probe
+    /// timeout. Returns each of them with the status change it caused.
+    pub fn on_tick(&mut self) -> Vec<(u64, Option<StatusChange>)> {
+        let now = self.clock.now();
+        let mut timed_out: Vec<u64> = self
+            .in_flight_probes
+            .iter()
+            .filter(|(_, &sent_at)| {
+                now.saturating_duration_since(sent_at) > self.policy.probe_timeout
+            })
+            .map(|(&seq, _)| seq)
+            .collect();
+        timed_out.sort_unstable();
+        timed_out
+            .into_iter()
+            .map(|seq| {
+                self.in_flight_probes.remove(&seq);
+                (seq, self.on_probe_failure())
+            })
+            .collect()
+    }
+
+    /// Whether the link's recent probes are past any Degraded threshold.
+    pub fn is_impaired(&self) -> bool {
+        (self.history.len() >= MIN_LOSS_SAMPLES
+            && self.history.loss_percent() > self.policy.degraded_loss_percent)
+            || self
+                .history
+                .srtt()
+                .is_some_and(|srtt| srtt > self.policy.degraded_rtt)
+            || self.history.jitter() > self.policy.degraded_jitter
+            || (self.history.len() >= MIN_LOSS_SAMPLES
+                && self
+                    .history
+                    .quality()
+                    .is_some_and(|quality| quality < self.policy.degraded_quality))
+    }
+
+    fn set_status(&mut self, status: LinkStatus, now: Instant) -> Option<StatusChange> {
+        let old = self.status;
+        if old == status {
+            return None;
+        }
+        self.status = status;
+        self.up_since = (status == LinkStatus::Up).then_some(now);
+        if matches!(status, LinkStatus::Degraded | LinkStatus::Down) {
+            self.held_until = Some(now + self.policy.recovery_hold);
+        }
+        Some((old, status))
+    }
+
+    fn may_recover(&self, probes: u32, now: Instant) -> bool {
+        probes >= self.policy.recovery_probes && self.held_until.is_none_or(|until| now >= until)
+    }
+
+    /// Updates the link after a probe was answered after `rtt`.
+    ///
+    /// A link leaves Down once `recovery_probes` probes in a row were
+    /// answered, and Degraded once as many were good, in both cases only
+    /// after the hold-down time has passed. An Up link becomes Degraded as
+    /// soon as it is impaired.
+    fn on_probe_success(&mut self, rtt: Duration) -> Option<StatusChange> {
+        let now = self.clock.now();
+        self.record_probe_rtt(rtt);
+        self.last_success = Some(now);
+        self.consecutive_failures = 0;
+        self.consecutive_successes += 1;
+        let impaired = self.is_impaired();
+        if impaired {
+            self.consecutive_good = 0;
+            if self.status != LinkStatus::Up {
+                self.held_until = Some(now + self.policy.recovery_hold);
+            }
+        } else {
+            self.consecutive_good += 1;
+        }
+        let healthy = if impaired {
+            LinkStatus::Degraded
+        } else {
+            LinkStatus::Up
+        };
+
+        let next = match self.status {
+            _ if self.checks_failing => self.status,
+            LinkStatus::Unknown | LinkStatus::Up => healthy,
+            LinkStatus::Down if self.may_recover(self.consecutive_successes, now) => healthy,
+            LinkStatus::Degraded if self.may_recover(self.consecutive_good, now) => LinkStatus::Up,
+            status => status,
+        };
+        self.set_status(next, now)
+    }
+
+    /// Updates the link after a probe timed out or could not be sent.
+    fn on_probe_failure(&mut self) -> Option<StatusChange> {
+        let now = self.clock.now();
+        self.record_probe_loss();
+        self.consecutive_successes = 0;
+        self.consecutive_good = 0;
+        if self.status != LinkStatus::Up {
+            self.held_until = Some(now + self.policy.recovery_hold);
+        }
+        let next = if self.consecutive_failures >= self.policy.down_after_failures {
+            LinkStatus::Down
+        } else if self.status == LinkStatus::Up && self.is_impaired() {
+            LinkStatus::Degraded
+        } else {
+            self.status
+        };
+        self.set_status(next, now)
+    }
+
+    /// Returns how long to wait before the next probe, given whether the link
+    /// carried any data since the previous one.
+    ///
+    /// Without adaptive probing this is always the configured interval. With
+    /// it, a link that is Up and idle doubles its interval on every probe up
+    /// to the maximum, a busy one probes at the configured interval, and any
+    /// recent loss or a status other than Up drops straight to the minimum.
+    pub fn next_probe_interval(&mut self, idle: bool) -> Duration {
+        let policy = &self.policy;
+        if !policy.adaptive_probing {
+            return policy.probe_interval;
+        }
+        let lossy = self.consecutive_failures > 0 || self.history.loss_percent() > 0.0;
+        let interval = if self.status != LinkStatus::Up || lossy {
+            policy.min_probe_interval
+        } else if idle {
+            self.probe_interval
+                .map_or(policy.probe_interval, |interval| {
+                    (interval * 2).max(policy.probe_interval)
+                })
+                .min(policy.max_probe_interval)
+        } else {
+            policy.probe_interval
+        };
+        self.probe_interval = Some(interval);
+        interval
+    }
+
+    /// How long data sent on the link may go unacknowledged: the RTT timeout
+    /// (RFC 6298) but no less than the configured minimum, plus the time the
+    /// server may hold back an acknowledgement.
+    pub fn stall_timeout(&self) -> Duration {
+        let rto = self
+            .history
+            .srtt()
+            .map_or(Duration::ZERO, |srtt| srtt + self.history.rttvar() * 4);
+        rto.max(self.policy.passive_timeout) + DATA_ACK_INTERVAL * 2
+    }
+
+    /// Updates the link after the data path saw it fail. Unacknowledged data
+    /// takes the link Down at once; send errors count like lost probes,
+    /// without entering the probe loss window.
+    pub fn on_data_failure(&mut self, failure: DataFailure) -> Option<StatusChange> {
+        self.consecutive_successes = 0;
+        self.consecutive_good = 0;
+        self.consecutive_failures = match failure {
+            DataFailure::Unacknowledged => self
+                .consecutive_failures
+                .max(self.policy.down_after_failures),
+            DataFailure::SendError => self.consecutive_failures + 1,
+        };
+        if self.consecutive_failures >= self.policy.down_after_failures {
+            self.set_status(LinkStatus::Down, self.clock.now())
+        } else {
+            None
+        }
+    }
+
+    /// Records the outcome of a round of the link's extra health checks. A
+    /// link that fails any check is Down; once all pass again it needs
+    /// `recovery_probes` answered probes to recover, like any other Down link.
+    pub fn on_checks(&mut self, passed: bool) -> Option<StatusChange> {
+        let was_failing = std::mem::replace(&mut self.checks_failing, !passed);
+        if passed && !was_failing {
+            return None;
+        }
+        self.consecutive_successes = 0;
+        self.consecutive_good = 0;
+        if passed {
+            return None;
+        }
+        self.set_status(LinkStatus::Down, self.clock.now())
+    }
+
+    /// Probe loss over the last `PROBE_WINDOW` probes, in percent.
+    pub fn packet_loss_percent(&self) -> f32 {
+        self.history.loss_percent()
+    }
+
+    fn record_probe_rtt(&mut self, rtt: Duration) {
+        self.probes_received += 1;
+        self.rtt = rtt;
+        self.history.record_rtt(rtt);
+    }
+
+    fn record_probe_loss(&mut self) {
+        self.consecutive_failures += 1;
+        self.history.record_loss();
+    }
+
+    /// A snapshot of the link's health, for reporting.
+    pub fn health(&self) -> LinkHealth {
+        let now = self.clock.now();
+        LinkHealth {
+            status: self.status,
+            rtt_ms: self.history.srtt().map(|srtt| srtt.as_millis() as u32),
+            packet_loss: (!self.history.is_empty()).then(|| self.history.loss_percent()),
+            quality: self.history.quality(),
+            last_success: self.last_success.and_then(|at| {
+                let since = now.saturating_duration_since(at);
+                let wall = SystemTime::now().checked_sub(since)?;
+                Some(wall.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+            }),
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD_RTT: Duration = Duration::from_millis(20);
+
+    fn test_policy() -> HealthPolicy {
+        HealthPolicy {
+            probe_interval: Duration::from_millis(500),
+            probe_timeout: Duration::from_secs(2),
+            adaptive_probing: false,
+            min_probe_interval: Duration::from_millis(200),
+            max_probe_interval: Duration::from_secs(5),
+            down_after_failures: 3,
+            degraded_rtt: Duration::from_millis(200),
+            degraded_jitter: Duration::from_millis(1000),
+            degraded_loss_percent: 50.0,
+            degraded_quality: 0.0,
+            recovery_probes: 3,
+            recovery_hold: Duration::from_secs(10),
+            degraded_weight_percent: 25,
+            passive_monitoring: true,
+            passive_timeout: Duration::from_secs(1),
+            suppress_probes: false,
+        }
+    }
+
+    fn monitor(policy: HealthPolicy) -> (LinkMonitor<ManualClock>, ManualClock) {
+        let clock = ManualClock::new();
+        (LinkMonitor::with_clock(policy, clock.clone()), clock)
+    }
+
+    /// Sends a probe and answers it after `rtt`.
+    fn answer(
+        monitor: &mut LinkMonitor<ManualClock>,
+        clock: &ManualClock,
+        rtt: Duration,
+    ) -> Option<StatusChange> {
+        let seq = monitor.on_probe_sent();
+        clock.advance(rtt);
+        monitor.on_echo(seq).unwrap().change
+    }
+
+    fn assert_ms(actual: Duration, expected_ms: f64) {
+        let actual_ms = actual.as_secs_f64() * 1000.0;
+        assert!(
+            (actual_ms - expected_ms).abs() < 0.001,
+            "{actual_ms} != {expected_ms}"
+        );
+    }
+
+    #[test]
+    fn probe_loss_is_windowed() {
+        let (mut monitor, _) = monitor(test_policy());
+        assert_eq!(monitor.packet_loss_percent(), 0.0);
+        for _ in 0..1000 {
+            monitor.record_probe_rtt(GOOD_RTT);
+        }
+        for _ in 0..PROBE_WINDOW / 2 {
+            monitor.record_probe_loss();
+            monitor.record_probe_rtt(GOOD_RTT);
+        }
+        assert_eq!(monitor.packet_loss_percent(), 50.0);
+        assert_eq!(monitor.probes_received, 1000 + PROBE_WINDOW as u64 / 2);
+        assert_eq!(monitor.consecutive_failures, PROBE_WINDOW as u32 / 2);
+    }
+
+    #[test]
+    fn smoothed_rtt_and_variance_follow_rfc6298() {
+        let mut history = ProbeHistory::new();
+        assert_eq!(history.srtt(), None);
+        history.record_rtt(Duration::from_millis(100));
+        assert_ms(history.srtt().unwrap(), 100.0);
+        assert_ms(history.rttvar(), 50.0);
+
+        history.record_rtt(Duration::from_millis(180));
+        assert_ms(history.srtt().unwrap(), 110.0);
+        assert_ms(history.rttvar(), 57.5);
+    }
+
+    #[test]
+    fn jitter_tracks_rtt_changes_between_probes() {
+        let mut history = ProbeHistory::new();
+        for _ in 0..10 {
+            history.record_rtt(Duration::from_millis(50));
+        }
+        assert_eq!(history.jitter(), Duration::ZERO);
+        history.record_rtt(Duration::from_millis(130));
+        assert_ms(history.jitter(), 5.0);
+        history.record_loss();
+        assert_ms(history.jitter(), 5.0);
+    }
+
+    #[test]
+    fn min_rtt_only_covers_the_window() {
+        let mut history = ProbeHistory::new();
+        history.record_rtt(Duration::from_millis(10));
+        history.record_loss();
+        history.record_rtt(Duration::from_millis(30));
+        assert_eq!(history.min_rtt(), Some(Duration::from_millis(10)));
+        for _ in 0..PROBE_WINDOW - 1 {
+            history.record_rtt(Duration::from_millis(40));
+        }
+        assert_eq!(history.min_rtt(), Some(Duration::from_millis(30)));
+        for _ in 0..PROBE_WINDOW {
+            history.record_loss();
+        }
+        assert_eq!(history.min_rtt(), None);
+        assert_eq!(history.loss_percent(), 100.0);
+    }
+
+    #[test]
+    fn echoes_measure_rtt_and_unknown_echoes_are_ignored() {
+        let (mut monitor, clock) = monitor(test_policy());
+        let seq = monitor.on_probe_sent();
+        clock.advance(Duration::from_millis(35));
+        assert_eq!(
+            monitor.on_echo(seq),
+            Some(Echo {
+                rtt: Duration::from_millis(35),
+                change: Some((LinkStatus::Unknown, LinkStatus::Up)),
+            })
+        );
+        assert_eq!(monitor.on_echo(seq), None);
+        assert_eq!(monitor.on_echo(seq + 1), None);
+        assert_eq!(monitor.up_since, Some(clock.now()));
+    }
+
+    #[test]
+    fn unanswered_probes_time_out_on_tick() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        answer(&mut monitor, &clock, GOOD_RTT);
+
+        let mut changes = Vec::new();
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            sent.push(monitor.on_probe_sent());
+            clock.advance(policy.probe_interval);
+            changes.extend(monitor.on_tick());
+        }
+        assert!(changes.is_empty(), "no probe is older than the timeout yet");
+
+        // Probes keep going out every interval. Each one times out on the
+        // first tick more than 2 s after it was sent, and the third loss takes
+        // the link down, right after the eighth probe overall went out.
+        let mut went_down = None;
+        for _ in 0..20 {
+            sent.push(monitor.on_probe_sent());
+            clock.advance(policy.probe_interval);
+            for (seq, change) in monitor.on_tick() {
+                assert!(sent.contains(&seq));
+                if change == Some((LinkStatus::Up, LinkStatus::Down)) {
+                    went_down.get_or_insert(monitor.probes_sent);
+                }
+            }
+        }
+        assert_eq!(went_down, Some(8));
+        assert_eq!(monitor.status, LinkStatus::Down);
+        assert!(monitor.in_flight_probes.len() <= 4);
+    }
+
+    #[test]
+    fn link_goes_down_and_recovers_only_after_hold_down() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        assert_eq!(
+            answer(&mut monitor, &clock, GOOD_RTT),
+            Some((LinkStatus::Unknown, LinkStatus::Up))
+        );
+
+        let mut changes = Vec::new();
+        for _ in 0..3 {
+            let seq = monitor.on_probe_sent();
+            changes.push(monitor.on_probe_send_failed(seq));
+        }
+        assert_eq!(
+            changes,
+            vec![None, None, Some((LinkStatus::Up, LinkStatus::Down))]
+        );
+        assert_eq!(monitor.up_since, None);
+
+        // Enough answered probes, but still within the hold-down time.
+        for _ in 0..5 {
+            assert_eq!(answer(&mut monitor, &clock, GOOD_RTT), None);
+        }
+        clock.advance(policy.recovery_hold);
+        assert_eq!(
+            answer(&mut monitor, &clock, GOOD_RTT),
+            Some((LinkStatus::Down, LinkStatus::Up))
+        );
+    }
+
+    #[test]
+    fn a_failure_restarts_recovery() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        monitor.status = LinkStatus::Down;
+        answer(&mut monitor, &clock, GOOD_RTT);
+        answer(&mut monitor, &clock, GOOD_RTT);
+        clock.advance(Duration::from_secs(5));
+        let seq = monitor.on_probe_sent();
+        monitor.on_probe_send_failed(seq);
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(answer(&mut monitor, &clock, GOOD_RTT), None);
+        assert_eq!(answer(&mut monitor, &clock, GOOD_RTT), None);
+        assert_eq!(
+            answer(&mut monitor, &clock, GOOD_RTT),
+            Some((LinkStatus::Down, LinkStatus::Up))
+        );
+    }
+
+    #[test]
+    fn high_rtt_degrades_with_hysteresis() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        answer(&mut monitor, &clock, GOOD_RTT);
+
+        let mut change = None;
+        for _ in 0..50 {
+            change = change.or(answer(&mut monitor, &clock, Duration::from_millis(500)));
+        }
+        assert_eq!(change, Some((LinkStatus::Up, LinkStatus::Degraded)));
+
+        // Good probes only count once the smoothed RTT is back under the
+        // threshold, and every impaired probe restarts the hold-down.
+        let start = clock.now();
+        let mut last_impaired = start;
+        while monitor.status == LinkStatus::Degraded {
+            clock.advance(Duration::from_secs(1) - GOOD_RTT);
+            answer(&mut monitor, &clock, GOOD_RTT);
+            if monitor.consecutive_good == 0 {
+                last_impaired = clock.now();
+            }
+            assert!(clock.now() < start + Duration::from_secs(100));
+        }
+        assert_eq!(monitor.status, LinkStatus::Up);
+        assert_eq!(clock.now(), last_impaired + policy.recovery_hold);
+        assert!(monitor.consecutive_good >= policy.recovery_probes);
+    }
+
+    #[test]
+    fn windowed_loss_degrades_an_up_link() {
+        let policy = HealthPolicy {
+            degraded_loss_percent: 35.0,
+            ..test_policy()
+        };
+        let (mut monitor, clock) = monitor(policy);
+        let lose = |monitor: &mut LinkMonitor<ManualClock>| {
+            let seq = monitor.on_probe_sent();
+            monitor.on_probe_send_failed(seq)
+        };
+        answer(&mut monitor, &clock, GOOD_RTT);
+        lose(&mut monitor);
+        assert_eq!(
+            monitor.status,
+            LinkStatus::Up,
+            "too few probes to judge loss"
+        );
+        for _ in 0..3 {
+            answer(&mut monitor, &clock, GOOD_RTT);
+        }
+        for _ in 0..2 {
+            lose(&mut monitor);
+        }
+        for _ in 0..3 {
+            answer(&mut monitor, &clock, GOOD_RTT);
+        }
+        assert_eq!(monitor.status, LinkStatus::Up);
+        lose(&mut monitor);
+        assert!(monitor.history.loss_percent() > 35.0);
+        assert_eq!(monitor.status, LinkStatus::Degraded);
+        assert_eq!(
+            answer(&mut monitor, &clock, GOOD_RTT),
+            None,
+            "loss is still above the threshold"
+        );
+    }
+
+    #[test]
+    fn probe_interval_is_fixed_unless_adaptive() {
+        let (mut monitor, clock) = monitor(test_policy());
+        answer(&mut monitor, &clock, GOOD_RTT);
+        for _ in 0..5 {
+            assert_eq!(
+                monitor.next_probe_interval(true),
+                Duration::from_millis(500)
+            );
+        }
+    }
+
+    #[test]
+    fn adaptive_probing_backs_off_when_idle_and_speeds_up_on_loss() {
+        let policy = HealthPolicy {
+            adaptive_probing: true,
+            ..test_policy()
+        };
+        let (mut monitor, clock) = monitor(policy);
+        assert_eq!(monitor.next_probe_interval(true), policy.min_probe_interval);
+
+        answer(&mut monitor, &clock, GOOD_RTT);
+        let idle: Vec<u64> = (0..6)
+            .map(|_| monitor.next_probe_interval(true).as_millis() as u64)
+            .collect();
+        assert_eq!(idle, vec![500, 1000, 2000, 4000, 5000, 5000]);
+        assert_eq!(monitor.next_probe_interval(false), policy.probe_interval);
+
+        let seq = monitor.on_probe_sent();
+        monitor.on_probe_send_failed(seq);
+        assert_eq!(monitor.next_probe_interval(true), policy.min_probe_interval);
+    }
+
+    #[test]
+    fn data_failures_take_a_link_down() {
+        let (mut monitor, clock) = monitor(test_policy());
+        answer(&mut monitor, &clock, GOOD_RTT);
+
+        assert_eq!(monitor.on_data_failure(DataFailure::SendError), None);
+        monitor.on_data_failure(DataFailure::SendError);
+        assert_eq!(
+            monitor.on_data_failure(DataFailure::SendError),
+            Some((LinkStatus::Up, LinkStatus::Down))
+        );
+        // Send errors don't count as probe loss.
+        assert_eq!(monitor.packet_loss_percent(), 0.0);
+
+        let (mut monitor, clock) = self::monitor(test_policy());
+        answer(&mut monitor, &clock, GOOD_RTT);
+        assert_eq!(
+            monitor.on_data_failure(DataFailure::Unacknowledged),
+            Some((LinkStatus::Up, LinkStatus::Down))
+        );
+    }
+
+    #[test]
+    fn stall_timeout_follows_rtt_above_the_minimum() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        let slack = DATA_ACK_INTERVAL * 2;
+        assert_eq!(monitor.stall_timeout(), policy.passive_timeout + slack);
+        for _ in 0..20 {
+            answer(&mut monitor, &clock, Duration::from_millis(1200));
+        }
+        assert!(monitor.stall_timeout() > Duration::from_millis(1200) + slack);
+    }
+
+    #[test]
+    fn quality_scores_follow_the_e_model() {
+        let clean = mos_score(Duration::from_millis(20), Duration::ZERO, 0.0);
+        assert!((clean - 4.4).abs() < 0.05, "{clean}");
+        let lossy = mos_score(Duration::from_millis(20), Duration::ZERO, 10.0);
+        let slow = mos_score(Duration::from_millis(400), Duration::from_millis(30), 0.0);
+        assert!(lossy < 3.6 && lossy > 3.3, "{lossy}");
+        assert!(slow < 3.1, "{slow}");
+        assert_eq!(
+            mos_score(Duration::from_secs(5), Duration::ZERO, 100.0),
+            1.0
+        );
+        assert_eq!(ProbeHistory::new().quality(), None);
+    }
+
+    #[test]
+    fn low_quality_degrades_a_link() {
+        let policy = HealthPolicy {
+            degraded_rtt: Duration::from_secs(10),
+            degraded_quality: 4.0,
+            ..test_policy()
+        };
+        let (mut monitor, clock) = monitor(policy);
+        let rtt = Duration::from_millis(600);
+        let changes: Vec<_> = (0..MIN_LOSS_SAMPLES)
+            .filter_map(|_| answer(&mut monitor, &clock, rtt))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (LinkStatus::Unknown, LinkStatus::Up),
+                (LinkStatus::Up, LinkStatus::Degraded)
+            ]
+        );
+        assert!(monitor.health().quality.unwrap() < 4.0);
+    }
+
+    #[test]
+    fn failing_checks_keep_a_link_down_despite_probes() {
+        let policy = test_policy();
+        let (mut monitor, clock) = monitor(policy);
+        answer(&mut monitor, &clock, GOOD_RTT);
+        assert_eq!(
+            monitor.on_checks(false),
+            Some((LinkStatus::Up, LinkStatus::Down))
+        );
+        clock.advance(policy.recovery_hold);
+        for _ in 0..5 {
+            assert_eq!(answer(&mut monitor, &clock, GOOD_RTT), None);
+        }
+
+        assert_eq!(monitor.on_checks(true), None);
+        assert_eq!(monitor.status, LinkStatus::Down);
+        let changes: Vec<_> = (0..3)
+            .map(|_| answer(&mut monitor, &clock, GOOD_RTT))
+            .collect();
+        assert_eq!(changes[..2], [None, None]);
+        assert_eq!(changes[2], Some((LinkStatus::Down, LinkStatus::Up)));
+    }
+
+    #[test]
+    fn health_snapshot_reports_the_link_state() {
+        let (mut monitor, clock) = monitor(test_policy());
+        let health = monitor.health();
+        assert_eq!(health.status, LinkStatus::Unknown);
+        assert_eq!(health.rtt_ms, None);
+        assert_eq!(health.packet_loss, None);
+        assert_eq!(health.last_success, None);
+
+        answer(&mut monitor, &clock, Duration::from_millis(40));
+        let health = monitor.health();
+        assert_eq!(health.status, LinkStatus::Up);
+        assert_eq!(health.rtt_ms, Some(40));
+        assert_eq!(health.packet_loss, Some(0.0));
+        assert!(health.last_success.is_some());
+        assert_eq!(health.consecutive_failures, 0);
+    }
+}
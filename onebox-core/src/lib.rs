@@ -4,10 +4,15 @@
 //! This library provides the fundamental data structures, networking primitives,
 //! and utilities needed by both the client and server components.
 
+pub mod bandwidth;
 pub mod config;
+pub mod control;
 pub mod crypto;
 pub mod error;
+pub mod health;
+pub mod netconfig;
 pub mod packet;
+pub mod scheduler;
 pub mod types;
 
 pub use error::{OneboxError, OneboxResult};
@@ -0,0 +1,918 @@
+//! Native network configuration: links, addresses, routes and policy rules
+//! over rtnetlink, NAT through nftables JSON batches, sysctls through
+//! `/proc/sys`, and plain files such as `/etc/resolv.conf`.
+//!
+//! Changes are made in a [`Transaction`], which remembers how to undo each
+//! one. When a step fails, rolling the transaction back undoes the steps
+//! before it; once committed, the returned [`Change`]s can be reverted when
+//! the program exits.
+
+use crate::error::{OneboxError, OneboxResult};
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::link::Nla as LinkNla;
+use netlink_packet_route::nlas::route::Nla as RouteNla;
+use netlink_packet_route::nlas::rule::Nla as RuleNla;
+use netlink_packet_route::{
+    RouteMessage, RuleMessage, AF_INET, AF_INET6, FR_ACT_TO_TBL, IFA_F_NODAD, RTN_UNICAST,
+    RTPROT_STATIC, RT_SCOPE_LINK, RT_SCOPE_NOWHERE, RT_SCOPE_UNIVERSE, RT_TABLE_UNSPEC,
+};
+use nix::libc::{EADDRNOTAVAIL, ENODEV, ENOENT, ESRCH};
+use rtnetlink::{Handle, IpVersion};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::process::{Command, Stdio};
+use tracing::{debug, warn};
+
+/// The main routing table.
+pub const MAIN_TABLE: u32 = 254;
+
+/// The nftables table, in each family, holding onebox's NAT rules.
+const NFT_TABLE: &str = "onebox";
+
+/// An IP address family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub fn of(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => Family::Ipv4,
+            IpAddr::V6(_) => Family::Ipv6,
+        }
+    }
+
+    fn af(self) -> u8 {
+        match self {
+            Family::Ipv4 => AF_INET as u8,
+            Family::Ipv6 => AF_INET6 as u8,
+        }
+    }
+
+    fn ip_version(self) -> IpVersion {
+        match self {
+            Family::Ipv4 => IpVersion::V4,
+            Family::Ipv6 => IpVersion::V6,
+        }
+    }
+
+    fn host_prefix_len(self) -> u8 {
+        match self {
+            Family::Ipv4 => 32,
+            Family::Ipv6 => 128,
+        }
+    }
+}
+
+/// A route, as `ip route` shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// The interface the route leaves through.
+    pub dev: Option<String>,
+    pub table: u32,
+    /// The route's priority among routes to the same destination; lower
+    /// wins. The kernel's default when `None`.
+    #[serde(default)]
+    pub metric: Option<u32>,
+}
+
+impl Route {
+    /// A route to `destination/prefix_len` in the main table.
+    pub fn new(destination: IpAddr, prefix_len: u8) -> Self {
+        Self {
+            destination,
+            prefix_len,
+            gateway: None,
+            dev: None,
+            table: MAIN_TABLE,
+            metric: None,
+        }
+    }
+
+    /// A default route of the family in the main table.
+    pub fn default_for(family: Family) -> Self {
+        match family {
+            Family::Ipv4 => Self::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            Family::Ipv6 => Self::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        }
+    }
+
+    pub fn via(mut self, gateway: Option<IpAddr>) -> Self {
+        self.gateway = gateway;
+        self
+    }
+
+    pub fn dev(mut self, dev: &str) -> Self {
+        self.dev = Some(dev.to_string());
+        self
+    }
+
+    pub fn table(mut self, table: u32) -> Self {
+        self.table = table;
+        self
+    }
+
+    pub fn metric(mut self, metric: u32) -> Self {
+        self.metric = Some(metric);
+        self
+    }
+
+    /// A route to a single address.
+    pub fn host(address: IpAddr) -> Self {
+        Self::new(address, Family::of(address).host_prefix_len())
+    }
+
+    pub fn family(&self) -> Family {
+        Family::of(self.destination)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.destination, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+        if let Some(dev) = &self.dev {
+            write!(f, " dev {dev}")?;
+        }
+        if let Some(metric) = self.metric {
+            write!(f, " metric {metric}")?;
+        }
+        if self.table != MAIN_TABLE {
+            write!(f, " table {}", self.table)?;
+        }
+        Ok(())
+    }
+}
+
+/// A policy routing rule sending traffic from a source address, or with a
+/// firewall mark, to a routing table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub family: Family,
+    pub priority: u32,
+    pub table: u32,
+    pub from: Option<IpAddr>,
+    pub fwmark: Option<u32>,
+}
+
+/// Source NAT of a network behind an interface's address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Masquerade {
+    pub source: IpAddr,
+    pub prefix_len: u8,
+    /// The interface whose address traffic leaves with.
+    pub oif: String,
+}
+
+/// A change made to the system's network configuration, holding what it
+/// takes to undo it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    Address {
+        dev: String,
+        address: IpAddr,
+        prefix_len: u8,
+    },
+    Route(Route),
+    Rule(Rule),
+    Sysctl {
+        key: String,
+        previous: String,
+    },
+    Masquerade(Masquerade),
+    /// A file such as `/etc/resolv.conf` was written. It is put back as it
+    /// was, or removed if there was none.
+    File {
+        path: String,
+        previous: Option<String>,
+    },
+}
+
+/// A connection to the kernel's routing subsystem.
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    handle: Handle,
+}
+
+impl NetConfig {
+    /// Opens an rtnetlink socket. Must be called within a Tokio runtime.
+    pub fn new() -> OneboxResult<Self> {
+        let (connection, handle, _) = rtnetlink::new_connection()
+            .map_err(|e| system("Failed to open rtnetlink socket", e))?;
+        tokio::spawn(connection);
+        Ok(Self { handle })
+    }
+
+    /// Starts a set of changes that are undone together.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            net: self,
+            changes: Vec::new(),
+        }
+    }
+
+    /// The index of the named interface, or `None` if there is none.
+    pub async fn link_index(&self, name: &str) -> OneboxResult<Option<u32>> {
+        let result = self
+            .handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute()
+            .try_next()
+            .await;
+        match result {
+            Ok(link) => Ok(link.map(|link| link.header.index)),
+            Err(e) if errno(&e) == Some(ENODEV) => Ok(None),
+            Err(e) => Err(system(format!("Failed to look up interface {name}"), e)),
+        }
+    }
+
+    async fn require_link(&self, name: &str) -> OneboxResult<u32> {
+        self.link_index(name)
+            .await?
+            .ok_or_else(|| OneboxError::System(format!("No interface named {name}")))
+    }
+
+    /// The name of the interface with the given index.
+    pub async fn link_name(&self, index: u32) -> OneboxResult<Option<String>> {
+        let result = self
+            .handle
+            .link()
+            .get()
+            .match_index(index)
+            .execute()
+            .try_next()
+            .await;
+        match result {
+            Ok(link) => Ok(link.and_then(|link| {
+                link.nlas.into_iter().find_map(|nla| match nla {
+                    LinkNla::IfName(name) => Some(name),
+                    _ => None,
+                })
+            })),
+            Err(e) if errno(&e) == Some(ENODEV) => Ok(None),
+            Err(e) => Err(system(format!("Failed to look up interface {index}"), e)),
+        }
+    }
+
+    /// Deletes an interface. Returns whether there was one to delete.
+    pub async fn delete_link(&self, name: &str) -> OneboxResult<bool> {
+        let Some(index) = self.link_index(name).await? else {
+            return Ok(false);
+        };
+        match self.handle.link().del(index).execute().await {
+            Ok(()) => Ok(true),
+            Err(e) if gone(&e) => Ok(false),
+            Err(e) => Err(system(format!("Failed to delete interface {name}"), e)),
+        }
+    }
+
+    /// Adds an address to an interface. IPv6 addresses skip duplicate
+    /// address detection, as they go on point-to-point tunnels.
+    pub async fn add_address(
+        &self,
+        dev: &str,
+        address: IpAddr,
+        prefix_len: u8,
+    ) -> OneboxResult<()> {
+        let index = self.require_link(dev).await?;
+        let mut request = self.handle.address().add(index, address, prefix_len);
+        if address.is_ipv6() {
+            request.message_mut().header.flags |= IFA_F_NODAD as u8;
+        }
+        request
+            .execute()
+            .await
+            .map_err(|e| system(format!("Failed to add {address}/{prefix_len} to {dev}"), e))
+    }
+
+    /// Removes an address from an interface. Succeeds if it is already gone.
+    pub async fn delete_address(
+        &self,
+        dev: &str,
+        address: IpAddr,
+        prefix_len: u8,
+    ) -> OneboxResult<()> {
+        let Some(index) = self.link_index(dev).await? else {
+            return Ok(());
+        };
+        let mut request = self.handle.address().add(index, address, prefix_len);
+        let message = request.message_mut().clone();
+        match self.handle.address().del(message).execute().await {
+            Err(e) if !gone(&e) => Err(system(
+                format!("Failed to remove {address}/{prefix_len} from {dev}"),
+                e,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a route. Fails if the same route exists.
+    pub async fn add_route(&self, route: &Route) -> OneboxResult<()> {
+        let oif = match &route.dev {
+            Some(dev) => Some(self.require_link(dev).await?),
+            None => None,
+        };
+        let mut request = self.handle.route().add();
+        *request.message_mut() = route_message(route, oif);
+        request
+            .execute()
+            .await
+            .map_err(|e| system(format!("Failed to add route {route}"), e))
+    }
+
+    /// Deletes a route. Succeeds if it is already gone.
+    pub async fn delete_route(&self, route: &Route) -> OneboxResult<()> {
+        let oif = match &route.dev {
+            Some(dev) => match self.link_index(dev).await? {
+                Some(index) => Some(index),
+                // Routes go with their interface.
+                None => return Ok(()),
+            },
+            None => None,
+        };
+        let mut message = route_message(route, oif);
+        // Match the route whatever its scope and protocol.
+        message.header.scope = RT_SCOPE_NOWHERE;
+        message.header.protocol = 0;
+        match self.handle.route().del(message).execute().await {
+            Err(e) if !gone(&e) => Err(system(format!("Failed to delete route {route}"), e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn route_messages(&self, family: Family) -> OneboxResult<Vec<RouteMessage>> {
+        self.handle
+            .route()
+            .get(family.ip_version())
+            .execute()
+            .try_collect()
+            .await
+            .map_err(|e| system("Failed to list routes", e))
+    }
+
+    /// The default route of the family in the main table with the lowest
+    /// metric, optionally only among those through `dev`. This is the route
+    /// traffic to the internet takes.
+    pub async fn default_route(
+        &self,
+        family: Family,
+        dev: Option<&str>,
+    ) -> OneboxResult<Option<Route>> {
+        let oif = match dev {
+            Some(dev) => Some(self.require_link(dev).await?),
+            None => None,
+        };
+        let best = self
+            .route_messages(family)
+            .await?
+            .iter()
+            .filter_map(parse_route)
+            .filter(|entry| entry.route.prefix_len == 0 && entry.route.table == MAIN_TABLE)
+            .filter(|entry| oif.is_none() || entry.oif == oif)
+            .min_by_key(|entry| entry.route.metric.unwrap_or(0));
+        let Some(RouteEntry { mut route, oif }) = best else {
+            return Ok(None);
+        };
+        if let Some(oif) = oif {
+            route.dev = self.link_name(oif).await?;
+        }
+        Ok(Some(route))
+    }
+
+    /// Deletes every route in a routing table, in both families.
+    pub async fn flush_table(&self, table: u32) -> OneboxResult<()> {
+        for family in [Family::Ipv4, Family::Ipv6] {
+            for message in self.route_messages(family).await? {
+                if parse_route(&message).is_some_and(|entry| entry.route.table == table) {
+                    if let Err(e) = self.handle.route().del(message).execute().await {
+                        if !gone(&e) {
+                            return Err(system(format!("Failed to flush table {table}"), e));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a policy routing rule.
+    pub async fn add_rule(&self, rule: &Rule) -> OneboxResult<()> {
+        let mut request = self.handle.rule().add();
+        *request.message_mut() = rule_message(rule);
+        request.execute().await.map_err(|e| {
+            system(
+                format!("Failed to add rule at priority {}", rule.priority),
+                e,
+            )
+        })
+    }
+
+    /// Deletes a policy routing rule. Succeeds if it is already gone.
+    pub async fn delete_rule(&self, rule: &Rule) -> OneboxResult<()> {
+        match self.handle.rule().del(rule_message(rule)).execute().await {
+            Err(e) if !gone(&e) => Err(system(
+                format!("Failed to delete rule at priority {}", rule.priority),
+                e,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes every rule, of both families, whose priority is in the range.
+    /// Returns the priorities of the rules deleted.
+    pub async fn delete_rules(&self, priorities: RangeInclusive<u32>) -> OneboxResult<Vec<u32>> {
+        let mut deleted = Vec::new();
+        for family in [Family::Ipv4, Family::Ipv6] {
+            let rules: Vec<RuleMessage> = self
+                .handle
+                .rule()
+                .get(family.ip_version())
+                .execute()
+                .try_collect()
+                .await
+                .map_err(|e| system("Failed to list rules", e))?;
+            for rule in rules {
+                let Some(priority) = rule_priority(&rule) else {
+                    continue;
+                };
+                if !priorities.contains(&priority) {
+                    continue;
+                }
+                match self.handle.rule().del(rule).execute().await {
+                    Ok(()) => deleted.push(priority),
+                    Err(e) if gone(&e) => {}
+                    Err(e) => {
+                        return Err(system(
+                            format!("Failed to delete rule at priority {priority}"),
+                            e,
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Undoes a change. Succeeds if there is nothing left to undo.
+    pub async fn revert(&self, change: &Change) -> OneboxResult<()> {
+        match change {
+            Change::Address {
+                dev,
+                address,
+                prefix_len,
+            } => self.delete_address(dev, *address, *prefix_len).await,
+            Change::Route(route) => self.delete_route(route).await,
+            Change::Rule(rule) => self.delete_rule(rule).await,
+            Change::Sysctl { key, previous } => set_sysctl(key, previous),
+            Change::Masquerade(masquerade) => delete_masquerade(Family::of(masquerade.source)),
+            Change::File { path, previous } => restore_file(path, previous.as_deref()),
+        }
+    }
+
+    /// Undoes changes, latest first. Failures are logged and skipped, so
+    /// that as much as possible is undone.
+    pub async fn revert_all(&self, changes: Vec<Change>) {
+        for change in changes.into_iter().rev() {
+            match self.revert(&change).await {
+                Ok(()) => debug!("Undid {:?}", change),
+                Err(e) => warn!("Failed to undo {:?}: {}", change, e),
+            }
+        }
+    }
+}
+
+/// Network changes made so far, undone together on `rollback`.
+#[must_use = "a transaction must be committed or rolled back"]
+pub struct Transaction<'a> {
+    net: &'a NetConfig,
+    changes: Vec<Change>,
+}
+
+impl Transaction<'_> {
+    pub async fn add_address(
+        &mut self,
+        dev: &str,
+        address: IpAddr,
+        prefix_len: u8,
+    ) -> OneboxResult<()> {
+        self.net.add_address(dev, address, prefix_len).await?;
+        self.changes.push(Change::Address {
+            dev: dev.to_string(),
+            address,
+            prefix_len,
+        });
+        Ok(())
+    }
+
+    pub async fn add_route(&mut self, route: Route) -> OneboxResult<()> {
+        self.net.add_route(&route).await?;
+        self.changes.push(Change::Route(route));
+        Ok(())
+    }
+
+    pub async fn add_rule(&mut self, rule: Rule) -> OneboxResult<()> {
+        self.net.add_rule(&rule).await?;
+        self.changes.push(Change::Rule(rule));
+        Ok(())
+    }
+
+    /// Sets a sysctl, remembering its value to restore.
+    pub fn set_sysctl(&mut self, key: &str, value: &str) -> OneboxResult<()> {
+        let previous = sysctl(key)?;
+        if previous == value {
+            return Ok(());
+        }
+        set_sysctl(key, value)?;
+        self.changes.push(Change::Sysctl {
+            key: key.to_string(),
+            previous,
+        });
+        Ok(())
+    }
+
+    /// Replaces a file's contents, remembering them to restore.
+    pub fn write_file(&mut self, path: &str, contents: &str) -> OneboxResult<()> {
+        let previous = match std::fs::read_to_string(path) {
+            Ok(previous) => Some(previous),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(system(format!("Failed to read {path}"), e)),
+        };
+        std::fs::write(path, contents).map_err(|e| system(format!("Failed to write {path}"), e))?;
+        self.changes.push(Change::File {
+            path: path.to_string(),
+            previous,
+        });
+        Ok(())
+    }
+
+    pub fn add_masquerade(&mut self, masquerade: Masquerade) -> OneboxResult<()> {
+        add_masquerade(&masquerade)?;
+        self.changes.push(Change::Masquerade(masquerade));
+        Ok(())
+    }
+
+    /// Keeps the changes, returning them so they can be reverted later.
+    pub fn commit(self) -> Vec<Change> {
+        self.changes
+    }
+
+    /// Undoes the changes made so far.
+    pub async fn rollback(self) {
+        self.net.revert_all(self.changes).await
+    }
+}
+
+/// Reads a sysctl, e.g. `net.ipv4.ip_forward`.
+pub fn sysctl(key: &str) -> OneboxResult<String> {
+    std::fs::read_to_string(sysctl_path(key))
+        .map(|value| value.trim().to_string())
+        .map_err(|e| system(format!("Failed to read {key}"), e))
+}
+
+/// Writes a sysctl.
+pub fn set_sysctl(key: &str, value: &str) -> OneboxResult<()> {
+    std::fs::write(sysctl_path(key), value).map_err(|e| system(format!("Failed to set {key}"), e))
+}
+
+fn restore_file(path: &str, previous: Option<&str>) -> OneboxResult<()> {
+    let result = match previous {
+        Some(previous) => std::fs::write(path, previous),
+        None => match std::fs::remove_file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+    result.map_err(|e| system(format!("Failed to restore {path}"), e))
+}
+
+fn sysctl_path(key: &str) -> String {
+    format!("/proc/sys/{}", key.replace('.', "/"))
+}
+
+/// Masquerades a network behind an interface's address, replacing any
+/// masquerade onebox set up before in the same family.
+pub fn add_masquerade(masquerade: &Masquerade) -> OneboxResult<()> {
+    nft(&masquerade_batch(masquerade))
+}
+
+/// Removes onebox's NAT rules of a family.
+pub fn delete_masquerade(family: Family) -> OneboxResult<()> {
+    let table = json!({ "family": nft_family(family), "name": NFT_TABLE });
+    // Adding the table first makes the delete succeed if it doesn't exist.
+    nft(&json!({ "nftables": [
+        { "add": { "table": table } },
+        { "delete": { "table": table } },
+    ]}))
+}
+
+fn nft_family(family: Family) -> &'static str {
+    match family {
+        Family::Ipv4 => "ip",
+        Family::Ipv6 => "ip6",
+    }
+}
+
+fn masquerade_batch(masquerade: &Masquerade) -> serde_json::Value {
+    let family = nft_family(Family::of(masquerade.source));
+    let network = network(masquerade.source, masquerade.prefix_len);
+    json!({ "nftables": [
+        { "add": { "table": { "family": family, "name": NFT_TABLE } } },
+        { "flush": { "table": { "family": family, "name": NFT_TABLE } } },
+        { "add": { "chain": {
+            "family": family,
+            "table": NFT_TABLE,
+            "name": "postrouting",
+            "type": "nat",
+            "hook": "postrouting",
+            "prio": 100,
+            "policy": "accept",
+        } } },
+        { "add": { "rule": {
+            "family": family,
+            "table": NFT_TABLE,
+            "chain": "postrouting",
+            "expr": [
+                { "match": {
+                    "op": "==",
+                    "left": { "payload": { "protocol": family, "field": "saddr" } },
+                    "right": { "prefix": {
+                        "addr": network.to_string(),
+                        "len": masquerade.prefix_len,
+                    } },
+                } },
+                { "match": {
+                    "op": "==",
+                    "left": { "meta": { "key": "oifname" } },
+                    "right": masquerade.oif,
+                } },
+                { "masquerade": null },
+            ],
+        } } },
+    ]})
+}
+
+/// Runs an nftables JSON batch, which the kernel applies atomically.
+fn nft(batch: &serde_json::Value) -> OneboxResult<()> {
+    let mut child = Command::new("nft")
+        .args(["-j", "-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| system("Failed to run nft", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(batch.to_string().as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(OneboxError::System(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// The network an address is in, e.g. `10.8.0.0` for `10.8.0.1/24`.
+pub fn network(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            Ipv4Addr::from(u32::from(v4) & mask).into()
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            Ipv6Addr::from(u128::from(v6) & mask).into()
+        }
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+fn parse_addr(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into()),
+        _ => None,
+    }
+}
+
+/// The netlink message for a route, going out of interface `oif`.
+fn route_message(route: &Route, oif: Option<u32>) -> RouteMessage {
+    let family = route.family();
+    let mut message = RouteMessage::default();
+    message.header.address_family = family.af();
+    message.header.destination_prefix_length = route.prefix_len;
+    message.header.protocol = RTPROT_STATIC;
+    message.header.kind = RTN_UNICAST;
+    // Routes straight out of an interface reach hosts on the link.
+    message.header.scope = match route.gateway {
+        Some(_) => RT_SCOPE_UNIVERSE,
+        None if oif.is_some() && family == Family::Ipv4 => RT_SCOPE_LINK,
+        None => RT_SCOPE_UNIVERSE,
+    };
+    if route.table < 256 {
+        message.header.table = route.table as u8;
+    } else {
+        message.header.table = RT_TABLE_UNSPEC;
+        message.nlas.push(RouteNla::Table(route.table));
+    }
+    if route.prefix_len > 0 {
+        message
+            .nlas
+            .push(RouteNla::Destination(octets(route.destination)));
+    }
+    if let Some(gateway) = route.gateway {
+        message.nlas.push(RouteNla::Gateway(octets(gateway)));
+    }
+    if let Some(oif) = oif {
+        message.nlas.push(RouteNla::Oif(oif));
+    }
+    if let Some(metric) = route.metric {
+        message.nlas.push(RouteNla::Priority(metric));
+    }
+    message
+}
+
+/// A route read from the kernel, with its interface by index.
+struct RouteEntry {
+    route: Route,
+    oif: Option<u32>,
+}
+
+fn parse_route(message: &RouteMessage) -> Option<RouteEntry> {
+    let family = match message.header.address_family {
+        af if af == AF_INET as u8 => Family::Ipv4,
+        af if af == AF_INET6 as u8 => Family::Ipv6,
+        _ => return None,
+    };
+    if message.header.kind != RTN_UNICAST {
+        return None;
+    }
+    let mut route = Route::default_for(family);
+    route.prefix_len = message.header.destination_prefix_length;
+    route.table = u32::from(message.header.table);
+    let mut oif = None;
+    for nla in &message.nlas {
+        match nla {
+            RouteNla::Destination(bytes) => route.destination = parse_addr(bytes)?,
+            RouteNla::Gateway(bytes) => route.gateway = parse_addr(bytes),
+            RouteNla::Oif(index) => oif = Some(*index),
+            RouteNla::Priority(priority) => route.metric = Some(*priority),
+            RouteNla::Table(table) => route.table = *table,
+            _ => {}
+        }
+    }
+    Some(RouteEntry { route, oif })
+}
+
+fn rule_message(rule: &Rule) -> RuleMessage {
+    let mut message = RuleMessage::default();
+    message.header.family = rule.family.af();
+    message.header.action = FR_ACT_TO_TBL;
+    if rule.table < 256 {
+        message.header.table = rule.table as u8;
+    } else {
+        message.header.table = RT_TABLE_UNSPEC;
+        message.nlas.push(RuleNla::Table(rule.table));
+    }
+    message.nlas.push(RuleNla::Priority(rule.priority));
+    if let Some(from) = rule.from {
+        message.header.src_len = rule.family.host_prefix_len();
+        message.nlas.push(RuleNla::Source(octets(from)));
+    }
+    if let Some(mark) = rule.fwmark {
+        message.nlas.push(RuleNla::FwMark(mark));
+    }
+    message
+}
+
+fn rule_priority(rule: &RuleMessage) -> Option<u32> {
+    rule.nlas.iter().find_map(|nla| match nla {
+        RuleNla::Priority(priority) => Some(*priority),
+        _ => None,
+    })
+}
+
+fn errno(e: &rtnetlink::Error) -> Option<i32> {
+    match e {
+        rtnetlink::Error::NetlinkError(message) => message.code.map(|code| -code.get()),
+        _ => None,
+    }
+}
+
+/// Whether an error says the thing to delete doesn't exist.
+fn gone(e: &rtnetlink::Error) -> bool {
+    matches!(
+        errno(e),
+        Some(ENOENT) | Some(ESRCH) | Some(ENODEV) | Some(EADDRNOTAVAIL)
+    )
+}
+
+fn system(context: impl fmt::Display, e: impl fmt::Display) -> OneboxError {
+    OneboxError::System(format!("{context}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn routes_survive_a_round_trip_through_netlink_messages() {
+        let route = Route::new(ip("10.8.0.0"), 24).via(Some(ip("10.99.99.2")));
+        let entry = parse_route(&route_message(&route, Some(7))).unwrap();
+        assert_eq!(entry.route, route);
+        assert_eq!(entry.oif, Some(7));
+
+        let route = Route::default_for(Family::Ipv6)
+            .via(Some(ip("fe80::1")))
+            .table(7000);
+        let message = route_message(&route, None);
+        assert_eq!(message.header.table, RT_TABLE_UNSPEC);
+        assert_eq!(parse_route(&message).unwrap().route, route);
+        assert_eq!(route.to_string(), "::/0 via fe80::1 table 7000");
+
+        let route = Route::host(ip("203.0.113.7"))
+            .via(Some(ip("192.168.1.1")))
+            .metric(7001);
+        assert_eq!(route.prefix_len, 32);
+        assert_eq!(
+            parse_route(&route_message(&route, Some(2))).unwrap().route,
+            route
+        );
+        assert_eq!(
+            route.to_string(),
+            "203.0.113.7/32 via 192.168.1.1 metric 7001"
+        );
+    }
+
+    #[test]
+    fn rules_carry_their_selector_and_table() {
+        let rule = Rule {
+            family: Family::Ipv4,
+            priority: 7000,
+            table: 7000,
+            from: Some(ip("192.168.1.10")),
+            fwmark: None,
+        };
+        let message = rule_message(&rule);
+        assert_eq!(message.header.action, FR_ACT_TO_TBL);
+        assert_eq!(message.header.src_len, 32);
+        assert_eq!(rule_priority(&message), Some(7000));
+        assert!(message.nlas.contains(&RuleNla::Table(7000)));
+        assert!(message
+            .nlas
+            .contains(&RuleNla::Source(vec![192, 168, 1, 10])));
+    }
+
+    #[test]
+    fn networks_are_masked() {
+        assert_eq!(network(ip("10.8.0.1"), 24), ip("10.8.0.0"));
+        assert_eq!(network(ip("2001:db8:8:ff::1"), 56), ip("2001:db8:8::"));
+        assert_eq!(network(ip("10.8.0.1"), 0), ip("0.0.0.0"));
+        assert_eq!(network(ip("10.8.0.1"), 32), ip("10.8.0.1"));
+    }
+
+    #[test]
+    fn masquerade_batches_match_the_network_and_interface() {
+        let batch = masquerade_batch(&Masquerade {
+            source: ip("fd00:8::1"),
+            prefix_len: 64,
+            oif: "eth0".to_string(),
+        });
+        let commands = batch["nftables"].as_array().unwrap();
+        assert_eq!(commands[0]["add"]["table"]["family"], "ip6");
+        assert_eq!(commands[1]["flush"]["table"]["name"], NFT_TABLE);
+        let expr = &commands[3]["add"]["rule"]["expr"];
+        assert_eq!(expr[0]["match"]["right"]["prefix"]["addr"], "fd00:8::");
+        assert_eq!(expr[0]["match"]["right"]["prefix"]["len"], 64);
+        assert_eq!(expr[1]["match"]["right"], "eth0");
+        assert!(expr[2]["masquerade"].is_null());
+    }
+}
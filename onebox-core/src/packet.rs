@@ -41,6 +41,12 @@ pub enum PacketType {
 
     /// Control packet for session management
     Control = 0x05,
+
+    /// Packet of a bandwidth probe train
+    BandwidthProbe = 0x06,
+
+    /// Receiver's report on a bandwidth probe train
+    BandwidthReport = 0x07,
 }
 
 impl Default for PacketHeader {
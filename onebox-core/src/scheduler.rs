@@ -0,0 +1,121 @@
+//! Weighted link selection shared by the client and server schedulers.
+
+use std::collections::HashMap;
+
+/// Smooth weighted round-robin over a set of named links.
+///
+/// Each link is picked in proportion to its weight, with picks of different
+/// links interleaved rather than bunched together. Links without an explicit
+/// weight count as weight 1, so with no weights set this is plain round-robin.
+#[derive(Debug, Clone, Default)]
+pub struct WeightedRoundRobin {
+    weights: HashMap<String, u32>,
+    current: HashMap<String, i64>,
+}
+
+impl WeightedRoundRobin {
+    /// Creates a scheduler where every link has weight 1.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the weight of a link. A weight of 0 keeps the link from being
+    /// picked as long as any other candidate has a non-zero weight.
+    pub fn set_weight(&mut self, name: &str, weight: u32) {
+        self.weights.insert(name.to_string(), weight);
+    }
+
+    /// Drops a link's weight and its place in the rotation, for links that
+    /// are gone for good.
+    pub fn forget(&mut self, name: &str) {
+        self.weights.remove(name);
+        self.current.remove(name);
+    }
+
+    /// The weight of a link.
+    pub fn weight(&self, name: &str) -> u32 {
+        self.weights.get(name).copied().unwrap_or(1)
+    }
+
+    /// Picks one of `links` and returns its index.
+    pub fn pick<T>(&mut self, links: &[(String, T)]) -> Option<usize> {
+        let all_zero = links.iter().all(|(name, _)| self.weight(name) == 0);
+        let weight_of = |this: &Self, name: &str| -> i64 {
+            if all_zero {
+                1
+            } else {
+                this.weight(name) as i64
+            }
+        };
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (index, (name, _)) in links.iter().enumerate() {
+            let weight = weight_of(self, name);
+            if weight == 0 {
+                continue;
+            }
+            total += weight;
+            let current = self.current.entry(name.clone()).or_insert(0);
+            *current += weight;
+            if best.is_none_or(|(_, best_current)| *current > best_current) {
+                best = Some((index, *current));
+            }
+        }
+        let (index, _) = best?;
+        if let Some(current) = self.current.get_mut(&links[index].0) {
+            *current -= total;
+        }
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(names: &[&str]) -> Vec<(String, ())> {
+        names.iter().map(|name| (name.to_string(), ())).collect()
+    }
+
+    #[test]
+    fn unweighted_links_are_picked_in_turn() {
+        let mut wrr = WeightedRoundRobin::new();
+        let links = links(&["eth0", "wlan0", "wwan0"]);
+        let picks: Vec<usize> = (0..6).map(|_| wrr.pick(&links).unwrap()).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2]);
+        assert_eq!(wrr.pick::<()>(&[]), None);
+    }
+
+    #[test]
+    fn picks_follow_weights_and_interleave() {
+        let mut wrr = WeightedRoundRobin::new();
+        wrr.set_weight("eth0", 3);
+        wrr.set_weight("wwan0", 1);
+        let links = links(&["eth0", "wwan0"]);
+        let picks: Vec<usize> = (0..8).map(|_| wrr.pick(&links).unwrap()).collect();
+        assert_eq!(picks.iter().filter(|&&index| index == 0).count(), 6);
+        assert_eq!(picks[..4], [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn zero_weight_links_are_only_used_as_a_last_resort() {
+        let mut wrr = WeightedRoundRobin::new();
+        wrr.set_weight("eth0", 0);
+        let both = links(&["eth0", "wwan0"]);
+        assert!((0..4).all(|_| wrr.pick(&both) == Some(1)));
+        assert_eq!(wrr.pick(&links(&["eth0"])), Some(0));
+    }
+
+    #[test]
+    fn forgotten_links_start_over() {
+        let mut wrr = WeightedRoundRobin::new();
+        wrr.set_weight("eth0", 0);
+        let both = links(&["eth0", "wwan0"]);
+        wrr.pick(&both);
+        wrr.forget("eth0");
+        wrr.forget("wwan0");
+        assert!(wrr.weights.is_empty() && wrr.current.is_empty());
+        assert_eq!(wrr.pick(&both), Some(0));
+    }
+}
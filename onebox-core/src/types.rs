@@ -45,11 +45,14 @@ pub enum LinkStatus {
     /// Link is healthy and operational
     Up,
 
+    /// Link works, but its RTT, jitter or loss is past a threshold
+    Degraded,
+
     /// Link is down or unreachable
     Down,
 
-    /// Link is being tested/probed
-    Testing,
+    /// Link has not been probed yet
+    Unknown,
 }
 
 /// Link health metrics
@@ -64,7 +67,10 @@ pub struct LinkHealth {
     /// Packet loss percentage (0-100)
     pub packet_loss: Option<f32>,
 
-    /// Last successful probe timestamp
+    /// Quality score as an estimated MOS (1.0-4.5)
+    pub quality: Option<f32>,
+
+    /// Last successful probe timestamp (Unix timestamp in milliseconds)
     pub last_success: Option<u64>,
 
     /// Number of consecutive failures
@@ -73,8 +79,16 @@ pub struct LinkHealth {
 
 /// Configuration for a network link
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LinkConfig {
-    /// Local socket address to bind to
+    /// Name of the network interface this link is bound to (e.g., "wwan0"),
+    /// or a glob matching several (e.g., "wwan*")
+    pub name: String,
+
+    /// Local socket address to bind to; its IP, when set, selects which of the
+    /// interface's addresses the link uses as its source address. Left
+    /// unspecified, `0.0.0.0` takes an IPv4 address if possible and IPv6
+    /// otherwise, while `::` only takes IPv6
     pub local_addr: SocketAddr,
 
     /// Remote server address
@@ -85,6 +99,160 @@ pub struct LinkConfig {
 
     /// Whether this link is enabled
     pub enabled: bool,
+
+    /// Monthly data cap in megabytes (10^6 bytes), for metered links
+    pub monthly_cap_mb: Option<u64>,
+
+    /// Daily data cap in megabytes (10^6 bytes), for metered links
+    pub daily_cap_mb: Option<u64>,
+
+    /// Day of the month (1-31) on which the monthly billing cycle resets
+    pub billing_cycle_day: u8,
+
+    /// Percentage of a cap after which the link is deprioritised (soft cap)
+    pub soft_cap_percent: u8,
+
+    /// Upper bound on the send rate of this link in kbit/s
+    pub max_rate_kbps: Option<u64>,
+
+    /// Share of traffic in both directions for this link, unless set per
+    /// direction
+    pub weight: Option<u32>,
+
+    /// Share of upstream traffic for this link; derived from its measured
+    /// capacity when unset
+    pub upstream_weight: Option<u32>,
+
+    /// Share of downstream traffic for this link; derived from its measured
+    /// capacity when unset
+    pub downstream_weight: Option<u32>,
+
+    /// Relative cost of data on this link; weights derived from capacity are
+    /// divided by it, so that costlier links carry less
+    pub cost: u32,
+
+    /// Probe interval of this link in milliseconds, overriding `[client.health]`
+    pub probe_interval_ms: Option<u64>,
+
+    /// Probe timeout of this link in milliseconds, overriding `[client.health]`
+    pub probe_timeout_ms: Option<u64>,
+
+    /// Consecutive lost probes after which this link is Down, overriding
+    /// `[client.health]`
+    pub down_after_failures: Option<u32>,
+
+    /// Whether this link is probed adaptively, overriding `[client.health]`
+    pub adaptive_probing: Option<bool>,
+
+    /// Checks beyond the tunnel probes that the link must pass to be healthy,
+    /// such as reaching a host outside the tunnel
+    pub checks: Vec<HealthCheck>,
+}
+
+impl LinkConfig {
+    /// Whether this configuration applies to the named interface.
+    pub fn matches(&self, iface: &str) -> bool {
+        glob_match(&self.name, iface)
+    }
+
+    /// The configured (upstream, downstream) weights.
+    pub fn weights(&self) -> (Option<u32>, Option<u32>) {
+        (
+            self.upstream_weight.or(self.weight),
+            self.downstream_weight.or(self.weight),
+        )
+    }
+
+    /// Chooses the link's source address from the interface's addresses,
+    /// among those the server has an address of the same family for.
+    /// Link-local and loopback addresses are never chosen.
+    pub fn source_address(&self, addrs: &[IpAddr], servers: &[SocketAddr]) -> Option<IpAddr> {
+        let wanted = self.local_addr.ip();
+        let usable = |ip: &&IpAddr| {
+            !ip.is_loopback()
+                && !is_link_local(ip)
+                && servers.iter().any(|s| s.is_ipv4() == ip.is_ipv4())
+        };
+        if !wanted.is_unspecified() {
+            return addrs
+                .iter()
+                .filter(usable)
+                .find(|&&ip| ip == wanted)
+                .copied();
+        }
+        let v4 = addrs.iter().filter(|ip| ip.is_ipv4()).find(usable);
+        let v6 = addrs.iter().filter(|ip| ip.is_ipv6()).find(usable);
+        match wanted {
+            IpAddr::V4(_) => v4.or(v6).copied(),
+            IpAddr::V6(_) => v6.copied(),
+        }
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Matches a name against a glob pattern, where `*` matches any run of
+/// characters and `?` any single one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the name position it matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A health check run through a link besides the tunnel probes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// An ICMP echo to `target`, a host name or IP address
+    Icmp { target: String },
+
+    /// A TCP connection to `target`, given as `host:port`. A host name is
+    /// resolved by the system resolver, not through the link
+    Tcp { target: String },
+
+    /// A plain-HTTP GET of `url` that must answer with `expect_status`. Its
+    /// host name is resolved like a TCP check's
+    Http {
+        url: String,
+        #[serde(default = "default_expect_status")]
+        expect_status: u16,
+    },
+
+    /// A DNS lookup of `name` sent to `server`, an IP address with an
+    /// optional port, that must return an address
+    Dns { name: String, server: String },
+}
+
+fn default_expect_status() -> u16 {
+    200
 }
 
 impl Default for LinkHealth {
@@ -93,12 +261,39 @@ impl Default for LinkHealth {
             status: LinkStatus::Up,
             rtt_ms: None,
             packet_loss: None,
+            quality: None,
             last_success: None,
             consecutive_failures: 0,
         }
     }
 }
 
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            remote_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            priority: 100,
+            enabled: true,
+            monthly_cap_mb: None,
+            daily_cap_mb: None,
+            billing_cycle_day: 1,
+            soft_cap_percent: 80,
+            max_rate_kbps: None,
+            weight: None,
+            upstream_weight: None,
+            downstream_weight: None,
+            cost: 1,
+            probe_interval_ms: None,
+            probe_timeout_ms: None,
+            down_after_failures: None,
+            adaptive_probing: None,
+            checks: Vec::new(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,18 +325,82 @@ mod tests {
             IpAddr::V4(v4) => assert_eq!(v4, Ipv4Addr::UNSPECIFIED),
             _ => panic!("expected IPv4 unspecified"),
         }
+        assert!(c.name.is_empty());
         assert_eq!(c.priority, 100);
         assert!(c.enabled);
+        assert!(c.monthly_cap_mb.is_none());
+        assert!(c.daily_cap_mb.is_none());
+        assert_eq!(c.billing_cycle_day, 1);
+        assert_eq!(c.soft_cap_percent, 80);
+        assert!(c.max_rate_kbps.is_none());
+        assert!(c.upstream_weight.is_none());
+        assert!(c.downstream_weight.is_none());
+        assert_eq!(c.cost, 1);
+        assert!(c.probe_interval_ms.is_none());
+        assert!(c.adaptive_probing.is_none());
     }
-}
 
-impl Default for LinkConfig {
-    fn default() -> Self {
-        Self {
-            local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-            remote_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-            priority: 100,
-            enabled: true,
-        }
+    #[test]
+    fn link_config_deserializes_with_partial_fields() {
+        let c: LinkConfig = serde_json::from_str(r#"{"name":"wwan0","priority":200}"#).unwrap();
+        assert_eq!(c.name, "wwan0");
+        assert_eq!(c.priority, 200);
+        assert!(c.enabled);
+    }
+
+    #[test]
+    fn link_names_match_globs() {
+        assert!(glob_match("wwan0", "wwan0"));
+        assert!(!glob_match("wwan0", "wwan01"));
+        assert!(glob_match("wwan*", "wwan0"));
+        assert!(glob_match("wwan*", "wwan"));
+        assert!(glob_match("*docker*", "br-docker0"));
+        assert!(glob_match("eth?", "eth1"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(glob_match("*a*b", "xaab"));
+        assert!(!glob_match("lo*", "wlo1"));
+    }
+
+    #[test]
+    fn directional_weights_override_the_shared_one() {
+        let c = LinkConfig {
+            weight: Some(3),
+            downstream_weight: Some(8),
+            ..LinkConfig::default()
+        };
+        assert_eq!(c.weights(), (Some(3), Some(8)));
+        assert_eq!(LinkConfig::default().weights(), (None, None));
+    }
+
+    #[test]
+    fn source_addresses_follow_the_servers_families() {
+        let v4: IpAddr = "192.168.1.10".parse().unwrap();
+        let v6: IpAddr = "2001:db8::10".parse().unwrap();
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+        let addrs = [link_local, v6, v4];
+        let both: [SocketAddr; 2] = [
+            "1.2.3.4:51820".parse().unwrap(),
+            "[2001:db8::1]:51820".parse().unwrap(),
+        ];
+        let only_v6 = &both[1..];
+
+        let auto = LinkConfig::default();
+        assert_eq!(auto.source_address(&addrs, &both), Some(v4));
+        assert_eq!(auto.source_address(&addrs, only_v6), Some(v6));
+        assert_eq!(auto.source_address(&[link_local], only_v6), None);
+        assert_eq!(auto.source_address(&[v4], only_v6), None);
+
+        let v6_only = LinkConfig {
+            local_addr: "[::]:0".parse().unwrap(),
+            ..LinkConfig::default()
+        };
+        assert_eq!(v6_only.source_address(&addrs, &both), Some(v6));
+
+        let pinned = LinkConfig {
+            local_addr: SocketAddr::new(v6, 0),
+            ..LinkConfig::default()
+        };
+        assert_eq!(pinned.source_address(&addrs, &both), Some(v6));
+        assert_eq!(pinned.source_address(&[v4], &both), None);
     }
 }
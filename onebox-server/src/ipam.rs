@@ -0,0 +1,291 @@
+//! Tunnel addresses handed out to clients.
+//!
+//! Clients with a reservation always get its address. Others get the lowest
+//! free address of the pool, and keep it as a lease, saved to the lease file
+//! so it survives restarts. When the tunnel carries IPv6, a client's IPv6
+//! address is the same host of the IPv6 prefix, so the two are easy to tell
+//! apart in logs, and packets to either find the client.
+
+use onebox_core::types::ClientId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The tunnel network and the addresses assigned in it.
+#[derive(Debug)]
+pub struct AddressPool {
+    /// The network address.
+    network: u32,
+    /// Number of addresses in the network.
+    size: u32,
+    /// The server's own host number.
+    server_host: u32,
+    /// Host numbers handed out to clients without a reservation.
+    range: RangeInclusive<u32>,
+    /// The IPv6 network, its prefix length and the server's host number in it.
+    network_v6: Option<(u128, u8, u128)>,
+    reservations: HashMap<ClientId, u32>,
+    leases: HashMap<ClientId, u32>,
+    /// The client each reserved or leased host number belongs to.
+    owners: HashMap<u32, ClientId>,
+    lease_file: Option<PathBuf>,
+}
+
+/// A lease, as saved to the lease file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    client: ClientId,
+    address: Ipv4Addr,
+}
+
+impl AddressPool {
+    /// A pool handing out the addresses from `first` to `last` in the
+    /// server's network, `server/prefix_len`.
+    pub fn new(
+        server: Ipv4Addr,
+        prefix_len: u8,
+        server_v6: Option<(Ipv6Addr, u8)>,
+        (first, last): (Ipv4Addr, Ipv4Addr),
+    ) -> Self {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        let network_v6 = server_v6.map(|(addr, prefix_len)| {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            let addr = u128::from(addr);
+            (addr & mask, prefix_len, addr & !mask)
+        });
+        Self {
+            network: u32::from(server) & mask,
+            size: !mask + 1,
+            server_host: u32::from(server) & !mask,
+            range: u32::from(first) & !mask..=u32::from(last) & !mask,
+            network_v6,
+            reservations: HashMap::new(),
+            leases: HashMap::new(),
+            owners: HashMap::new(),
+            lease_file: None,
+        }
+    }
+
+    /// Reserves an address in the network for a client.
+    pub fn reserve(&mut self, client: ClientId, address: Ipv4Addr) {
+        let host = u32::from(address) - self.network;
+        self.reservations.insert(client, host);
+        self.owners.insert(host, client);
+    }
+
+    /// Takes up the leases saved in `path`, which new leases are saved to.
+    /// Leases outside the pool, or clashing with a reservation, are dropped.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.lease_file = Some(path.as_ref().to_path_buf());
+        let leases: Vec<Lease> = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for lease in leases {
+            let host = u32::from(lease.address).wrapping_sub(self.network);
+            if !self.is_free(host) || self.reservations.contains_key(&lease.client) {
+                warn!(
+                    "Dropping lease of {} for client {}",
+                    lease.address, lease.client.0
+                );
+                continue;
+            }
+            self.leases.insert(lease.client, host);
+            self.owners.insert(host, lease.client);
+        }
+        info!("Loaded {} address leases", self.leases.len());
+        Ok(())
+    }
+
+    /// The client's addresses: its reserved or leased ones, or else the
+    /// lowest free ones of the pool, leased to it. `None` when the pool is
+    /// exhausted.
+    pub fn assign(&mut self, client: ClientId) -> Option<(Ipv4Addr, Option<Ipv6Addr>)> {
+        let host = match self
+            .reservations
+            .get(&client)
+            .or_else(|| self.leases.get(&client))
+        {
+            Some(&host) => host,
+            None => {
+                let host = self.range.clone().find(|&host| self.is_free(host))?;
+                self.leases.insert(client, host);
+                self.owners.insert(host, client);
+                self.save();
+                host
+            }
+        };
+        let v6 = self
+            .network_v6
+            .map(|(network, _, _)| Ipv6Addr::from(network | u128::from(host)));
+        Some((Ipv4Addr::from(self.network | host), v6))
+    }
+
+    /// The client a packet to `addr` is for.
+    pub fn client_for(&self, addr: IpAddr) -> Option<ClientId> {
+        let host = match addr {
+            IpAddr::V4(addr) => {
+                let host = u32::from(addr).wrapping_sub(self.network);
+                (host < self.size).then_some(host)?
+            }
+            IpAddr::V6(addr) => {
+                let (network, prefix_len, _) = self.network_v6?;
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                let addr = u128::from(addr);
+                if addr & mask != network {
+                    return None;
+                }
+                u32::try_from(addr & !mask).ok()?
+            }
+        };
+        self.owners.get(&host).copied()
+    }
+
+    /// The IPv6 prefix length clients are given.
+    pub fn prefix_len_v6(&self) -> Option<u8> {
+        self.network_v6.map(|(_, prefix_len, _)| prefix_len)
+    }
+
+    /// Whether a host number of the pool may be leased.
+    fn is_free(&self, host: u32) -> bool {
+        // Leave out the network and broadcast addresses.
+        self.range.contains(&host)
+            && host != 0
+            && host != self.size - 1
+            && host != self.server_host
+            && self
+                .network_v6
+                .is_none_or(|(_, _, server)| u128::from(host) != server)
+            && !self.owners.contains_key(&host)
+    }
+
+    /// Writes the leases to the lease file, replacing it atomically.
+    fn save(&self) {
+        let Some(path) = &self.lease_file else {
+            return;
+        };
+        let mut leases: Vec<Lease> = self
+            .leases
+            .iter()
+            .map(|(&client, &host)| Lease {
+                client,
+                address: Ipv4Addr::from(self.network | host),
+            })
+            .collect();
+        leases.sort_by_key(|lease| lease.address);
+        let result = serde_json::to_string_pretty(&leases)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let tmp_path = path.with_extension("tmp");
+                std::fs::write(&tmp_path, content)?;
+                std::fs::rename(&tmp_path, path)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("Failed to save address leases to {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn pool(prefix_len: u8, first: u8, last: u8) -> AddressPool {
+        AddressPool::new(
+            Ipv4Addr::new(10, 99, 99, 1),
+            prefix_len,
+            Some(("fd00:8::1".parse().unwrap(), 64)),
+            (
+                Ipv4Addr::new(10, 99, 99, first),
+                Ipv4Addr::new(10, 99, 99, last),
+            ),
+        )
+    }
+
+    #[test]
+    fn clients_get_the_lowest_free_hosts() {
+        let mut pool = pool(30, 1, 2);
+        assert_eq!(
+            pool.assign(ClientId(7)),
+            Some((
+                Ipv4Addr::new(10, 99, 99, 2),
+                Some("fd00:8::2".parse().unwrap())
+            ))
+        );
+        // A client keeps its addresses.
+        assert_eq!(
+            pool.assign(ClientId(7)).unwrap().0,
+            Ipv4Addr::new(10, 99, 99, 2)
+        );
+        // A /30 has room for one client besides the server.
+        assert_eq!(pool.assign(ClientId(8)), None);
+        assert_eq!(pool.prefix_len_v6(), Some(64));
+    }
+
+    #[test]
+    fn reservations_are_kept_out_of_the_pool() {
+        let mut pool = pool(24, 10, 11);
+        pool.reserve(ClientId(1), Ipv4Addr::new(10, 99, 99, 10));
+        assert_eq!(
+            pool.assign(ClientId(2)).unwrap().0,
+            Ipv4Addr::new(10, 99, 99, 11)
+        );
+        assert_eq!(pool.assign(ClientId(3)), None);
+        assert_eq!(
+            pool.assign(ClientId(1)).unwrap().0,
+            Ipv4Addr::new(10, 99, 99, 10)
+        );
+    }
+
+    #[test]
+    fn packets_find_the_client_by_destination() {
+        let mut pool = pool(24, 2, 254);
+        pool.reserve(ClientId(1), Ipv4Addr::new(10, 99, 99, 50));
+        pool.assign(ClientId(2));
+        let client_for = |addr: &str| pool.client_for(addr.parse().unwrap());
+        assert_eq!(client_for("10.99.99.50"), Some(ClientId(1)));
+        assert_eq!(client_for("fd00:8::32"), Some(ClientId(1)));
+        assert_eq!(client_for("10.99.99.2"), Some(ClientId(2)));
+        assert_eq!(client_for("10.99.99.3"), None);
+        assert_eq!(client_for("10.99.98.2"), None);
+        assert_eq!(client_for("fd00:9::2"), None);
+    }
+
+    #[test]
+    fn leases_survive_a_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("leases.json");
+        let mut before = pool(24, 2, 254);
+        before.load(&path).unwrap();
+        before.assign(ClientId(1));
+        before.assign(ClientId(2));
+
+        let mut restarted = pool(24, 2, 254);
+        // Client 1 has been given a reservation since.
+        restarted.reserve(ClientId(1), Ipv4Addr::new(10, 99, 99, 100));
+        restarted.load(&path).unwrap();
+        assert_eq!(
+            restarted.assign(ClientId(2)).unwrap().0,
+            Ipv4Addr::new(10, 99, 99, 3)
+        );
+        assert_eq!(
+            restarted.assign(ClientId(3)).unwrap().0,
+            Ipv4Addr::new(10, 99, 99, 2)
+        );
+    }
+}
@@ -1,17 +1,32 @@
 //! onebox-server - Server binary for the onebox-rs internet bonding solution
 
+pub mod ipam;
+
+use chacha20poly1305::Key;
 use clap::{Parser, Subcommand};
+use ipam::AddressPool;
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn6,
+};
+use onebox_core::bandwidth::{self, BandwidthProbe, BandwidthReport, TrainArrivals, TRAIN_LENGTH};
+use onebox_core::control::{self, ControlMessage, LinkAdvert, NetworkSettings};
+use onebox_core::health::{Echo, HealthPolicy, LinkMonitor, LinkStatus, StatusChange};
+use onebox_core::netconfig::{Change, Family, Masquerade, NetConfig, Route};
 use onebox_core::packet::PacketHeader;
 use onebox_core::packet::PacketType;
 use onebox_core::prelude::*;
+use onebox_core::scheduler::WeightedRoundRobin;
 use onebox_core::types::ClientId;
 use std::collections::{BTreeMap, HashMap};
-use std::net::{Ipv4Addr, SocketAddr};
-use std::process::Command;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::fd::AsRawFd;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio_tun::TunBuilder;
 use tracing::{debug, error, info, warn, Level};
@@ -52,6 +67,12 @@ enum Commands {
     Config,
 }
 
+/// How long an incomplete bandwidth probe train is kept around.
+const TRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client link may stay silent before downstream traffic avoids it.
+const LINK_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum AuthStatus {
     Pending,
@@ -62,20 +83,253 @@ struct ClientState {
     auth_status: AuthStatus,
     jitter_buffer: BTreeMap<u64, Vec<u8>>,
     next_seq: Option<u64>,
-    last_seen_addr: SocketAddr,
+    /// Every address authenticated packets arrived from, with when the
+    /// latest did.
+    sources: HashMap<SocketAddr, Instant>,
+    /// Bandwidth probe trains being received, keyed by source address and train id.
+    trains: HashMap<(SocketAddr, u32), TrainArrivals>,
+    /// The client's links, keyed by the client's name for them.
+    links: HashMap<String, ClientLink>,
+    /// Picks the link each downstream packet is sent to.
+    downstream: WeightedRoundRobin,
+    /// Picks the source address downstream packets go to while no link is
+    /// known, keyed by the address.
+    downstream_sources: WeightedRoundRobin,
+    /// The highest data sequence number received from each source address
+    /// since data from it was last acknowledged.
+    unacked: HashMap<SocketAddr, u64>,
+    /// Probes in flight, keyed by their server-wide counter, with the link
+    /// each was sent to, its counter in the link's monitor and when it was
+    /// sent.
+    probes: HashMap<u64, (String, u64, Instant)>,
+    /// Thresholds for the health of the client's links, as seen from here.
+    health: HealthPolicy,
+}
+
+/// One of a client's links, as learned from its link tables.
+struct ClientLink {
+    /// The source address the link's packets arrive from, once known.
+    addr: Option<SocketAddr>,
+    /// When a packet last arrived from the link.
+    last_seen: Option<Instant>,
+    /// How the client wants the link to be used.
+    advert: LinkAdvert,
+    /// Packets received from the link, reported back for loss accounting.
+    received: u64,
+    /// Packets sent to the link.
+    sent: u64,
+    /// Downstream health of the link, from the server's own probes.
+    monitor: LinkMonitor,
 }
 
 impl ClientState {
-    fn new(addr: SocketAddr) -> Self {
+    fn new(health: HealthPolicy) -> Self {
         Self {
             auth_status: AuthStatus::Pending,
             jitter_buffer: BTreeMap::new(),
             next_seq: None,
-            last_seen_addr: addr,
+            sources: HashMap::new(),
+            trains: HashMap::new(),
+            links: HashMap::new(),
+            downstream: WeightedRoundRobin::new(),
+            downstream_sources: WeightedRoundRobin::new(),
+            unacked: HashMap::new(),
+            probes: HashMap::new(),
+            health,
+        }
+    }
+
+    /// Expires unanswered probes and starts a new probe on every link with a
+    /// known address. Each probe takes its counter from `next_counter`, the
+    /// server-wide control counter, so no sequence number, and no nonce, is
+    /// used twice. Returns where to send the probes and their sequence
+    /// numbers.
+    fn probe_links(&mut self, mut next_counter: impl FnMut() -> u64) -> Vec<(SocketAddr, u64)> {
+        let now = Instant::now();
+        let probe_timeout = self.health.probe_timeout;
+        self.probes
+            .retain(|_, (_, _, sent_at)| now.duration_since(*sent_at) <= probe_timeout);
+        let mut probes = Vec::new();
+        for (name, link) in self.links.iter_mut() {
+            let Some(addr) = link.addr else {
+                continue;
+            };
+            for (_, change) in link.monitor.on_tick() {
+                if let Some(change) = change {
+                    log_link_change(name, change);
+                }
+            }
+            let counter = next_counter();
+            self.probes
+                .insert(counter, (name.clone(), link.monitor.on_probe_sent(), now));
+            link.sent += 1;
+            probes.push((addr, control::server_probe_sequence(counter)));
+        }
+        probes
+    }
+
+    /// Records the echo of a server probe that came back from `peer`. Only
+    /// an echo from the address the probe went to counts.
+    fn on_probe_echo(&mut self, peer: SocketAddr, counter: u64) {
+        let Some((name, seq, _)) = self.probes.remove(&counter) else {
+            return;
+        };
+        let Some(link) = self
+            .links
+            .get_mut(&name)
+            .filter(|link| link.addr == Some(peer))
+        else {
+            return;
+        };
+        if let Some(Echo {
+            change: Some(change),
+            ..
+        }) = link.monitor.on_echo(seq)
+        {
+            log_link_change(&name, change);
+        }
+    }
+
+    /// Notes a data packet to be acknowledged to the address it came from.
+    fn record_data(&mut self, peer: SocketAddr, seq: u64) {
+        let highest = self.unacked.entry(peer).or_insert(seq);
+        *highest = (*highest).max(seq);
+    }
+
+    /// Takes the acknowledgements that are due, counting each as a packet
+    /// sent to its link.
+    fn take_acks(&mut self) -> Vec<(SocketAddr, u64)> {
+        let acks: Vec<(SocketAddr, u64)> = self.unacked.drain().collect();
+        for &(peer, _) in &acks {
+            if let Some(link) = self.link_for_addr(peer) {
+                link.sent += 1;
+            }
+        }
+        acks
+    }
+
+    /// Forgets the source addresses nothing arrived from for a while, but
+    /// the latest.
+    fn expire_sources(&mut self, now: Instant) {
+        let latest = self.sources.values().max().copied();
+        let downstream_sources = &mut self.downstream_sources;
+        self.sources.retain(|addr, seen| {
+            let keep = Some(*seen) == latest || now.duration_since(*seen) < LINK_TIMEOUT;
+            if !keep {
+                downstream_sources.forget(&addr.to_string());
+            }
+            keep
+        });
+    }
+
+    /// The link whose packets arrive from `addr`, if known.
+    fn link_for_addr(&mut self, addr: SocketAddr) -> Option<&mut ClientLink> {
+        self.links.values_mut().find(|link| link.addr == Some(addr))
+    }
+
+    /// Applies a link table that arrived over the client's link `via`.
+    fn apply_link_table(
+        &mut self,
+        via: &str,
+        peer: SocketAddr,
+        adverts: Vec<LinkAdvert>,
+        now: Instant,
+    ) {
+        self.links
+            .retain(|name, _| adverts.iter().any(|advert| &advert.name == name));
+        for advert in adverts {
+            // Links on standby only get downstream traffic as a last resort.
+            let weight = if advert.active {
+                advert.downstream.weight
+            } else {
+                0
+            };
+            self.downstream.set_weight(&advert.name, weight);
+            match self.links.get_mut(&advert.name) {
+                Some(link) => link.advert = advert,
+                None => {
+                    self.links.insert(
+                        advert.name.clone(),
+                        ClientLink {
+                            addr: None,
+                            last_seen: None,
+                            advert,
+                            received: 0,
+                            sent: 0,
+                            monitor: LinkMonitor::new(self.health),
+                        },
+                    );
+                }
+            }
+        }
+        if let Some(link) = self.links.get_mut(via) {
+            if link.addr != Some(peer) {
+                info!("Client link {} is reachable at {}", via, peer);
+                // Health seen at the old address says nothing about the new one.
+                link.monitor = LinkMonitor::new(self.health);
+            }
+            link.addr = Some(peer);
+            link.last_seen = Some(now);
+        }
+    }
+
+    /// Chooses where to send the next downstream packet: one of the client's
+    /// recently heard-from links that the server's probes don't find Down,
+    /// picked by their downstream weights. Until the client's link tables
+    /// name such a link, packets take turns over the addresses the client was
+    /// recently heard from, or go to the latest of them.
+    fn downstream_addr(&mut self, now: Instant) -> Option<SocketAddr> {
+        let candidates: Vec<(String, SocketAddr)> = self
+            .links
+            .iter()
+            .filter(|(_, link)| {
+                link.monitor.status != LinkStatus::Down
+                    && link
+                        .last_seen
+                        .is_some_and(|seen| now.duration_since(seen) < LINK_TIMEOUT)
+            })
+            .filter_map(|(name, link)| Some((name.clone(), link.addr?)))
+            .collect();
+        if let Some(index) = self.downstream.pick(&candidates) {
+            let (name, addr) = &candidates[index];
+            if let Some(link) = self.links.get_mut(name) {
+                link.sent += 1;
+            }
+            return Some(*addr);
+        }
+
+        let down = |addr: &SocketAddr| {
+            self.links
+                .values()
+                .any(|link| link.addr == Some(*addr) && link.monitor.status == LinkStatus::Down)
+        };
+        let sources: Vec<(String, SocketAddr)> = self
+            .sources
+            .iter()
+            .filter(|(addr, seen)| now.duration_since(**seen) < LINK_TIMEOUT && !down(addr))
+            .map(|(addr, _)| (addr.to_string(), *addr))
+            .collect();
+        match self.downstream_sources.pick(&sources) {
+            Some(index) => Some(sources[index].1),
+            None => self
+                .sources
+                .iter()
+                .max_by_key(|(_, seen)| **seen)
+                .map(|(addr, _)| *addr),
         }
     }
 }
 
+fn log_link_change(name: &str, (old, new): StatusChange) {
+    match new {
+        LinkStatus::Down => warn!(
+            "Client link {} is DOWN (was {:?}); downstream traffic avoids it.",
+            name, old
+        ),
+        _ => info!("Client link {} is {:?} (was {:?}).", name, new, old),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
@@ -112,33 +366,22 @@ async fn main() -> anyhow::Result<()> {
             }
 
             // Determine bind address (override takes precedence)
-            let bind_addr_str = bind.unwrap_or_else(|| {
-                format!(
-                    "{}:{}",
-                    config.server.listen_address, config.server.listen_port
-                )
-            });
-
-            let bind_addr: SocketAddr = match bind_addr_str.parse() {
-                Ok(addr) => {
-                    info!("Binding to address: {}", addr);
-                    addr
-                }
-                Err(e) => {
-                    error!("Invalid bind address '{}': {}", bind_addr_str, e);
-                    return Err(anyhow::anyhow!("Invalid bind address"));
-                }
+            let bind_addr: SocketAddr = match bind {
+                Some(bind) => bind.parse().map_err(|e| {
+                    error!("Invalid bind address '{}': {}", bind, e);
+                    anyhow::anyhow!("Invalid bind address")
+                })?,
+                None => config.server.listen_addr()?,
             };
+            info!("Binding to address: {}", bind_addr);
 
-            let tun_ip: Ipv4Addr = "10.99.99.1"
-                .parse()
-                .expect("Failed to parse TUN IP address");
-            let tun_netmask: Ipv4Addr = "255.255.255.0"
-                .parse()
-                .expect("Failed to parse TUN netmask");
+            let (tun_ip, tun_prefix_len) = config.server.tun_addr()?;
+            let tun_netmask = Ipv4Addr::from(u32::MAX << (32 - u32::from(tun_prefix_len)));
+            let tun_ipv6 = config.server.tun_ipv6()?;
 
             info!("Ensuring old TUN device 'onebox0' is cleaned up...");
-            let _ = std::process::Command::new("ip").args(["link", "delete", "onebox0"]).status(); // Ignore result
+            let net = NetConfig::new()?;
+            net.delete_link("onebox0").await?;
             info!("Creating TUN device 'onebox0'...");
             let tun = match TunBuilder::new()
                 .name("onebox0")
@@ -147,11 +390,15 @@ async fn main() -> anyhow::Result<()> {
                 .up() // Bring the interface up
                 .address(tun_ip)
                 .netmask(tun_netmask)
+                .mtu(i32::from(config.server.mtu))
                 .try_build()
             {
                 Ok(tun) => {
                     info!("TUN device 'onebox0' created successfully.");
-                    info!("IP: 10.99.99.1, Netmask: 255.255.255.0");
+                    info!(
+                        "IP: {}/{}, MTU: {}",
+                        tun_ip, tun_prefix_len, config.server.mtu
+                    );
                     tun
                 }
                 Err(e) => {
@@ -160,61 +407,54 @@ async fn main() -> anyhow::Result<()> {
                 }
             };
 
-            // Add a route for the client's TUN network to go via our TUN device
-            info!("Adding route for client TUN network (10.8.0.0/24)...");
-            let route_add_output = Command::new("ip")
-                .args(["route", "add", "10.8.0.0/24", "dev", "onebox0"])
-                .output()
-                .map_err(|e| anyhow::anyhow!("Failed to execute 'ip route add': {}", e))?;
-            if !route_add_output.status.success() {
-                error!(
-                    "Failed to add route for client TUN: {}",
-                    String::from_utf8_lossy(&route_add_output.stderr)
-                );
-                return Err(anyhow::anyhow!("Failed to add route for client TUN"));
+            let net_changes = setup_network(
+                &net,
+                (tun_ip, tun_prefix_len),
+                tun_ipv6,
+                config.server.ipv6_nat,
+            )
+            .await?;
+
+            // What every client is told about the tunnel; each gets its own
+            // addresses from the pool.
+            let push_settings = NetworkSettings {
+                address: Ipv4Addr::UNSPECIFIED,
+                prefix_len: tun_prefix_len,
+                address_v6: None,
+                dns_servers: config.server.dns_servers()?,
+                dns_search: config.server.dns_search.clone(),
+                routes: config.server.push_routes()?,
+                mtu: config.server.mtu,
+            };
+            let mut address_pool = AddressPool::new(
+                tun_ip,
+                tun_prefix_len,
+                tun_ipv6,
+                config.server.address_pool()?,
+            );
+            for (client, address) in config.server.reservations()? {
+                address_pool.reserve(client, address);
             }
-
-            // Enable IP forwarding
-            info!("Enabling IP forwarding...");
-            let output = Command::new("sysctl")
-                .arg("-w")
-                .arg("net.ipv4.ip_forward=1")
-                .output()
-                .map_err(|e| anyhow::anyhow!("Failed to execute sysctl: {}", e))?;
-
-            if !output.status.success() {
-                let error_message = String::from_utf8_lossy(&output.stderr);
-                error!("Failed to enable IP forwarding: {}", error_message);
-                return Err(anyhow::anyhow!(
-                    "Failed to enable IP forwarding: {}",
-                    error_message
-                ));
+            if let Err(e) = address_pool.load(&config.server.lease_file) {
+                warn!(
+                    "Failed to load address leases from {}: {}. Starting without them.",
+                    config.server.lease_file, e
+                );
             }
-            info!("IP forwarding enabled successfully.");
-
-            // Set up NAT masquerading
-            match get_default_interface() {
-                Ok(iface) => {
-                    if let Err(e) = setup_nat_masquerade(&iface, "10.8.0.0/24") {
-                        warn!("Failed to set up NAT masquerading: {}. This may be expected in some test environments.", e);
-                    }
-                }
-                Err(e) => {
-                    warn!("Could not get default network interface: {}. Skipping NAT setup. This is expected in CI/test environments without a default route.", e);
-                }
-            };
+            let address_pool = Arc::new(Mutex::new(address_pool));
 
             // Bind UDP socket and log incoming datagrams
-            let socket = UdpSocket::bind(bind_addr).await.map_err(|e| {
+            let socket = bind_udp(bind_addr).map_err(|e| {
                 error!("Failed to bind UDP socket on {}: {}", bind_addr, e);
-                anyhow::anyhow!(e)
+                e
             })?;
 
-            info!("UDP server listening on {}", bind_addr);
+            info!("UDP server listening on {}", socket.local_addr()?);
 
             // Derive the encryption key from the PSK
             let key = derive_key(&config.preshared_key);
             let key = Arc::new(key);
+            let health_policy = HealthPolicy::from(&config.server.health);
 
             // Split TUN device into reader and writer
             let (mut tun_reader, tun_writer) = tokio::io::split(tun);
@@ -231,7 +471,10 @@ async fn main() -> anyhow::Result<()> {
             let num_workers = num_cpus::get();
             info!("Spawning {} UDP->TUN worker tasks...", num_workers);
 
-            let (tx, rx) = tokio::sync::mpsc::channel::<(Vec<u8>, SocketAddr)>(1024);
+            // Started from the clock, so that after a restart control and
+            // probe sequence numbers, and with them nonces, aren't reused.
+            let control_seq = Arc::new(AtomicU64::new(control::counter_seed()));
+            let (tx, rx) = tokio::sync::mpsc::channel::<(Vec<u8>, SocketAddr, Instant)>(1024);
             let shared_rx = Arc::new(Mutex::new(rx));
 
             for i in 0..num_workers {
@@ -240,6 +483,9 @@ async fn main() -> anyhow::Result<()> {
                 let worker_tun = tun_writer.clone();
                 let worker_key = decryption_key.clone();
                 let worker_socket = socket.clone();
+                let worker_control_seq = control_seq.clone();
+                let worker_pool = address_pool.clone();
+                let worker_settings = push_settings.clone();
 
                 tokio::spawn(async move {
                     info!("Worker {} started", i);
@@ -250,7 +496,7 @@ async fn main() -> anyhow::Result<()> {
                             rx_guard.recv().await
                         };
 
-                        if let Some((buf, peer)) = packet_data {
+                        if let Some((buf, peer, arrived_at)) = packet_data {
                             debug!("[Worker {}] Received {} bytes from {}", i, buf.len(), peer);
                             let header = match bincode::deserialize::<PacketHeader>(&buf) {
                                 Ok(h) => h,
@@ -272,9 +518,19 @@ async fn main() -> anyhow::Result<()> {
                                     let mut clients_guard = worker_clients.lock().await;
                                     let client_state = clients_guard
                                         .entry(header.client_id)
-                                        .or_insert_with(|| ClientState::new(peer));
+                                        .or_insert_with(|| ClientState::new(health_policy));
 
-                                    client_state.last_seen_addr = peer;
+                                    if client_state.auth_status == AuthStatus::Authenticated {
+                                        client_state.sources.insert(peer, arrived_at);
+                                    }
+                                    if let Some(link) = client_state.link_for_addr(peer) {
+                                        link.last_seen = Some(arrived_at);
+                                        // Trains are bursts that would skew the client's
+                                        // loss windows; both ends leave them out.
+                                        if header.packet_type != PacketType::BandwidthProbe {
+                                            link.received += 1;
+                                        }
+                                    }
 
                                     match header.packet_type {
                                         PacketType::AuthRequest => {
@@ -282,28 +538,60 @@ async fn main() -> anyhow::Result<()> {
                                                 "[Worker {}] AuthRequest from client {}",
                                                 i, header.client_id.0
                                             );
+                                            let Some((address, address_v6)) =
+                                                worker_pool.lock().await.assign(header.client_id)
+                                            else {
+                                                error!(
+                                                    "[Worker {}] No tunnel address left for client {}",
+                                                    i, header.client_id.0
+                                                );
+                                                continue;
+                                            };
                                             client_state.auth_status = AuthStatus::Authenticated;
-
+                                            client_state.sources.insert(peer, arrived_at);
+
+                                            // The settings go out encrypted under the server's
+                                            // own control sequence, so no nonce is reused.
+                                            let settings = NetworkSettings {
+                                                address,
+                                                address_v6: address_v6
+                                                    .zip(worker_pool.lock().await.prefix_len_v6()),
+                                                ..worker_settings.clone()
+                                            };
+                                            let seq = control::control_sequence(
+                                                worker_control_seq.fetch_add(1, Ordering::Relaxed),
+                                                true,
+                                            );
                                             let resp_header = PacketHeader::new(
-                                                0,
+                                                seq,
                                                 PacketType::AuthResponse,
                                                 header.client_id,
                                             );
-                                            let resp_header_bytes =
-                                                bincode::serialize(&resp_header).unwrap();
-                                            let resp_payload =
-                                                encrypt(&worker_key, b"AUTH_OK", 0).unwrap();
-                                            let resp_packet =
-                                                [&resp_header_bytes[..], &resp_payload[..]]
-                                                    .concat();
-
-                                            if let Err(e) =
-                                                worker_socket.send_to(&resp_packet, peer).await
-                                            {
-                                                error!(
+                                            let sent = match bincode::serialize(&settings) {
+                                                Ok(payload) => {
+                                                    match seal_packet(
+                                                        &worker_key,
+                                                        &resp_header,
+                                                        &payload,
+                                                    ) {
+                                                        Ok(packet) => worker_socket
+                                                            .send_to(&packet, peer)
+                                                            .await
+                                                            .map_err(anyhow::Error::from),
+                                                        Err(e) => Err(e),
+                                                    }
+                                                }
+                                                Err(e) => Err(e.into()),
+                                            };
+                                            match sent {
+                                                Ok(_) => info!(
+                                                    "[Worker {}] Client {} has address {}",
+                                                    i, header.client_id.0, address
+                                                ),
+                                                Err(e) => error!(
                                                     "[Worker {}] Failed to send AuthResponse: {}",
                                                     i, e
-                                                );
+                                                ),
                                             }
                                         }
                                         PacketType::Data => {
@@ -311,6 +599,7 @@ async fn main() -> anyhow::Result<()> {
                                             {
                                                 continue; // Ignore data from unauthenticated clients
                                             }
+                                            client_state.record_data(peer, header.sequence_number);
 
                                             // Insert the packet into the jitter buffer.
                                             client_state
@@ -345,19 +634,129 @@ async fn main() -> anyhow::Result<()> {
                                                 client_state.next_seq = Some(current_seq);
                                             }
                                         }
+                                        #[allow(clippy::collapsible_match)]
                                         PacketType::Probe => {
                                             if client_state.auth_status == AuthStatus::Authenticated
                                             {
-                                                if let Err(e) =
+                                                if let Some(counter) = control::server_probe_counter(
+                                                    header.sequence_number,
+                                                ) {
+                                                    client_state.on_probe_echo(peer, counter);
+                                                } else if let Err(e) =
                                                     worker_socket.send_to(&buf, peer).await
                                                 {
                                                     error!(
                                                         "[Worker {}] Failed to echo probe: {}",
                                                         i, e
                                                     );
+                                                } else if let Some(link) =
+                                                    client_state.link_for_addr(peer)
+                                                {
+                                                    link.sent += 1;
                                                 }
                                             }
                                         }
+                                        PacketType::Control
+                                            if client_state.auth_status
+                                                == AuthStatus::Authenticated =>
+                                        {
+                                            let Ok(ControlMessage::LinkTable { via, links }) =
+                                                bincode::deserialize::<ControlMessage>(&plaintext)
+                                            else {
+                                                continue;
+                                            };
+                                            client_state
+                                                .apply_link_table(&via, peer, links, arrived_at);
+                                            let Some(link) = client_state.links.get_mut(&via)
+                                            else {
+                                                continue;
+                                            };
+                                            link.sent += 1;
+                                            let feedback = ControlMessage::LinkFeedback {
+                                                link: via,
+                                                received: link.received,
+                                                sent: link.sent,
+                                            };
+                                            let seq = control::control_sequence(
+                                                worker_control_seq.fetch_add(1, Ordering::Relaxed),
+                                                true,
+                                            );
+                                            if let Err(e) = send_control(
+                                                &worker_socket,
+                                                &worker_key,
+                                                header.client_id,
+                                                seq,
+                                                peer,
+                                                &feedback,
+                                            )
+                                            .await
+                                            {
+                                                error!(
+                                                    "[Worker {}] Failed to send link feedback: {}",
+                                                    i, e
+                                                );
+                                            }
+                                        }
+                                        PacketType::BandwidthProbe
+                                            if client_state.auth_status
+                                                == AuthStatus::Authenticated =>
+                                        {
+                                            let Ok(probe) =
+                                                bincode::deserialize::<BandwidthProbe>(&plaintext)
+                                            else {
+                                                continue;
+                                            };
+                                            client_state.trains.retain(|_, train| {
+                                                train.started_at().is_some_and(|started| {
+                                                    arrived_at.duration_since(started)
+                                                        < TRAIN_TIMEOUT
+                                                })
+                                            });
+                                            let train = client_state
+                                                .trains
+                                                .entry((peer, probe.train_id))
+                                                .or_insert_with(|| {
+                                                    TrainArrivals::new(
+                                                        probe.count.clamp(2, TRAIN_LENGTH),
+                                                    )
+                                                });
+                                            train.record(buf.len(), arrived_at);
+                                            // Answer once the train is complete, or once its
+                                            // last packet shows up if earlier ones were lost.
+                                            if !train.is_complete() && probe.index + 1 < probe.count
+                                            {
+                                                continue;
+                                            }
+                                            let Some(train) =
+                                                client_state.trains.remove(&(peer, probe.train_id))
+                                            else {
+                                                continue;
+                                            };
+                                            let report = BandwidthReport {
+                                                train_id: probe.train_id,
+                                                received: train.received,
+                                                bytes_per_sec: train.bytes_per_sec(),
+                                            };
+                                            debug!(
+                                                "[Worker {}] Bandwidth train {} from {}: {:?}",
+                                                i, probe.train_id, peer, report
+                                            );
+                                            if let Err(e) = send_train_reply(
+                                                &worker_socket,
+                                                &worker_key,
+                                                header.client_id,
+                                                peer,
+                                                &report,
+                                                train.count,
+                                            )
+                                            .await
+                                            {
+                                                error!(
+                                                    "[Worker {}] Failed to answer bandwidth probe: {}",
+                                                    i, e
+                                                );
+                                            }
+                                        }
                                         _ => {}
                                     }
                                 }
@@ -376,6 +775,88 @@ async fn main() -> anyhow::Result<()> {
                 });
             }
 
+            // Acknowledge received data per client link, so that clients
+            // notice a failing link from their data traffic alone.
+            let ack_socket = socket.clone();
+            let ack_clients = clients.clone();
+            let ack_key = key.clone();
+            let ack_control_seq = control_seq.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(control::DATA_ACK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let acks: Vec<(ClientId, SocketAddr, u64)> = {
+                        let mut clients_guard = ack_clients.lock().await;
+                        clients_guard
+                            .iter_mut()
+                            .flat_map(|(&client_id, client_state)| {
+                                client_state
+                                    .take_acks()
+                                    .into_iter()
+                                    .map(move |(peer, highest)| (client_id, peer, highest))
+                            })
+                            .collect()
+                    };
+                    for (client_id, peer, highest) in acks {
+                        let seq = control::control_sequence(
+                            ack_control_seq.fetch_add(1, Ordering::Relaxed),
+                            true,
+                        );
+                        let message = ControlMessage::DataAck { highest };
+                        if let Err(e) =
+                            send_control(&ack_socket, &ack_key, client_id, seq, peer, &message)
+                                .await
+                        {
+                            debug!("Failed to acknowledge data to {}: {}", peer, e);
+                        }
+                    }
+                }
+            });
+
+            // Probe every known client link, so that downstream traffic
+            // avoids dead links even while the client has nothing to send.
+            let probe_socket = socket.clone();
+            let probe_clients = clients.clone();
+            let probe_key = key.clone();
+            let probe_control_seq = control_seq.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(health_policy.probe_interval);
+                loop {
+                    interval.tick().await;
+                    let probes: Vec<(ClientId, SocketAddr, u64)> = {
+                        let mut clients_guard = probe_clients.lock().await;
+                        clients_guard
+                            .iter_mut()
+                            .filter(|(_, client_state)| {
+                                client_state.auth_status == AuthStatus::Authenticated
+                            })
+                            .flat_map(|(&client_id, client_state)| {
+                                client_state.expire_sources(Instant::now());
+                                client_state
+                                    .probe_links(|| {
+                                        probe_control_seq.fetch_add(1, Ordering::Relaxed)
+                                    })
+                                    .into_iter()
+                                    .map(move |(peer, seq)| (client_id, peer, seq))
+                            })
+                            .collect()
+                    };
+                    for (client_id, peer, seq) in probes {
+                        let header = PacketHeader::new(seq, PacketType::Probe, client_id);
+                        let result = match seal_packet(&probe_key, &header, b"") {
+                            Ok(packet) => probe_socket.send_to(&packet, peer).await.map(|_| ()),
+                            Err(e) => {
+                                error!("Failed to seal probe: {}", e);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = result {
+                            debug!("Failed to probe client link at {}: {}", peer, e);
+                        }
+                    }
+                }
+            });
+
             // The Dispatcher Task
             let dispatcher_socket = socket.clone();
             let dispatcher = tokio::spawn(async move {
@@ -383,7 +864,12 @@ async fn main() -> anyhow::Result<()> {
                 loop {
                     match dispatcher_socket.recv_from(&mut buf).await {
                         Ok((len, peer)) => {
-                            if tx.send((buf[..len].to_vec(), peer)).await.is_err() {
+                            let arrived_at = Instant::now();
+                            if tx
+                                .send((buf[..len].to_vec(), peer, arrived_at))
+                                .await
+                                .is_err()
+                            {
                                 error!("Worker channel closed, dispatcher shutting down.");
                                 break;
                             }
@@ -399,6 +885,7 @@ async fn main() -> anyhow::Result<()> {
             // Task 2: TUN -> UDP (with Encryption)
             let tun_to_udp_socket = socket.clone();
             let clients_reader = clients.clone();
+            let reader_pool = address_pool.clone();
             let downstream_seq = Arc::new(AtomicU64::new(0));
             let encryption_key = key.clone();
             let tun_to_udp = tokio::spawn(async move {
@@ -410,16 +897,22 @@ async fn main() -> anyhow::Result<()> {
                                 continue;
                             }
 
-                            // Find the first authenticated client to send the packet to.
-                            // Note: A proper implementation would map TUN IPs to client addresses.
-                            let clients_guard = clients_reader.lock().await;
-                            let client_info = clients_guard.iter().find_map(|(id, state)| {
-                                if state.auth_status == AuthStatus::Authenticated {
-                                    Some((*id, state.last_seen_addr))
-                                } else {
-                                    None
-                                }
-                            });
+                            // The packet goes to the client holding its destination.
+                            let Some(destination) = packet_destination(&buf[..len]) else {
+                                continue;
+                            };
+                            let Some(client_id) = reader_pool.lock().await.client_for(destination)
+                            else {
+                                debug!("No client has address {}, dropping packet", destination);
+                                continue;
+                            };
+                            let now = Instant::now();
+                            let mut clients_guard = clients_reader.lock().await;
+                            let client_info = clients_guard
+                                .get_mut(&client_id)
+                                .filter(|state| state.auth_status == AuthStatus::Authenticated)
+                                .and_then(|state| Some((client_id, state.downstream_addr(now)?)));
+                            drop(clients_guard);
 
                             if let Some((client_id, peer_addr)) = client_info {
                                 let seq = downstream_seq.fetch_add(1, Ordering::Relaxed);
@@ -454,7 +947,9 @@ async fn main() -> anyhow::Result<()> {
             tokio::select! {
                 _ = dispatcher => info!("Dispatcher task finished."),
                 _ = tun_to_udp => info!("TUN->UDP task finished."),
+                _ = shutdown_signal() => info!("Shutting down."),
             }
+            net.revert_all(net_changes).await;
         }
 
         Commands::Stop => {
@@ -491,79 +986,261 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Finds the default network interface of the system.
-fn get_default_interface() -> anyhow::Result<String> {
-    info!("Querying for default network interface...");
-    let output = Command::new("ip")
-        .args(["route", "get", "8.8.8.8"])
-        .output()
-        .map_err(|e| anyhow::anyhow!("Failed to execute 'ip route': {}", e))?;
+/// The destination address of an IPv4 or IPv6 packet.
+fn packet_destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(packet.get(16..20)?).ok()?)),
+        6 => Some(IpAddr::from(
+            <[u8; 16]>::try_from(packet.get(24..40)?).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+/// Encrypts `plaintext` and prepends the serialized `header`.
+fn seal_packet(key: &Key, header: &PacketHeader, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let header_bytes = bincode::serialize(header)?;
+    let ciphertext = encrypt(key, plaintext, header.sequence_number)?;
+    Ok([&header_bytes[..], &ciphertext[..]].concat())
+}
+
+/// Sends a control message to one of a client's addresses.
+async fn send_control(
+    socket: &UdpSocket,
+    key: &Key,
+    client_id: ClientId,
+    seq: u64,
+    peer: SocketAddr,
+    message: &ControlMessage,
+) -> anyhow::Result<()> {
+    let header = PacketHeader::new(seq, PacketType::Control, client_id);
+    let packet = seal_packet(key, &header, &bincode::serialize(message)?)?;
+    socket.send_to(&packet, peer).await?;
+    Ok(())
+}
+
+/// Answers a completed bandwidth probe train: reports the upstream arrival
+/// rate, then sends a train of `count` packets back so the client can measure
+/// the downstream capacity of the same link.
+async fn send_train_reply(
+    socket: &UdpSocket,
+    key: &Key,
+    client_id: ClientId,
+    peer: SocketAddr,
+    report: &BandwidthReport,
+    count: u16,
+) -> anyhow::Result<()> {
+    let report_header = PacketHeader::new(
+        bandwidth::train_sequence(report.train_id, u16::MAX, true),
+        PacketType::BandwidthReport,
+        client_id,
+    );
+    let packet = seal_packet(key, &report_header, &bincode::serialize(report)?)?;
+    socket.send_to(&packet, peer).await?;
+
+    let mut train = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let probe = BandwidthProbe {
+            train_id: report.train_id,
+            index,
+            count,
+        };
+        let header = PacketHeader::new(
+            bandwidth::train_sequence(report.train_id, index, true),
+            PacketType::BandwidthProbe,
+            client_id,
+        );
+        train.push(seal_packet(
+            key,
+            &header,
+            &bandwidth::train_payload(&probe),
+        )?);
+    }
+    // Packets are built up front so that they leave back to back.
+    for packet in &train {
+        socket.send_to(packet, peer).await?;
+    }
+    Ok(())
+}
 
-    if !output.status.success() {
-        let error_message = String::from_utf8_lossy(&output.stderr);
+/// Binds the server's UDP socket. The unspecified IPv6 address is bound
+/// dual-stack, so IPv4 clients reach it too, as IPv4-mapped addresses; on
+/// hosts without IPv6 the server falls back to IPv4 only.
+fn bind_udp(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let socket = match addr {
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => match bind_dual_stack(v6) {
+            Ok(socket) => socket,
+            Err(Errno::EAFNOSUPPORT) => {
+                warn!("IPv6 is not available, listening on IPv4 only");
+                std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?
+            }
+            Err(e) => return Err(e.into()),
+        },
+        _ => std::net::UdpSocket::bind(addr)?,
+    };
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+fn bind_dual_stack(addr: SocketAddrV6) -> nix::Result<std::net::UdpSocket> {
+    let fd = socket(
+        AddressFamily::Inet6,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    setsockopt(&fd, sockopt::Ipv6V6Only, &false)?;
+    bind(fd.as_raw_fd(), &SockaddrIn6::from(addr))?;
+    Ok(fd.into())
+}
+
+/// Enables forwarding and masquerades traffic from the tunnel network, which
+/// the TUN device's address routes to it, leaving through the default route.
+/// The IPv6 address, when there is one, also routes its prefix into the
+/// tunnel; its traffic is masqueraded too (NAT66) unless `ipv6_nat` is off
+/// because the prefix is routed to the server. Returns the changes to undo on
+/// exit; if a step fails, the ones before it are undone.
+async fn setup_network(
+    net: &NetConfig,
+    (tun_ip, tun_prefix_len): (Ipv4Addr, u8),
+    tun_ipv6: Option<(Ipv6Addr, u8)>,
+    ipv6_nat: bool,
+) -> anyhow::Result<Vec<Change>> {
+    let mut transaction = net.transaction();
+    let result = async {
+        info!("Enabling IP forwarding...");
+        transaction.set_sysctl("net.ipv4.ip_forward", "1")?;
+        if let Some((address, prefix_len)) = tun_ipv6 {
+            info!(
+                "Adding IPv6 address {}/{} to onebox0...",
+                address, prefix_len
+            );
+            transaction
+                .add_address("onebox0", address.into(), prefix_len)
+                .await?;
+            info!("Enabling IPv6 forwarding...");
+            transaction.set_sysctl("net.ipv6.conf.all.forwarding", "1")?;
+        }
+        OneboxResult::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        transaction.rollback().await;
         return Err(anyhow::anyhow!(
-            "Failed to get default route: {}",
-            error_message
+            "Failed to configure the tunnel network: {}",
+            e
         ));
     }
+    info!("IP forwarding enabled successfully.");
 
-    let stdout = String::from_utf8(output.stdout)?;
-    // Example output: "8.8.8.8 via 192.168.1.1 dev enp0s3 src 192.168.1.100 uid 0"
-    let parts: Vec<&str> = stdout.split_whitespace().collect();
-    if let Some(dev_idx) = parts.iter().position(|&r| r == "dev") {
-        if let Some(iface) = parts.get(dev_idx + 1) {
-            info!("Found default interface: {}", iface);
-            return Ok(iface.to_string());
+    let mut masquerades = vec![(IpAddr::from(tun_ip), tun_prefix_len)];
+    if let Some((address, prefix_len)) = tun_ipv6.filter(|_| ipv6_nat) {
+        masquerades.push((address.into(), prefix_len));
+    }
+    for (source, prefix_len) in masquerades {
+        let family = Family::of(source);
+        let oif = match net.default_route(family, None).await {
+            Ok(Some(Route { dev: Some(dev), .. })) => dev,
+            Ok(_) => {
+                warn!("No default {:?} route. Skipping NAT setup for {}/{}. This is expected in CI/test environments without a default route.", family, source, prefix_len);
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Could not get default network interface: {}. Skipping NAT setup.",
+                    e
+                );
+                continue;
+            }
+        };
+        info!(
+            "Setting up NAT masquerade on {} for source {}/{}",
+            oif, source, prefix_len
+        );
+        let masquerade = Masquerade {
+            source,
+            prefix_len,
+            oif,
+        };
+        if let Err(e) = transaction.add_masquerade(masquerade) {
+            warn!("Failed to set up NAT masquerading: {}. This may be expected in some test environments.", e);
         }
     }
+    Ok(transaction.commit())
+}
 
-    Err(anyhow::anyhow!(
-        "Could not parse default interface from 'ip route' output"
-    ))
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
 
-/// Sets up NAT masquerading using iptables.
-fn setup_nat_masquerade(interface: &str, source_net: &str) -> anyhow::Result<()> {
-    info!(
-        "Setting up NAT masquerade on {} for source {}",
-        interface, source_net
-    );
-    // Flush all existing NAT rules to ensure a clean state.
-    let flush_status = Command::new("iptables")
-        .args(["-t", "nat", "-F"])
-        .status()?;
-    if !flush_status.success() {
-        return Err(anyhow::anyhow!("Failed to flush iptables NAT table"));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use onebox_core::control::DirectionInfo;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
     }
-    info!("Flushed iptables NAT table.");
-
-    let add_rule_args = [
-        "-t",
-        "nat",
-        "-A",
-        "POSTROUTING",
-        "-s",
-        source_net,
-        "-o",
-        interface,
-        "-j",
-        "MASQUERADE",
-    ];
-
-    let add_output = Command::new("iptables")
-        .args(add_rule_args)
-        .output()
-        .map_err(|e| anyhow::anyhow!("Failed to execute iptables: {}", e))?;
-
-    if !add_output.status.success() {
-        let error_message = String::from_utf8_lossy(&add_output.stderr);
-        error!("Failed to add iptables MASQUERADE rule: {}", error_message);
-        return Err(anyhow::anyhow!(
-            "Failed to add iptables rule: {}",
-            error_message
-        ));
+
+    /// A client heard from three addresses, the first of them the address
+    /// of its link eth0.
+    fn client(now: Instant) -> ClientState {
+        let mut client = ClientState::new(HealthPolicy::default());
+        for (i, source) in ["192.0.2.1:4000", "192.0.2.2:4000", "192.0.2.3:4000"]
+            .into_iter()
+            .enumerate()
+        {
+            client
+                .sources
+                .insert(addr(source), now - Duration::from_millis(3 - i as u64));
+        }
+        let advert = LinkAdvert {
+            name: "eth0".to_string(),
+            active: true,
+            upstream: DirectionInfo::default(),
+            downstream: DirectionInfo::default(),
+        };
+        client.apply_link_table("eth0", addr("192.0.2.1:4000"), vec![advert], now);
+        client
     }
 
-    info!("iptables MASQUERADE rule set successfully.");
-    Ok(())
+    #[test]
+    fn downstream_packets_take_turns_over_sources_of_links_not_down() {
+        let now = Instant::now();
+        let mut client = client(now);
+        assert_eq!(client.downstream_addr(now), Some(addr("192.0.2.1:4000")));
+
+        client.links.get_mut("eth0").unwrap().monitor.status = LinkStatus::Down;
+        let picks: Vec<SocketAddr> = (0..4)
+            .map(|_| client.downstream_addr(now).unwrap())
+            .collect();
+        assert_eq!(picks[0], picks[2]);
+        assert_eq!(picks[1], picks[3]);
+        assert_ne!(picks[0], picks[1]);
+        assert!(!picks.contains(&addr("192.0.2.1:4000")));
+    }
+
+    #[test]
+    fn downstream_packets_fall_back_to_the_latest_source() {
+        let now = Instant::now();
+        let mut client = client(now);
+        let later = now + LINK_TIMEOUT;
+        assert_eq!(client.downstream_addr(later), Some(addr("192.0.2.3:4000")));
+
+        client.expire_sources(later);
+        assert_eq!(client.sources.len(), 1);
+        assert_eq!(client.downstream_addr(later), Some(addr("192.0.2.3:4000")));
+    }
 }